@@ -0,0 +1,158 @@
+//! Connection authentication. The server makes a random token at startup and
+//! hands it to the launcher in the ready line; each connection then proves it
+//! knows the token by answering a challenge, so the token itself never crosses
+//! the wire.
+//!
+//! ```text
+//! server -> client: "REMAP" version:u8 nonce:[u8; 32]
+//! client -> server: HMAC-SHA256(token, nonce):[u8; 32]
+//! server -> client: status:u8 (0 = ok), then on failure a reason (String)
+//! ```
+
+use std::io::{Read, Write};
+
+use anyhow::{bail, Context, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::Message;
+
+const MAGIC: &[u8; 5] = b"REMAP";
+const VERSION: u8 = 1;
+const NONCE_LEN: usize = 32;
+const TOKEN_LEN: usize = 32;
+
+const STATUS_OK: u8 = 0;
+const STATUS_DENIED: u8 = 1;
+
+/// Shared secret for one server session, shown as hex.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Token(Vec<u8>);
+
+impl Token {
+    pub fn generate() -> Result<Token> {
+        let mut bytes = vec![0u8; TOKEN_LEN];
+        getrandom::getrandom(&mut bytes).context("no randomness for the session token")?;
+        Ok(Token(bytes))
+    }
+
+    fn mac(&self, nonce: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).expect("hmac accepts any key length");
+        mac.update(nonce);
+        mac
+    }
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{b:02x}"))
+    }
+}
+
+// Keep the secret out of debug logs.
+impl std::fmt::Debug for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Token(..)")
+    }
+}
+
+impl std::str::FromStr for Token {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Token> {
+        if s.is_empty() || !s.len().is_multiple_of(2) || !s.is_ascii() {
+            bail!("invalid token: expected an even number of hex digits");
+        }
+        let bytes = (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .context("invalid token: not hex")?;
+        Ok(Token(bytes))
+    }
+}
+
+/// Server side: challenge the peer and tell it whether it passed.
+pub fn challenge<S: Read + Write>(stream: &mut S, token: &Token) -> Result<()> {
+    let mut nonce = [0u8; NONCE_LEN];
+    getrandom::getrandom(&mut nonce).context("no randomness for the auth nonce")?;
+    stream.write_all(MAGIC)?;
+    stream.write_all(&[VERSION])?;
+    stream.write_all(&nonce)?;
+    stream.flush()?;
+
+    let mut answer = [0u8; 32];
+    stream.read_exact(&mut answer).context("no auth response")?;
+
+    // verify_slice compares in constant time
+    if token.mac(&nonce).verify_slice(&answer).is_err() {
+        stream.write_all(&[STATUS_DENIED])?;
+        "authentication failed".to_string().write_to(stream)?;
+        stream.flush()?;
+        bail!("authentication failed");
+    }
+    stream.write_all(&[STATUS_OK])?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Client side: answer the server's challenge.
+pub fn respond<S: Read + Write>(stream: &mut S, token: &Token) -> Result<()> {
+    let mut magic = [0u8; 5];
+    stream.read_exact(&mut magic).context("no auth challenge from server")?;
+    if &magic != MAGIC {
+        bail!("not a remap server (or a server without authentication)");
+    }
+    let mut version = [0u8; 1];
+    stream.read_exact(&mut version)?;
+    if version[0] != VERSION {
+        bail!("unsupported auth version {} (expected {VERSION})", version[0]);
+    }
+    let mut nonce = [0u8; NONCE_LEN];
+    stream.read_exact(&mut nonce)?;
+
+    stream.write_all(&token.mac(&nonce).finalize().into_bytes())?;
+    stream.flush()?;
+
+    let mut status = [0u8; 1];
+    stream.read_exact(&mut status).context("server closed the connection during auth")?;
+    if status[0] != STATUS_OK {
+        let reason = String::read_from(stream).unwrap_or_default();
+        bail!("server rejected the connection: {reason}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+
+    fn run(server_token: &Token, client_token: &Token) -> (Result<()>, Result<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut b = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut a, _) = listener.accept().unwrap();
+        let token = server_token.clone();
+        let server = std::thread::spawn(move || challenge(&mut a, &token));
+        let client = respond(&mut b, client_token);
+        (server.join().unwrap(), client)
+    }
+
+    #[test]
+    fn accepts_matching_token_only() {
+        let token = Token::generate().unwrap();
+        let (s, c) = run(&token, &token);
+        assert!(s.is_ok() && c.is_ok());
+
+        let other = Token::generate().unwrap();
+        let (s, c) = run(&token, &other);
+        assert!(s.is_err());
+        assert!(c.unwrap_err().to_string().contains("authentication failed"));
+    }
+
+    #[test]
+    fn token_hex_round_trip() {
+        let token = Token::generate().unwrap();
+        assert_eq!(token.to_string().parse::<Token>().unwrap(), token);
+        assert!("xyz".parse::<Token>().is_err());
+    }
+}
@@ -1,120 +1,846 @@
-use std::net::TcpStream;
-use std::process::{Command, Stdio};
+use std::io::{BufRead, BufReader};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::str::FromStr;
 use std::time::Duration;
 use anyhow::{Context, Result};
 use byteorder::{BigEndian, ReadBytesExt};
-use log::{info, warn};
-use remap::{ClientEvent, ServerEvent};
+use clap::Parser;
+use log::{debug, info, warn};
+use remap::{auth, doctor, tls, util, ClientEvent, Encoding, ReadyInfo, ServerEvent, READY_PREFIX};
 use remap::canvas::Canvas;
+use remap::transport::Stream;
 use remap::Message;
+use tokio::runtime::Handle;
+use tunnel::{ConnectOptions, HostKeyPolicy, Output, RemoteCommand, Ssh, SshTunnel, Status as TunnelStatus, Target};
 
-// helper: wait until a TCP connect to addr works (up to timeout)
-fn wait_tcp(addr: &str, total_ms: u64) -> bool {
-    let start = std::time::Instant::now();
-    while start.elapsed() < Duration::from_millis(total_ms) {
-        if TcpStream::connect(addr).is_ok() { return true; }
-        std::thread::sleep(Duration::from_millis(120));
+/// Remap client: run a remote app over SSH and show it in a local window
+#[derive(Parser, Debug)]
+#[command(author, version, about = "Remap client", long_about = None)]
+struct ClientArgs {
+    /// Remote host as [user@]host[:ssh_port]; user and port otherwise come from ~/.ssh/config
+    #[arg(required_unless_present = "direct")]
+    target: Option<SshTarget>,
+
+    /// App (and args) to run on the remote host [default: the server's configured app]
+    #[arg(short, long)]
+    app: Option<String>,
+
+    /// Connect to a server already listening on this remote port instead of launching one
+    #[arg(short, long)]
+    remote_port: Option<u16>,
+
+    /// Connect to a server already listening on this remote Unix socket instead of launching one
+    #[arg(long, value_name = "PATH", conflicts_with = "remote_port")]
+    remote_socket: Option<String>,
+
+    /// Have the launched server listen on a TCP port instead of a Unix socket
+    #[arg(long)]
+    tcp: bool,
+
+    /// Start (or reattach to) a named session that keeps running when the window closes
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["remote_port", "remote_socket", "attach"])]
+    session: Option<String>,
+
+    /// Reattach to a running session on the remote host
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["remote_port", "remote_socket", "app"])]
+    attach: Option<String>,
+
+    /// How long a server launched by this client keeps running after the ssh
+    /// connection drops, waiting for us to reconnect (seconds)
+    #[arg(long, value_name = "SECS", default_value_t = 120)]
+    linger: u64,
+
+    /// List the sessions running on the remote host and exit
+    #[arg(long, conflicts_with = "direct")]
+    list: bool,
+
+    /// Server command on the remote host
+    #[arg(long, default_value = "server")]
+    server: String,
+
+    /// Run the server command as is, even if it is missing or a different
+    /// version; by default a matching server is installed in ~/.cache/remap
+    /// on the remote host (built-in SSH client only)
+    #[arg(long)]
+    no_deploy: bool,
+
+    /// Linux server binary to install on remote hosts that lack this version
+    /// [default: server-linux-ARCH next to the client, or on Linux the server next to it]
+    #[arg(long, value_name = "PATH", env = "REMAP_SERVER_BINARY")]
+    server_binary: Option<PathBuf>,
+
+    /// Launch the server without first checking that the remote host has
+    /// what it needs (`server --doctor`; built-in SSH client only)
+    #[arg(long)]
+    no_preflight: bool,
+
+    /// Local end of the tunnel: a port number, or "auto" to pick a free one
+    #[arg(short, long, default_value = "auto", value_parser = parse_local_port)]
+    local_port: u16,
+
+    /// Private key to try after ssh-agent (repeatable) [default: ~/.ssh/id_ed25519, id_ecdsa, id_rsa].
+    /// Encrypted keys ask for their passphrase; keyboard-interactive and password login come last.
+    #[arg(short, long, value_name = "PATH")]
+    identity: Vec<PathBuf>,
+
+    /// Unknown SSH hosts: "strict" refuses them, "accept-new" adds them to
+    /// ~/.ssh/known_hosts, "ask" shows the key fingerprint and asks. A changed
+    /// host key is always refused.
+    #[arg(long, value_name = "POLICY", default_value_t = HostKeyPolicy::Ask)]
+    host_key_check: HostKeyPolicy,
+
+    /// Use the installed OpenSSH client instead of the built-in one
+    /// (needed for -o options and ssh config keywords we don't read)
+    #[arg(long, conflicts_with = "direct")]
+    openssh: bool,
+
+    /// Extra ssh option passed as `-o OPTION` (repeatable), e.g. -o ProxyJump=bastion
+    #[arg(short = 'o', long = "ssh-option", value_name = "OPTION", requires = "openssh")]
+    ssh_options: Vec<String>,
+
+    /// Connect straight to a server at host:port instead of tunneling over SSH (TLS by default)
+    #[arg(long, value_name = "HOST:PORT", conflicts_with = "target")]
+    direct: Option<String>,
+
+    /// Expected server certificate for --direct (sha256:HEX, as logged by `server --tls`).
+    /// Without it the certificate is trusted on first use and remembered.
+    #[arg(long, requires = "direct")]
+    fingerprint: Option<String>,
+
+    /// Session token of a server started by hand (printed in its REMAP_READY line).
+    /// Not needed when the client launches the server itself.
+    #[arg(long, env = "REMAP_TOKEN", hide_env_values = true)]
+    token: Option<auth::Token>,
+
+    /// Use plain TCP for --direct (only for servers on a trusted network)
+    #[arg(long, requires = "direct", conflicts_with = "fingerprint")]
+    no_tls: bool,
+}
+
+/// "auto" is port 0.
+fn parse_local_port(s: &str) -> Result<u16, String> {
+    if s == "auto" {
+        return Ok(0);
     }
-    false
+    s.parse::<u16>().map_err(|_| format!("expected a port number or \"auto\", got: {s}"))
 }
 
-pub fn main() -> Result<()> {
-    dotenv::dotenv().ok();
-    env_logger::init();
+/// SSH destination as given on the command line: `[user@]host[:port]`.
+#[derive(Debug, Clone)]
+struct SshTarget {
+    user: Option<String>,
+    host: String,
+    port: Option<u16>,
+}
+
+impl FromStr for SshTarget {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (user, rest) = match s.rsplit_once('@') {
+            Some((u, r)) => (Some(u.to_string()), r),
+            None => (None, s),
+        };
+        // [v6addr]:port, host:port or plain host
+        let (host, port) = if let Some(r) = rest.strip_prefix('[') {
+            let (h, tail) = r.split_once(']').ok_or_else(|| format!("unclosed '[' in {s}"))?;
+            (h, tail.strip_prefix(':'))
+        } else {
+            match rest.split_once(':') {
+                Some((h, p)) => (h, Some(p)),
+                None => (rest, None),
+            }
+        };
+        if host.is_empty() {
+            return Err(format!("missing host in {s}"));
+        }
+        let port = port
+            .map(|p| p.parse::<u16>().map_err(|_| format!("invalid ssh port in {s}")))
+            .transpose()?;
+        Ok(SshTarget { user, host: host.to_string(), port })
+    }
+}
+
+impl SshTarget {
+    /// Destination argument for ssh; host aliases are resolved by ssh itself.
+    fn destination(&self) -> String {
+        match &self.user {
+            Some(u) => format!("{u}@{}", self.host),
+            None => self.host.clone(),
+        }
+    }
+
+    /// Common ssh arguments: user options first (ssh keeps the first value it sees),
+    /// keepalives so a dead network is noticed within ~30s, then the port if one
+    /// was given on the command line.
+    fn ssh_args(&self, options: &[String]) -> Vec<String> {
+        let mut args = Vec::new();
+        for o in options {
+            args.push("-o".to_string());
+            args.push(o.clone());
+        }
+        for o in ["ServerAliveInterval=10", "ServerAliveCountMax=3"] {
+            args.push("-o".to_string());
+            args.push(o.to_string());
+        }
+        if let Some(p) = self.port {
+            args.push("-p".to_string());
+            args.push(p.to_string());
+        }
+        args
+    }
+}
 
-    let user = std::env::var("REMAP_USER").context("REMAP_USER missing")?;
-    let host = std::env::var("REMAP_HOST").context("REMAP_HOST missing")?;
-    let port: u16 = 10100;               // consider picking a free one
-    
-    // If something already binds local_port, fail fast instead of assuming it's our tunnel.
-    if TcpStream::connect(("127.0.0.1", port)).is_ok() {
-        warn!("Local port {} already accepts connections; not starting SSH (might be the wrong process).", port);
+/// Login settings for the built-in SSH client. Without a user in the target we
+/// log in as the local user, like ssh does.
+fn connect_options(target: &SshTarget, args: &ClientArgs) -> Result<ConnectOptions> {
+    let mut opts = ConnectOptions::from_ssh_config(&target.host, target.user.as_deref(), target.port)?;
+    // -i keys come before the config's, as with ssh; jump hosts get them too.
+    let mut hop = Some(&mut opts);
+    while let Some(o) = hop {
+        o.identity_files.splice(0..0, args.identity.iter().cloned());
+        o.host_key_policy = args.host_key_check;
+        hop = o.proxy_jump.as_deref_mut();
     }
+    Ok(opts)
+}
+
+/// An ssh child process, killed when dropped.
+struct SshChild(Child);
+
+impl Drop for SshChild {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Remote server command line for this run.
+/// A plain launch picks a free display and socket, and exits once this ssh
+/// session (and with it the server's stdin) goes away; a session launch
+/// detaches and keeps running; an attach only reports an existing session.
+fn server_command(args: &ClientArgs) -> Vec<String> {
+    let mut cmd = vec![args.server.clone()];
+    let mut arg = |a: &[&str]| cmd.extend(a.iter().map(|s| s.to_string()));
+    if let Some(name) = &args.attach {
+        arg(&["--attach", name]);
+        return cmd;
+    }
+    match &args.session {
+        Some(name) => arg(&["--session", name]),
+        None => arg(&["--exit-on-eof", "--linger", &args.linger.to_string()]),
+    }
+    arg(&["--display", "auto"]);
+    if args.tcp {
+        arg(&["--port", "0"]);
+    } else {
+        arg(&["--unix-socket"]);
+    }
+    if let Some(app) = &args.app {
+        arg(&["--app", app]);
+    }
+    cmd
+}
+
+/// Run the server command on the remote host and wait for its ready line.
+fn launch_server(target: &SshTarget, options: &[String], cmd: Vec<String>) -> Result<(SshChild, ReadyInfo)> {
+    let remote_cmd = shell_words::join(cmd);
+    info!("Starting remote server: {}", remote_cmd);
 
-    // Start SSH tunnel, keep child handle so we can clean it up.
     let mut child = Command::new("ssh")
+        .args(target.ssh_args(options))
+        .args(["-T", "-o", "BatchMode=yes", &target.destination(), &remote_cmd])
+        .stdin(Stdio::piped())      // kept open: closing it tells the server to quit
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .context("failed to spawn ssh to launch the server")?;
+    let stdout = child.stdout.take().context("ssh stdout")?;
+    let child = SshChild(child);
+
+    let (ready_tx, ready_rx) = flume::bounded::<Result<ReadyInfo>>(1);
+    std::thread::spawn(move || {
+        for line in BufReader::new(stdout).lines().map_while(|l| l.ok()) {
+            if line.starts_with(READY_PREFIX) {
+                let _ = ready_tx.send(line.parse::<ReadyInfo>());
+            } else {
+                debug!("server: {line}");
+            }
+        }
+    });
+
+    match ready_rx.recv_timeout(Duration::from_secs(60)) {
+        Ok(info) => Ok((child, info?)),
+        Err(flume::RecvTimeoutError::Timeout) => anyhow::bail!("remote server did not report ready within 60s"),
+        Err(flume::RecvTimeoutError::Disconnected) => anyhow::bail!("remote server exited before it was ready"),
+    }
+}
+
+/// Like `launch_server`, over the built-in client, after `prepare_server`. One
+/// login serves for all of it, and is kept for the first forward; the command
+/// must be kept too, since dropping it closes the server's stdin.
+async fn launch_server_native(opts: ConnectOptions, mut cmd: Vec<String>, args: &ClientArgs) -> Result<(SshTunnel, RemoteCommand, ReadyInfo)> {
+    let tunnel = if args.no_deploy && args.no_preflight {
+        SshTunnel::connect(opts).await?
+    } else {
+        // Preparing needs SFTP, which the tunnel's session has no use for.
+        let ssh = Ssh::connect(opts.clone()).await?;
+        cmd[0] = prepare_server(&ssh, &opts.host, args).await?;
+        SshTunnel::from_ssh(ssh, opts).await?
+    };
+    info!("Logged in with {}", tunnel.auth_method());
+    let remote_cmd = shell_words::join(cmd);
+    info!("Starting remote server: {}", remote_cmd);
+    let mut command = tunnel.exec(&remote_cmd).await?;
+
+    let ready = tokio::time::timeout(Duration::from_secs(60), async {
+        while let Some(line) = command.next_line().await {
+            if line.starts_with(READY_PREFIX) {
+                return Some(line.parse::<ReadyInfo>());
+            }
+            debug!("server: {line}");
+        }
+        None
+    });
+    match ready.await {
+        Ok(Some(info)) => Ok((tunnel, command, info?)),
+        Ok(None) => anyhow::bail!("remote server exited before it was ready"),
+        Err(_) => anyhow::bail!("remote server did not report ready within 60s"),
+    }
+}
+
+/// Where installed servers go on the remote host, under its home directory;
+/// one directory per version, so clients of different versions can share a host.
+const SERVER_CACHE: &str = ".cache/remap";
+
+/// Get the remote host ready to launch a server: install a matching one
+/// unless --no-deploy, and check that the host has what it needs unless
+/// --no-preflight. Returns the server program to run.
+async fn prepare_server(ssh: &Ssh, host: &str, args: &ClientArgs) -> Result<String> {
+    let server = match args.no_deploy {
+        true => args.server.clone(),
+        false => deploy_server(ssh, host, args).await?,
+    };
+    if !args.no_preflight && args.attach.is_none() {
+        preflight(ssh, host, &server, args).await?;
+    }
+    Ok(server)
+}
+
+/// `args.server` if it is this client's version, else the copy in the remote
+/// cache, installed first if it is not there yet.
+async fn deploy_server(ssh: &Ssh, host: &str, args: &ClientArgs) -> Result<String> {
+    let version = env!("CARGO_PKG_VERSION");
+    let matches = |out: &Output| out.success() && out.stdout.split_whitespace().last() == Some(version);
+    if matches(&ssh.run(&shell_words::join([args.server.as_str(), "--version"])).await?) {
+        return Ok(args.server.clone());
+    }
+    let dir = ssh.real_path(".").await?.join(SERVER_CACHE).join(version);
+    let path = dir.join("server");
+    let cached = path.display().to_string();
+    if !matches(&ssh.run(&shell_words::join([cached.as_str(), "--version"])).await?) {
+        let uname = ssh.run("uname -sm").await?;
+        let (os, arch) = uname.stdout.trim().split_once(' ').unwrap_or(("unknown", ""));
+        if os != "Linux" {
+            anyhow::bail!("{host} runs {os}; the server only runs on Linux");
+        }
+        let local = server_binary(args, arch)?;
+        info!("Installing server {version} on {host} as {cached} (from {})", local.display());
+        ssh.mkdir_all(&dir, 0o755).await?;
+        let bytes = ssh.upload(&local, &path, |_| {}).await?;
+        let chmod = ssh.run(&shell_words::join(["chmod", "0755", cached.as_str()])).await?;
+        if !chmod.success() {
+            anyhow::bail!("chmod {cached} on {host} failed: {}", chmod.stderr.trim());
+        }
+        debug!("Uploaded {bytes} bytes");
+    }
+    Ok(cached)
+}
+
+/// Run `server --doctor` on the remote host and refuse to go on if the server
+/// could not start there, showing what is missing and how to fix it.
+async fn preflight(ssh: &Ssh, host: &str, server: &str, args: &ClientArgs) -> Result<()> {
+    let mut cmd = vec![server, "--doctor", "--json"];
+    if let Some(app) = &args.app {
+        cmd.extend(["--app", app.as_str()]);
+    }
+    let out = ssh.run(&shell_words::join(cmd)).await?;
+    let report: doctor::Report = match serde_json::from_str(out.stdout.trim()) {
+        Ok(r) => r,
+        Err(_) => {
+            // Older servers have no --doctor.
+            debug!("No preflight report from {host}: {}", out.stderr.trim());
+            return Ok(());
+        }
+    };
+    if !report.ok() {
+        eprint!("{report}");
+        anyhow::bail!("{host} is missing what the server needs (see above)");
+    }
+    for c in report.checks.iter().filter(|c| c.status == doctor::Status::Warn) {
+        warn!("{host}: {}: {}{}", c.name, c.detail, c.fix.as_ref().map(|f| format!(" (fix: {f})")).unwrap_or_default());
+    }
+    Ok(())
+}
+
+/// The local server binary to install on a remote host with this `arch`
+/// (as `uname -m` prints it).
+fn server_binary(args: &ClientArgs, arch: &str) -> Result<PathBuf> {
+    if let Some(path) = &args.server_binary {
+        return Ok(path.clone());
+    }
+    let exe = std::env::current_exe()?;
+    let dir = exe.parent().context("client executable has no directory")?;
+    let mut candidates = vec![dir.join(format!("server-linux-{arch}"))];
+    if cfg!(target_os = "linux") && std::env::consts::ARCH == arch {
+        candidates.push(dir.join("server"));
+    }
+    candidates.into_iter().find(|p| p.is_file()).with_context(|| {
+        format!(
+            "no server {} on the remote host, and no Linux {arch} server next to {} to install; \
+             pass --server-binary, or --no-deploy to run --server as is",
+            env!("CARGO_PKG_VERSION"),
+            exe.display()
+        )
+    })
+}
+
+/// Remote end of the tunnel.
+#[derive(Debug, Clone)]
+enum RemoteEnd {
+    Port(u16),
+    Socket(String),
+}
+
+impl std::fmt::Display for RemoteEnd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RemoteEnd::Port(p) => write!(f, "127.0.0.1:{p}"),
+            RemoteEnd::Socket(path) => f.write_str(path),
+        }
+    }
+}
+
+/// Start `ssh -N -L local:remote` and keep the child so it can be cleaned up.
+/// The remote end is `127.0.0.1:port` or a Unix socket path.
+fn spawn_tunnel(target: &SshTarget, options: &[String], local_port: u16, remote: &RemoteEnd) -> Result<SshChild> {
+    let child = Command::new("ssh")
+        .args(target.ssh_args(options))
         .args([
             "-N", "-T",
             "-o", "BatchMode=yes",
             "-o", "ExitOnForwardFailure=yes",
-            // dev-only hardening/telemetry options:
-            "-o", "ServerAliveInterval=30",
-            "-o", "ServerAliveCountMax=3",
-            // For production, keep StrictHostKeyChecking=accept-new or yes:
-            "-o", "StrictHostKeyChecking=no",
-            "-L",
-            &format!("{port}:127.0.0.1:{port}"),
-            &format!("{user}@{host}"),
+            "-L", &format!("{local_port}:{remote}"),
+            &target.destination(),
         ])
         .stdin(Stdio::null())
         .stdout(Stdio::null())
         .stderr(Stdio::inherit())   // inherit stderr so you can see SSH errors
         .spawn()
         .context("failed to spawn ssh tunnel")?;
+    Ok(SshChild(child))
+}
 
-    // Wait until the tunnel truly accepts connects (up to ~5s)
-    let local_addr = format!("127.0.0.1:{port}");
-    if !wait_tcp(&local_addr, 5000) {
-        // Try to reap SSH; if it already exited, its status will tell you why.
-        let _ = child.try_wait();
-        anyhow::bail!("SSH tunnel did not become ready on {}", local_addr);
+impl RemoteEnd {
+    fn target(&self) -> Target {
+        match self {
+            RemoteEnd::Port(p) => Target::Tcp { host: "127.0.0.1".to_string(), port: *p },
+            RemoteEnd::Socket(path) => Target::Unix(PathBuf::from(path)),
+        }
     }
-    info!("Tunnel ready on {local_addr}");
+}
 
-    // Connect application stream
-    let stream = TcpStream::connect(&local_addr).context("connect to tunnel failed")?;
-    stream.set_nodelay(true).ok();
-    stream.set_read_timeout(Some(Duration::from_secs(10))).ok();
-    stream.set_write_timeout(Some(Duration::from_secs(10))).ok();
+// helper: wait until a TCP connect to addr works (up to timeout), or ssh gives up
+fn wait_tunnel(child: &mut Child, addr: &str, total_ms: u64) -> Result<()> {
+    let start = std::time::Instant::now();
+    while start.elapsed() < Duration::from_millis(total_ms) {
+        if TcpStream::connect(addr).is_ok() { return Ok(()); }
+        if let Some(status) = child.try_wait()? {
+            anyhow::bail!("ssh exited before the tunnel was ready ({status})");
+        }
+        std::thread::sleep(Duration::from_millis(120));
+    }
+    anyhow::bail!("SSH tunnel did not become ready on {}", addr)
+}
 
-    // Split into reader/writer clones
-    let mut reader = stream.try_clone()?;
-    let writer = stream; // keep original as writer
+/// How to reach the server: straight over the network, or through an ssh forward
+/// that is (re)started as needed, either by an ssh process or by the built-in client.
+enum Route {
+    Direct { addr: String, tls: bool, fingerprint: Option<String> },
+    Tunnel { target: SshTarget, options: Vec<String>, local_port: u16, remote: RemoteEnd, child: Option<SshChild> },
+    Native {
+        rt: Handle,
+        opts: ConnectOptions,
+        local_port: u16,
+        remote: RemoteEnd,
+        tunnel: Option<(SshTunnel, SocketAddr)>,
+    },
+}
 
-    // Read initial header (use the same handle consistently)
-    let width  = reader.read_u16::<BigEndian>()?;
-    let height = reader.read_u16::<BigEndian>()?;
-    info!("Server geometry: {}x{}", width, height);
+/// An authenticated connection and the screen size the server announced.
+struct Connection {
+    stream: Stream,
+    width: u16,
+    height: u16,
+}
 
-    let (client_tx, client_rx) = flume::unbounded::<ServerEvent>();
-    let (canvas_tx, canvas_rx) = flume::unbounded::<ClientEvent>();
+struct Connector {
+    route: Route,
+    token: auth::Token,
+}
 
-    // writer thread
-    let mut w = writer;
-    std::thread::spawn(move || {
-        while let Ok(evt) = canvas_rx.recv() {
-            if evt.write_to(&mut w).is_err() { break; }
+impl Connector {
+    /// Connect (restarting the ssh forward if it is gone), authenticate and read the header.
+    fn connect(&mut self) -> Result<Connection> {
+        let result = self.try_connect();
+        if result.is_err() {
+            // The forward may be wedged (e.g. the network changed); start a fresh one next time.
+            match &mut self.route {
+                Route::Tunnel { child, .. } => *child = None,
+                // Unless it is already logging in again by itself.
+                Route::Native { tunnel, .. } => {
+                    let reconnecting = tunnel
+                        .as_ref()
+                        .is_some_and(|(t, _)| matches!(*t.status().borrow(), TunnelStatus::Reconnecting { .. }));
+                    if !reconnecting {
+                        *tunnel = None;
+                    }
+                }
+                Route::Direct { .. } => {}
+            }
         }
-    });
+        result
+    }
 
-    // reader thread
-    let mut r = reader;
-    std::thread::spawn(move || {
-        while let Ok(reply) = ServerEvent::read_from(&mut r) {
-            let _ = client_tx.send(reply);
+    fn try_connect(&mut self) -> Result<Connection> {
+        let mut stream = match &mut self.route {
+            Route::Direct { addr, tls: true, fingerprint } => {
+                info!("Connecting to server at {}", addr);
+                tls::connect(addr, fingerprint.as_deref())?
+            }
+            Route::Direct { addr, tls: false, .. } => {
+                info!("Connecting to server at {}", addr);
+                Stream::Tcp(TcpStream::connect(&*addr).with_context(|| format!("connect to {addr} failed"))?)
+            }
+            Route::Tunnel { target, options, local_port, remote, child } => {
+                let local_addr = format!("127.0.0.1:{local_port}");
+                let alive = match child {
+                    Some(c) => c.0.try_wait()?.is_none(),
+                    None => false,
+                };
+                if !alive {
+                    *child = None;
+                    let mut c = spawn_tunnel(target, options, *local_port, remote)?;
+                    wait_tunnel(&mut c.0, &local_addr, 5000)?;
+                    info!("Tunnel ready on {local_addr} -> {}:{}", target.host, remote);
+                    *child = Some(c);
+                }
+                Stream::Tcp(TcpStream::connect(&local_addr).with_context(|| format!("connect to {local_addr} failed"))?)
+            }
+            Route::Native { rt, opts, local_port, remote, tunnel } => {
+                let addr = match tunnel {
+                    Some((t, addr)) => {
+                        if let TunnelStatus::Reconnecting { attempt, error } = &*t.status().borrow() {
+                            let why = error.as_deref().map(|e| format!(": {e}")).unwrap_or_default();
+                            anyhow::bail!("SSH session to {} lost, reconnecting (attempt {attempt}){why}", opts.host);
+                        }
+                        *addr
+                    }
+                    None => {
+                        let (t, addr) = rt.block_on(async {
+                            let mut t = SshTunnel::connect(opts.clone()).await?;
+                            let addr = t.forward(("127.0.0.1", *local_port), remote.target()).await?;
+                            Ok::<_, tunnel::Error>((t, addr))
+                        })?;
+                        info!("Tunnel ready on {addr} -> {}:{}", opts.host, remote);
+                        *tunnel = Some((t, addr));
+                        addr
+                    }
+                };
+                Stream::Tcp(TcpStream::connect(addr).with_context(|| format!("connect to {addr} failed"))?)
+            }
+        };
+        stream.set_nodelay(true).ok();
+        // Bounded waits for the handshake; afterwards an idle screen may send nothing for a long time.
+        stream.set_read_timeout(Some(Duration::from_secs(10))).ok();
+        stream.set_write_timeout(Some(Duration::from_secs(10))).ok();
+
+        // Prove we know the session token before anything else
+        auth::respond(&mut stream, &self.token)?;
+        let width = stream.read_u16::<BigEndian>()?;
+        let height = stream.read_u16::<BigEndian>()?;
+        stream.set_read_timeout(None).ok();
+        Ok(Connection { stream, width, height })
+    }
+}
+
+/// Connection state reported to the UI.
+enum Link {
+    Up { width: u16, height: u16 },
+    Down(String),
+}
+
+const RECONNECT_MIN: Duration = Duration::from_millis(500);
+const RECONNECT_MAX: Duration = Duration::from_secs(30);
+
+/// Forward canvas input to the server and server events to the canvas. When the
+/// connection drops, reconnect with exponential backoff, then ask for a full
+/// frame. Returns once the canvas is gone.
+fn run_connection(
+    mut connector: Connector,
+    first: Connection,
+    canvas_rx: flume::Receiver<ClientEvent>,
+    client_tx: flume::Sender<ServerEvent>,
+    link_tx: flume::Sender<Link>,
+) {
+    let mut next = Some(first);
+    loop {
+        let conn = match next.take() {
+            Some(c) => c,
+            None => {
+                let mut delay = RECONNECT_MIN;
+                let mut attempt = 1;
+                loop {
+                    let _ = link_tx.send(Link::Down(format!("reconnecting (attempt {attempt})...")));
+                    match connector.connect() {
+                        Ok(c) => break c,
+                        Err(e) => warn!("Reconnect attempt {attempt} failed: {e:#}"),
+                    }
+                    let _ = link_tx.send(Link::Down(format!("disconnected, retrying in {:.1}s", delay.as_secs_f32())));
+                    // Waiting on the input channel drops stale input and notices the window closing.
+                    let until = std::time::Instant::now() + delay;
+                    loop {
+                        match canvas_rx.recv_deadline(until) {
+                            Ok(_) => continue,
+                            Err(flume::RecvTimeoutError::Timeout) => break,
+                            Err(flume::RecvTimeoutError::Disconnected) => return,
+                        }
+                    }
+                    delay = (delay * 2).min(RECONNECT_MAX);
+                    attempt += 1;
+                }
+            }
+        };
+        info!("Connected ({}x{})", conn.width, conn.height);
+        let _ = link_tx.send(Link::Up { width: conn.width, height: conn.height });
+        canvas_rx.drain();
+
+        let Connection { stream: mut writer, width, height } = conn;
+        let mut reader = match writer.try_clone() {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("Cannot clone connection: {e}");
+                continue;
+            }
+        };
+
+        // reader thread; tells us when the server goes away
+        let (dead_tx, dead_rx) = flume::bounded::<()>(1);
+        let events = client_tx.clone();
+        std::thread::spawn(move || {
+            while let Ok(reply) = ServerEvent::read_from(&mut reader) {
+                if events.send(reply).is_err() { break; }
+            }
+            let _ = dead_tx.send(());
+        });
+
+        // DesktopSize lets the server tell us when it resizes its screen.
+        let encodings = ClientEvent::SetEncodings(vec![Encoding::Raw, Encoding::DesktopSize]);
+        // Whatever we had may be stale: start from a full frame.
+        let full = ClientEvent::FramebufferUpdateRequest { incremental: false, x: 0, y: 0, width, height };
+        let mut alive = encodings.write_to(&mut writer).is_ok() && full.write_to(&mut writer).is_ok();
+
+        while alive {
+            enum Next { Input(ClientEvent), Closed, Dead }
+            let next = flume::Selector::new()
+                .recv(&canvas_rx, |r| r.map(Next::Input).unwrap_or(Next::Closed))
+                .recv(&dead_rx, |_| Next::Dead)
+                .wait();
+            match next {
+                Next::Input(evt) => alive = evt.write_to(&mut writer).is_ok(),
+                Next::Dead => alive = false,
+                Next::Closed => {
+                    let _ = writer.shutdown();
+                    return;
+                }
+            }
         }
         info!("Server disconnected");
+        let _ = writer.shutdown();
+    }
+}
+
+pub fn main() -> Result<()> {
+    dotenv::dotenv().ok();
+    env_logger::init();
+
+    let mut args = ClientArgs::parse();
+    if args.openssh {
+        for key in &args.identity {
+            args.ssh_options.push(format!("IdentityFile={}", key.display()));
+        }
+        match args.host_key_check {
+            HostKeyPolicy::Strict => args.ssh_options.push("StrictHostKeyChecking=yes".to_string()),
+            HostKeyPolicy::AcceptNew => args.ssh_options.push("StrictHostKeyChecking=accept-new".to_string()),
+            HostKeyPolicy::Ask => {}
+        }
+    }
+    // The built-in SSH client runs on tokio; everything else stays on plain threads.
+    let rt = tokio::runtime::Runtime::new()?;
+
+    if args.list {
+        let target = args.target.as_ref().context("--list needs a remote host")?;
+        let list_cmd = shell_words::join([args.server.as_str(), "--list"]);
+        if !args.openssh {
+            let opts = connect_options(target, &args)?;
+            return rt.block_on(async {
+                let tunnel = SshTunnel::connect(opts).await?;
+                let mut command = tunnel.exec(&list_cmd).await?;
+                command.close_stdin();
+                while let Some(line) = command.next_line().await {
+                    println!("{line}");
+                }
+                Ok(tunnel.shutdown().await?)
+            });
+        }
+        let status = Command::new("ssh")
+            .args(target.ssh_args(&args.ssh_options))
+            .args(["-T", &target.destination(), &list_cmd])
+            .status()
+            .context("failed to run ssh")?;
+        std::process::exit(status.code().unwrap_or(1));
+    }
+
+    // Either connect directly, or start the server over SSH and tunnel to it.
+    let mut launcher: Option<SshChild> = None;
+    let mut remote_command: Option<RemoteCommand> = None;
+    let mut token = args.token.clone();
+    let route = match (&args.direct, &args.target) {
+        (Some(direct), _) => Route::Direct {
+            addr: direct.clone(),
+            tls: !args.no_tls,
+            fingerprint: args.fingerprint.clone(),
+        },
+        (None, Some(target)) if !args.openssh => {
+            let opts = connect_options(target, &args)?;
+            let mut tunnel = None;
+            let remote = match (args.remote_port, &args.remote_socket) {
+                (Some(p), _) => RemoteEnd::Port(p),
+                (None, Some(path)) => RemoteEnd::Socket(path.clone()),
+                (None, None) => {
+                    let cmd = server_command(&args);
+                    let (t, command, ready) = rt.block_on(launch_server_native(opts.clone(), cmd, &args))?;
+                    tunnel = Some(t);
+                    remote_command = Some(command);
+                    token = ready.token.or(token);
+                    let remote = match ready.socket {
+                        Some(path) => RemoteEnd::Socket(path.display().to_string()),
+                        None => RemoteEnd::Port(ready.port),
+                    };
+                    info!("Remote server ready on {} (display :{})", remote, ready.display);
+                    remote
+                }
+            };
+            let local_port = args.local_port;
+            // Reuse the launch session for the first forward rather than logging in twice.
+            let tunnel = match tunnel {
+                Some(mut t) => {
+                    let addr = rt.block_on(t.forward(("127.0.0.1", local_port), remote.target()))?;
+                    info!("Tunnel ready on {addr} -> {}:{}", opts.host, remote);
+                    Some((t, addr))
+                }
+                None => None,
+            };
+            Route::Native { rt: rt.handle().clone(), opts, local_port, remote, tunnel }
+        }
+        (None, Some(target)) => {
+            let local_port = match args.local_port {
+                0 => util::free_local_port().context("no free local port")?,
+                p => {
+                    // Don't assume whatever is bound there is our tunnel.
+                    if util::port_is_listening(p) {
+                        anyhow::bail!("Local port {} is already in use; pick another or use --local-port auto", p);
+                    }
+                    p
+                }
+            };
+            let remote = match (args.remote_port, &args.remote_socket) {
+                (Some(p), _) => RemoteEnd::Port(p),
+                (None, Some(path)) => RemoteEnd::Socket(path.clone()),
+                (None, None) => {
+                    let (child, ready) = launch_server(target, &args.ssh_options, server_command(&args))?;
+                    launcher = Some(child);
+                    token = ready.token.or(token);
+                    let remote = match ready.socket {
+                        Some(path) => RemoteEnd::Socket(path.display().to_string()),
+                        None => RemoteEnd::Port(ready.port),
+                    };
+                    info!("Remote server ready on {} (display :{})", remote, ready.display);
+                    remote
+                }
+            };
+            Route::Tunnel {
+                target: target.clone(),
+                options: args.ssh_options.clone(),
+                local_port,
+                remote,
+                child: None,
+            }
+        }
+        (None, None) => unreachable!("clap requires a target or --direct"),
+    };
+
+    let token = token.context("no session token: pass --token or set REMAP_TOKEN (see the server's REMAP_READY line)")?;
+    let mut connector = Connector { route, token };
+    let first = connector.connect()?;
+    let (width, height) = (first.width, first.height);
+    info!("Server geometry: {}x{}", width, height);
+
+    let (client_tx, client_rx) = flume::unbounded::<ServerEvent>();
+    let (canvas_tx, canvas_rx) = flume::unbounded::<ClientEvent>();
+    let (link_tx, link_rx) = flume::unbounded::<Link>();
+
+    // Connection thread: pumps events both ways and reconnects when the link drops
+    let manager = std::thread::spawn(move || {
+        run_connection(connector, first, canvas_rx, client_tx, link_tx)
     });
 
     // UI loop
-    info!("Connecting to server at 127.0.0.1:{}", port);
     let mut canvas = Canvas::new(canvas_tx, client_rx)?;
     canvas.resize(width as u32, height as u32)?;
-    canvas.request_update(false)?;
 
     while canvas.is_open() {
+        for link in link_rx.try_iter() {
+            match link {
+                Link::Up { width, height } => {
+                    // The screen may have been resized to fit the window before the link dropped
+                    if (width as u32, height as u32) != canvas.framebuffer_size() {
+                        canvas.resize(width as u32, height as u32)?;
+                    }
+                    canvas.set_status(None);
+                }
+                Link::Down(status) => canvas.set_status(Some(status)),
+            }
+        }
         canvas.handle_input()?;
+        canvas.handle_resize()?;
         canvas.handle_server_events()?;
         canvas.update()?;
     }
 
-    // Cleanly terminate the ssh tunnel
-    // (If you want to keep it, omit this.)
-    #[allow(unused_must_use)]
-    {
-        child.kill();
-        child.wait();
+    // Closing the canvas ends the connection thread, which drops the tunnel.
+    drop(canvas);
+    let _ = manager.join();
+
+    // Then the launcher; the remote server exits with it unless it is a named session.
+    drop(launcher);
+    drop(remote_command);
+    if let Some(name) = args.session.as_ref().or(args.attach.as_ref()) {
+        info!("Detached from session {name}; reattach with --attach {name}");
     }
 
+    // Forwards still draining on blocking threads must not hold up exit.
+    rt.shutdown_timeout(Duration::from_secs(2));
     Ok(())
 }
@@ -8,21 +8,31 @@
 //! capture rectangles via remap::capture::Capture and send to client,
 //! handle input via remap::input::Input, and clean up on Ctrl+C.
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use remap::input::Input;
 
 #[cfg(target_os = "linux")]
 mod linux_impl {
     use super::*;
     use clap::Parser;
-    use log::{debug, info, trace};
-    use std::io::Write;
+    use log::{debug, info, trace, warn};
+    use std::io::{BufRead, Write};
     use std::net::TcpListener;
-    use std::process::Command;
-    use std::time::Instant;
-
-    use remap::{util, ClientEvent, Message, Rec, ServerEvent};
+    use std::path::{Path, PathBuf};
+    use std::process::{Child, Command};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use flume::RecvTimeoutError;
+    use remap::{auth, doctor, util, ClientEvent, Encoding, Message, ReadyInfo, Rec, ServerEvent};
     use remap::capture::Capture;
+    use remap::config::{runtime_dir, BackendKind, DisplaySpec, KeyboardConfig, ServerConfig};
+    use remap::display::{self, Display};
+    use remap::pacer::{FramePacer, PacerConfig};
+    use remap::session::{self, Session};
+    use remap::tls::TlsServer;
+    use remap::transport::{Listener, Stream};
 
     // Client pointer bit masks (must match the client)
     const BTN_LEFT:       u8 = 0x01;
@@ -32,238 +42,339 @@ mod linux_impl {
     const BTN_WHEEL_DOWN: u8 = 0x10;
 
     /// Remap server (Linux only)
+    ///
+    /// Settings come from the config file and can be overridden by the flags below.
     #[derive(Parser, Debug)]
     #[command(author, version, about = "Remap server (Linux only)", long_about = None)]
     struct ServerArgs {
-        /// X display number (e.g. 100 -> :100)
-        #[arg(short, long, default_value_t = 100)]
-        display: u32,
-
-        /// App (and args) to run
-        #[arg(short, long, default_value = "xterm -fa 'Monospace' -fs 14 -geometry 110x24")]
-        app: String,
-
-        /// TCP port to listen on
-        #[arg(short, long, default_value_t = 10100)]
-        port: u16,
+        /// Config file [default: ~/.config/remap/server.toml, if present]
+        #[arg(short, long)]
+        config: Option<PathBuf>,
+
+        /// X display number (e.g. 100 -> :100), or "auto" for the first free one from :100 [default: auto]
+        #[arg(short, long)]
+        display: Option<DisplaySpec>,
+
+        /// What runs the display: "xvfb", "xorg" (Xorg with the dummy driver, which
+        /// can resize freely) or "existing" (the one in $DISPLAY) [default: xvfb]
+        #[arg(long, value_name = "BACKEND")]
+        display_backend: Option<BackendKind>,
+
+        /// App (and args) to run, or the name of a preset in the config file [default: xterm].
+        /// "desktop" runs no app and shows the whole screen.
+        #[arg(short, long)]
+        app: Option<String>,
+
+        /// Address to listen on [default: 127.0.0.1]
+        #[arg(short, long)]
+        bind: Option<String>,
+
+        /// TCP port to listen on, 0 for any free port [default: 10100]
+        #[arg(short, long)]
+        port: Option<u16>,
+
+        /// Virtual screen as WIDTHxHEIGHT[xDEPTH] [default: 1280x800x24]
+        #[arg(short, long, value_parser = parse_screen)]
+        screen: Option<(u16, u16, Option<u8>)>,
+
+        /// Screen DPI [default: 96]
+        #[arg(long)]
+        dpi: Option<u16>,
+
+        /// Keyboard layout passed to setxkbmap [default: us]
+        #[arg(long)]
+        layout: Option<String>,
+
+        /// Maximum frames per second sent to the client [default: 30]
+        #[arg(long)]
+        max_fps: Option<u32>,
+
+        /// Frames per second to poll at once the screen is idle [default: 2]
+        #[arg(long)]
+        idle_fps: Option<u32>,
+
+        /// Milliseconds without screen changes before dropping to --idle-fps [default: 2000]
+        #[arg(long)]
+        idle_after_ms: Option<u64>,
+
+        /// Serve TLS so clients can connect directly without an SSH tunnel
+        #[arg(long)]
+        tls: bool,
+
+        /// Listen on a Unix socket in $XDG_RUNTIME_DIR/remap instead of a TCP port
+        #[arg(long, conflicts_with_all = ["tls", "port", "bind"])]
+        unix_socket: bool,
+
+        /// Shut down when stdin closes (used when the client launches us over SSH)
+        #[arg(long, conflicts_with = "session")]
+        exit_on_eof: bool,
+
+        /// With --exit-on-eof: once stdin closes, keep running while a client is connected,
+        /// and for this many seconds without one, so a dropped client can reconnect
+        #[arg(long, value_name = "SECS", default_value_t = 0, requires = "exit_on_eof")]
+        linger: u64,
+
+        /// Run as a named session that outlives the connection that started it.
+        /// If the session already exists, print its ready line instead.
+        #[arg(long, value_name = "NAME")]
+        session: Option<String>,
+
+        /// List running sessions and exit
+        #[arg(long, exclusive = true)]
+        list: bool,
+
+        /// Print the ready line of a running session (for the client to connect) and exit
+        #[arg(long, value_name = "NAME", exclusive = true)]
+        attach: Option<String>,
+
+        /// Stop a running session and exit
+        #[arg(long, value_name = "NAME", exclusive = true)]
+        kill: Option<String>,
+
+        /// Check that this host has what the server needs, print a report and exit
+        #[arg(long, conflicts_with_all = ["session", "exit_on_eof"])]
+        doctor: bool,
+
+        /// With --doctor: print the report as JSON
+        #[arg(long, requires = "doctor")]
+        json: bool,
 
         /// Increase verbosity (-v, -vv, -vvv)
         #[arg(short, long, action = clap::ArgAction::Count)]
         verbose: u8,
     }
 
-    fn set_xkb_base(display: u32) {
-        let _ = Command::new("setxkbmap")
-            .env("DISPLAY", format!(":{display}"))
-            .args(["-rules","base","-model","pc105","-layout","us","-option",""])
-            .status();
+    /// Child processes (the app) and the display, torn down on Ctrl+C or when the
+    /// launcher goes away, plus files (sockets) to remove at that point.
+    #[derive(Clone, Default)]
+    struct Children {
+        procs: Arc<Mutex<Vec<(String, Child)>>>,
+        display: Arc<Mutex<Option<Display>>>,
+        files: Arc<Mutex<Vec<PathBuf>>>,
     }
 
+    impl Children {
+        fn push(&self, name: &str, child: Child) {
+            self.procs.lock().unwrap().push((name.to_string(), child));
+        }
 
+        fn set_display(&self, display: Display) {
+            *self.display.lock().unwrap() = Some(display);
+        }
 
-    // --- RANDR helpers: pick or create a mode, best-effort ---
-
-    fn run_cmd(env_display: &str, cmd: &str, args: &[&str]) -> std::io::Result<std::process::Output> {
-        std::process::Command::new(cmd)
-            .env("DISPLAY", env_display)
-            .args(args)
-            .output()
-    }
-
-    #[derive(Debug, Clone, Copy)]
-    struct Mode { w: u16, h: u16 }
-
-    fn parse_xrandr_query(out: &str) -> (Option<String>, Vec<Mode>) {
-        // Return (first_connected_output_name, all_modes_for_it)
-        let mut output_name: Option<String> = None;
-        let mut modes: Vec<Mode> = Vec::new();
+        fn remove_on_exit(&self, path: PathBuf) {
+            self.files.lock().unwrap().push(path);
+        }
 
-        for line in out.lines() {
-            // match "<name> connected"
-            if let Some((name, _rest)) = line.split_once(" connected") {
-                if output_name.is_none() {
-                    output_name = Some(name.trim().to_string());
+        /// Kill everything, most recently started first, and exit.
+        fn shutdown(&self) -> ! {
+            if let Ok(mut children) = self.procs.lock() {
+                for (name, p) in children.iter_mut().rev() {
+                    let _ = p.kill();
+                    let _ = p.wait();
+                    info!("{} stopped.", name);
                 }
-                continue;
             }
-
-            // indented lines with modes like "   1024x768  60.00*"
-            let t = line.trim_start();
-            if let Some(first) = t.split_whitespace().next() {
-                if let Some((w, h)) = first.split_once('x') {
-                    if let (Ok(wi), Ok(hi)) = (w.parse::<u16>(), h.parse::<u16>()) {
-                        modes.push(Mode { w: wi, h: hi });
-                    }
+            if let Ok(mut display) = self.display.lock() {
+                if let Some(d) = display.as_mut() {
+                    d.stop();
+                    info!("Display :{} stopped.", d.number());
+                }
+            }
+            if let Ok(files) = self.files.lock() {
+                for f in files.iter() {
+                    let _ = std::fs::remove_file(f);
                 }
             }
+            std::process::exit(0);
         }
-        (output_name, modes)
     }
 
-    fn pick_closest(modes: &[Mode], want: Mode) -> Option<Mode> {
-        use std::cmp::Ordering;
-        modes.iter().copied().min_by(|a, b| {
-            let dw_a = i32::from(a.w) - i32::from(want.w);
-            let dh_a = i32::from(a.h) - i32::from(want.h);
-            let da = dw_a * dw_a + dh_a * dh_a;
-
-            let dw_b = i32::from(b.w) - i32::from(want.w);
-            let dh_b = i32::from(b.h) - i32::from(want.h);
-            let db = dw_b * dw_b + dh_b * dh_b;
-
-            da.cmp(&db)
-        })
+    impl ServerArgs {
+        /// Load the config file and layer the command line on top of it.
+        fn into_config(self) -> Result<ServerConfig> {
+            let mut cfg = match &self.config {
+                Some(p) => ServerConfig::load(p)?,
+                None => ServerConfig::load_default()?,
+            };
+            let app = self.app.unwrap_or_else(|| cfg.app.clone());
+            cfg.select_app(&app);
+
+            if let Some(v) = self.display { cfg.display = v; }
+            if let Some(v) = self.display_backend { cfg.display_backend = v; }
+            if let Some(v) = self.bind { cfg.bind = v; }
+            if let Some(v) = self.port { cfg.port = v; }
+            if let Some((w, h, d)) = self.screen {
+                cfg.width = w;
+                cfg.height = h;
+                if let Some(d) = d { cfg.depth = d; }
+            }
+            if let Some(v) = self.dpi { cfg.dpi = v; }
+            if let Some(v) = self.layout { cfg.keyboard.layout = v; }
+            if let Some(v) = self.max_fps { cfg.max_fps = v; }
+            if let Some(v) = self.idle_fps { cfg.idle_fps = v; }
+            if let Some(v) = self.idle_after_ms { cfg.idle_after_ms = v; }
+            if self.tls { cfg.tls = true; }
+            if self.unix_socket { cfg.unix_socket = true; }
+            Ok(cfg)
+        }
     }
 
-    fn try_set_existing_mode(display: u32, out_name: &str, m: Mode) -> bool {
-        let disp = format!(":{}", display);
-        // Prefer --output ... --mode WxH (works on most servers)
-        let args = ["--output", out_name, "--mode", &format!("{}x{}", m.w, m.h)];
-        if let Ok(o) = run_cmd(&disp, "xrandr", &args) {
-            if o.status.success() {
-                return true;
-            }
+    /// Whether a client is connected, and since when nobody has been.
+    #[derive(Clone)]
+    struct Presence(Arc<Mutex<(usize, Instant)>>);
+
+    impl Presence {
+        fn new() -> Self {
+            Presence(Arc::new(Mutex::new((0, Instant::now()))))
         }
-        // Fallback: -s WxH
-        let args2 = ["-s", &format!("{}x{}", m.w, m.h)];
-        if let Ok(o) = run_cmd(&disp, "xrandr", &args2) {
-            if o.status.success() {
-                return true;
-            }
+        /// Count a client as connected until the returned guard is dropped.
+        fn connect(&self) -> PresenceGuard {
+            self.0.lock().unwrap().0 += 1;
+            PresenceGuard(self.clone())
+        }
+        /// How long nobody has been connected, or None while someone is.
+        fn idle_for(&self) -> Option<Duration> {
+            let p = self.0.lock().unwrap();
+            (p.0 == 0).then(|| p.1.elapsed())
         }
-        false
     }
 
-    fn try_create_and_set_mode(display: u32, out_name: &str, want: Mode) -> bool {
-        let disp = format!(":{}", display);
+    struct PresenceGuard(Presence);
 
-        // Generate a modeline with cvt (60 Hz). Use "cvt W H 60".
-        let cvt_out = match run_cmd(&disp, "cvt", &[&want.w.to_string(), &want.h.to_string(), "60"]) {
-            Ok(o) if o.status.success() => String::from_utf8_lossy(&o.stdout).into_owned(),
-            _ => return false,
-        };
+    impl Drop for PresenceGuard {
+        fn drop(&mut self) {
+            let mut p = (self.0).0.lock().unwrap();
+            p.0 = p.0.saturating_sub(1);
+            p.1 = Instant::now();
+        }
+    }
 
-        // Parse: last line like:
-        // Modeline "995x781_60.00"  49.50  995 1040 1144 1292  781 784 787 806  -hsync +vsync
-        let mut name = None::<String>;
-        let mut params = None::<String>;
-        for line in cvt_out.lines().rev() {
-            if let Some(rest) = line.trim().strip_prefix("Modeline ") {
-                // split into "name" and the rest
-                if let Some(idx) = rest.find('"') {
-                    let rest2 = &rest[idx+1..];
-                    if let Some(idx2) = rest2.find('"') {
-                        name = Some(rest2[..idx2].to_string());
-                        params = Some(rest2[idx2+1..].trim().to_string());
-                        break;
-                    }
+    /// Set in the environment of the detached session process.
+    const DETACHED_ENV: &str = "REMAP_SESSION_DETACHED";
+
+    /// Re-run ourselves in a new process session with output going to a log file,
+    /// relay its ready line, and return. The session keeps running after the ssh
+    /// connection that launched us closes.
+    fn detach(name: &str) -> Result<()> {
+        use std::os::unix::process::CommandExt;
+        use std::process::Stdio;
+
+        session::check_name(name)?;
+        let dir = session::sessions_dir();
+        std::fs::create_dir_all(&dir)?;
+        let log_path = dir.join(format!("{name}.log"));
+        let log = std::fs::File::create(&log_path)
+            .with_context(|| format!("create {}", log_path.display()))?;
+
+        let mut cmd = Command::new(std::env::current_exe()?);
+        cmd.args(std::env::args_os().skip(1))
+            .env(DETACHED_ENV, "1")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(log);
+        unsafe {
+            cmd.pre_exec(|| {
+                if libc::setsid() < 0 {
+                    return Err(std::io::Error::last_os_error());
                 }
+                Ok(())
+            });
+        }
+        let mut child = cmd.spawn().context("failed to start the session process")?;
+        let stdout = child.stdout.take().context("session stdout")?;
+
+        for line in std::io::BufReader::new(stdout).lines().map_while(|l| l.ok()) {
+            if line.parse::<ReadyInfo>().is_ok() {
+                println!("{line}");
+                return Ok(());
             }
         }
-        let (name, params) = match (name, params) {
-            (Some(n), Some(p)) => (n, p),
-            _ => return false,
-        };
+        let status = child.wait()?;
+        anyhow::bail!("session {name} failed to start ({status}); see {}", log_path.display())
+    }
 
-        // xrandr --newmode <name> <params...>
-        let mut tokens: Vec<&str> = Vec::new();
-        tokens.push("--newmode");
-        tokens.push(&name);
-        // Split params by whitespace preserving tokens
-        for t in params.split_whitespace() {
-            tokens.push(t);
+    fn list_sessions() -> Result<()> {
+        let sessions = session::list()?;
+        if sessions.is_empty() {
+            println!("No sessions.");
+            return Ok(());
         }
-        if let Ok(o) = run_cmd(&disp, "xrandr", &tokens) {
-            if !o.status.success() {
-                return false;
-            }
-        } else {
-            return false;
+        println!("{:<16} {:>8} {:>8} {:>8}  APP", "NAME", "DISPLAY", "PID", "AGE");
+        for s in sessions {
+            println!("{:<16} {:>8} {:>8} {:>8}  {}", s.name, format!(":{}", s.display), s.pid, s.age(), s.app);
         }
+        Ok(())
+    }
 
-        // xrandr --addmode <output> <name>
-        if let Ok(o) = run_cmd(&disp, "xrandr", &["--addmode", out_name, &name]) {
-            if !o.status.success() {
-                return false;
+    fn parse_screen(s: &str) -> std::result::Result<(u16, u16, Option<u8>), String> {
+        let parts: Vec<&str> = s.split('x').collect();
+        let num = |p: &str| p.parse::<u16>().map_err(|_| format!("invalid screen size: {s}"));
+        match parts.as_slice() {
+            [w, h] => Ok((num(w)?, num(h)?, None)),
+            [w, h, d] => {
+                let d = d.parse::<u8>().map_err(|_| format!("invalid screen depth: {s}"))?;
+                Ok((num(w)?, num(h)?, Some(d)))
             }
-        } else {
-            return false;
+            _ => Err(format!("expected WIDTHxHEIGHT[xDEPTH], got: {s}")),
         }
+    }
 
-        // xrandr --output <output> --mode <name>
-        if let Ok(o) = run_cmd(&disp, "xrandr", &["--output", out_name, "--mode", &name]) {
-            return o.status.success();
+    fn set_xkb_base(display: u32, auth: Option<&Path>, kb: &KeyboardConfig) {
+        let mut cmd = Command::new("setxkbmap");
+        cmd.env("DISPLAY", format!(":{display}"));
+        if let Some(auth) = auth {
+            cmd.env("XAUTHORITY", auth);
         }
-        false
+        let _ = cmd
+            .args([
+                "-rules", &kb.rules,
+                "-model", &kb.model,
+                "-layout", &kb.layout,
+                "-variant", &kb.variant,
+                "-option", &kb.options,
+            ])
+            .status();
     }
 
-    fn try_resize_display_best_effort(display: u32, w: u16, h: u16) {
-        let disp = format!(":{}", display);
-        let want = Mode { w, h };
 
-        // 1) Query current outputs + modes
-        let (out_name, all_modes) = match run_cmd(&disp, "xrandr", &["-q"]) {
-            Ok(o) if o.status.success() => {
-                let s = String::from_utf8_lossy(&o.stdout);
-                parse_xrandr_query(&s)
-            }
-            _ => {
-                log::debug!("xrandr -q not available or failed; cannot resize.");
-                return;
-            }
-        };
 
-        let Some(out) = out_name else {
-            log::debug!("No connected output reported by xrandr; cannot resize.");
-            return;
+    /// Maximize the app window to the screen, retrying while the app settles
+    /// on a size; returns its geometry afterwards.
+    fn fit_window(display: u32, auth: Option<&Path>, xid: i32) -> remap::Geometry {
+        let Ok((sw, sh)) = util::screen_size(display, auth) else {
+            info!("Could not read screen size; skipping maximize");
+            return util::get_window_geometry(xid, display, auth);
         };
+        info!("Target screen size: {}x{}", sw, sh);
 
-        // 2) If exact mode exists, use it
-        if all_modes.iter().any(|m| m.w == w && m.h == h) {
-            if try_set_existing_mode(display, &out, want) {
-                log::info!("X RANDR: switched to {}x{}", w, h);
-                return;
-            }
-            // fallthrough if failed
-        }
-
-        // 3) Try to create the mode (may fail on some Xvfb builds)
-        if try_create_and_set_mode(display, &out, want) {
-            log::info!("X RANDR: created and switched to {}x{}", w, h);
-            return;
+        // 1) Politely ask any WM to maximize/fullscreen (EWMH). Harmless on Xvfb-without-WM.
+        if let Err(e) = util::maximize_window(display, auth, xid as u32) {
+            debug!("maximize_window (EWMH) ignored/failed: {:?}", e);
         }
-
-        // 4) Fallback to closest available
-        if let Some(closest) = pick_closest(&all_modes, want) {
-            if try_set_existing_mode(display, &out, closest) {
-                log::info!(
-                    "X RANDR: requested {}x{} not available; using closest {}x{}",
-                    w, h, closest.w, closest.h
-                );
-                return;
+        std::thread::sleep(std::time::Duration::from_millis(120));
+
+        // 2) Force move+resize (works even with no WM). Retry a few times in case the app
+        //    does an initial layout pass and resizes itself once after mapping.
+        for attempt in 1..=8 {
+            let _ = util::force_move_resize(display, auth, xid as u32, 0, 0, sw, sh)
+                .or_else(|_| util::resize_window_to(display, auth, xid as u32, sw, sh));
+
+            std::thread::sleep(std::time::Duration::from_millis(80));
+
+            let g = util::get_window_geometry(xid, display, auth);
+            if g.width == sw as i32 && g.height == sh as i32 {
+                info!("Window matched screen on attempt {}: {}x{}", attempt, g.width, g.height);
+                break;
+            } else {
+                debug!("still {}x{}, want {}x{} (attempt {})", g.width, g.height, sw, sh, attempt);
             }
         }
 
-        log::debug!("X RANDR: unable to set {}x{} (kept current mode)", w, h);
-    }
-
-    // Optional: if you want to try resizing the X screen when the client resizes.
-    #[allow(dead_code)]
-    fn try_resize_display(display: u32, w: u16, h: u16) {
-        let mode = format!("{}x{}", w, h);
-        let disp = format!(":{}", display);
-        match Command::new("xrandr")
-            .env("DISPLAY", &disp)
-            .args(["-s", &mode])
-            .output()
-        {
-            Ok(o) if o.status.success() => info!("X RANDR: set mode {mode}"),
-            Ok(o) => {
-                let err = String::from_utf8_lossy(&o.stderr);
-                debug!("xrandr failed ({}): {}", o.status, err.trim());
-            }
-            Err(e) => debug!("xrandr not available: {e}"),
-        }
+        // 3) Re-read final geometry for logging
+        let geometry = util::get_window_geometry(xid, display, auth);
+        info!("Window geometry after maximize: {:?} (server)", geometry);
+        geometry
     }
 
     pub fn main_linux() -> Result<()> {
@@ -271,195 +382,339 @@ mod linux_impl {
         env_logger::init();
 
         let args = ServerArgs::parse();
-        let display = args.display;
-        let port = args.port;
+        if args.list {
+            return list_sessions();
+        }
+        if let Some(name) = &args.attach {
+            let s = Session::find(name)?.with_context(|| format!("no session named {name}"))?;
+            println!("{}", s.ready_info()?);
+            return Ok(());
+        }
+        if let Some(name) = &args.kill {
+            let s = Session::find(name)?.with_context(|| format!("no session named {name}"))?;
+            unsafe { libc::kill(s.pid as i32, libc::SIGTERM) };
+            info!("Stopped session {} (pid {})", s.name, s.pid);
+            return Ok(());
+        }
+        if args.doctor {
+            let json = args.json;
+            let report = doctor::check(&args.into_config()?);
+            if json {
+                println!("{}", serde_json::to_string(&report)?);
+            } else {
+                print!("{report}");
+            }
+            std::process::exit(if report.ok() { 0 } else { 1 });
+        }
+        let session = args.session.clone();
+        if let Some(name) = &session {
+            if let Some(s) = Session::find(name)? {
+                info!("Session {name} is already running on :{}", s.display);
+                println!("{}", s.ready_info()?);
+                return Ok(());
+            }
+            if std::env::var_os(DETACHED_ENV).is_none() {
+                return detach(name);
+            }
+        }
+
+        let verbose = args.verbose;
+        let exit_on_eof = args.exit_on_eof;
+        let linger = Duration::from_secs(args.linger);
+        let cfg = args.into_config()?;
+        let port = cfg.port;
+        let encodings = cfg.encodings()?;
 
         // Parse command + args (allow quoted args in the default)
-        let parts = shell_words::split(&args.app)
-            .unwrap_or_else(|_| args.app.split_whitespace().map(|s| s.to_string()).collect());
-        let app = parts.get(0).cloned().unwrap_or_else(|| "xterm".to_string());
+        let parts = shell_words::split(&cfg.app)
+            .unwrap_or_else(|_| cfg.app.split_whitespace().map(|s| s.to_string()).collect());
+        let app = parts.first().cloned().unwrap_or_else(|| "xterm".to_string());
         let app_args = if parts.len() > 1 { &parts[1..] } else { &[] };
 
-        let desktop = app == "desktop"; // if you ever want a headless "desktop" mode
+        // "desktop" runs no app; clients see and drive the whole screen
+        let desktop = app == "desktop";
+
+        // Keep child handles here so Ctrl+C can kill them.
+        let children = Children::default();
+        let mut app_pid: Option<u32> = None;
+
+        let backend = display::backend(&cfg)?;
+        let started = Display::start(backend, cfg.display, Duration::from_secs(30))?;
+        let display = started.number();
+        let shared = started.shared();
+        // The app, capture, input and the X tools we run all connect with this cookie.
+        let xauth = started.xauthority().map(Path::to_path_buf);
+        let auth = xauth.as_deref();
+        if let Some(auth) = auth {
+            info!("Xauthority: {}", auth.display());
+        }
+        children.set_display(started);
 
         info!("Display: :{}", display);
         info!("App: {}", app);
         info!("Args: {:?}", app_args);
-        info!("Port: {}", port);
-        info!("Verbosity: {}", args.verbose);
-
-        if !desktop {
-            std::env::set_var("DISPLAY", format!(":{display}"));
+        info!("Screen: {}x{}x{} @ {} dpi", cfg.width, cfg.height, cfg.depth, cfg.dpi);
+        info!("Bind: {}:{}", cfg.bind, port);
+        info!("Max fps: {} (idle: {} after {} ms)", cfg.max_fps, cfg.idle_fps, cfg.idle_after_ms);
+        info!("Verbosity: {}", verbose);
+
+        // Someone else's display keeps its keyboard layout.
+        if !shared {
+            set_xkb_base(display, auth, &cfg.keyboard);
+            info!("Keyboard layout set.");
         }
 
-        // Keep child handles here so Ctrl+C can kill them.
-        let mut display_proc: Option<std::process::Child> = None;
-        let mut app_proc: Option<std::process::Child> = None;
-
-        // Start Xvfb if not desktop mode
         if !desktop {
-            // Start Xvfb with a decent 24-bit screen (with no alpha channel)
-            let p = Command::new("Xvfb")
-                .args([
-                    "+extension", "GLX",
-                    "+extension", "Composite",
-                    "-screen", "0", "1280x800x24",
-                    "-nolisten", "tcp",
-                    "-noreset",
-                    "-dpi", "96",
-                    &format!(":{display}"),
-                ])
-                .spawn()
-                .expect("Failed to start Xvfb");
-            info!("Xvfb pid: {}", p.id());
-            display_proc = Some(p);
-
-            // Wait until Xvfb is up
-            while !util::is_display_server_running(display) {
-                debug!("Waiting for Xvfb :{display}...");
-                std::thread::sleep(std::time::Duration::from_millis(200));
+            // Launch the app; if that fails, the display goes down with `children`
+            let mut cmd = Command::new(&app);
+            cmd.env("DISPLAY", format!(":{display}"));
+            if let Some(auth) = auth {
+                cmd.env("XAUTHORITY", auth);
             }
-            info!("Xvfb :{} is running.", display);
-            set_xkb_base(display);
-            info!("Keyboard layout set.");
-
-            // Launch the app
-            let p = Command::new(&app)
+            let p = cmd
                 .args(app_args)
                 .spawn()
-                .expect("Failed to start app");
+                .with_context(|| format!("failed to start {app}; run `server --doctor` to check this host"))?;
             info!("App pid: {}", p.id());
-            app_proc = Some(p);
+            app_pid = Some(p.id());
+            children.push("App", p);
         }
 
         // Find the app's window and its geometry
         let mut xid: i32 = 0;
         let mut geometry = remap::Geometry::default();
         if !desktop {
-            if let Some(ref p) = app_proc {
-                let pid = p.id();
-                xid = util::get_window_id(pid, &app, display);
+            if let Some(pid) = app_pid {
+                xid = util::get_window_id(pid, &app, display, auth);
                 info!("Waiting for window id...");
+                let deadline = Instant::now() + Duration::from_secs(30);
                 while xid == 0 {
+                    if Instant::now() > deadline {
+                        anyhow::bail!("{app} did not open a window on :{display} within 30s");
+                    }
                     std::thread::sleep(std::time::Duration::from_millis(200));
-                    xid = util::get_window_id(pid, &app, display);
+                    xid = util::get_window_id(pid, &app, display, auth);
                 }
                 info!("Window xid: {} ({:#06x})", xid, xid);
-                geometry = util::get_window_geometry(xid, display);
+                geometry = util::get_window_geometry(xid, display, auth);
                 info!("Window geometry: {:?} (server)", geometry);
 
-                // // Enlarge the app to the full Xvfb screen
-                if xid != 0 {
-                    if let Ok((sw, sh)) = util::screen_size(display) {
-                        info!("Target Xvfb size: {}x{}", sw, sh);
-
-                        // 1) Politely ask any WM to maximize/fullscreen (EWMH). Harmless on Xvfb-without-WM.
-                        if let Err(e) = util::maximize_window(display, xid as u32) {
-                            debug!("maximize_window (EWMH) ignored/failed: {:?}", e);
-                        }
-                        std::thread::sleep(std::time::Duration::from_millis(120));
-
-                        // 2) Force move+resize (works even with no WM). Retry a few times in case the app
-                        //    does an initial layout pass and resizes itself once after mapping.
-                        for attempt in 1..=8 {
-                            // Try move+resize to (0,0, sw, sh). Fall back to util::resize_window_to if you prefer.
-                            let _ = util::force_move_resize(display, xid as u32, 0, 0, sw, sh)
-                                .or_else(|_| util::resize_window_to(display, xid as u32, sw, sh));
-
-                            std::thread::sleep(std::time::Duration::from_millis(80));
-
-                            let g = util::get_window_geometry(xid, display);
-                            if g.width == sw as i32 && g.height == sh as i32 {
-                                info!("Window matched screen on attempt {}: {}x{}", attempt, g.width, g.height);
-                                break;
-                            } else {
-                                debug!("still {}x{}, want {}x{} (attempt {})", g.width, g.height, sw, sh, attempt);
-                            }
-                        }
-
-                        // 3) Re-read final geometry for logging
-                        geometry = util::get_window_geometry(xid, display);
-                        info!("Window geometry after maximize: {:?} (server)", geometry);
-                    } else {
-                        info!("Could not read screen size; skipping maximize");
-                    }
-                }
+                // Enlarge the app to the full screen
+                geometry = fit_window(display, auth, xid);
             }
         }
 
         // On Ctrl+C: kill the child processes and exit
         {
-            let mut app_proc = app_proc.take();
-            let mut display_proc = display_proc.take();
-            ctrlc::set_handler(move || {
-                if let Some(p) = &mut app_proc {
-                    let _ = p.kill();
-                    info!("App stopped.");
-                }
-                if let Some(p) = &mut display_proc {
-                    let _ = p.kill();
-                    info!("Display :{} stopped.", display);
+            let children = children.clone();
+            ctrlc::set_handler(move || children.shutdown())?;
+        }
+
+        // When launched over SSH, the launcher closing our stdin means the client is gone,
+        // though with --linger it gets a grace period to reconnect.
+        let presence = Presence::new();
+        if exit_on_eof {
+            let children = children.clone();
+            let presence = presence.clone();
+            std::thread::spawn(move || {
+                let stdin = std::io::stdin();
+                for _ in stdin.lock().lines().map_while(|l| l.ok()) {}
+                if !linger.is_zero() {
+                    info!("stdin closed, waiting up to {:?} without a client", linger);
+                    while presence.idle_for().is_none_or(|idle| idle < linger) {
+                        std::thread::sleep(Duration::from_secs(1));
+                    }
                 }
-                std::process::exit(0);
-            })?;
+                info!("stdin closed, shutting down");
+                children.shutdown();
+            });
         }
 
         // Listen for client connections
-        let addr = format!("127.0.0.1:{port}");
-        let listener = TcpListener::bind(&addr)?;
-        info!("Listening on {}", addr);
+        if cfg.unix_socket && cfg.tls {
+            anyhow::bail!("tls and unix_socket cannot be combined");
+        }
+        let mut ready = ReadyInfo { display, ..ReadyInfo::default() };
+        let listener = if cfg.unix_socket {
+            let path = runtime_dir().join(format!("display-{display}.sock"));
+            let listener = Listener::bind_unix(&path)
+                .with_context(|| format!("listen on {}", path.display()))?;
+            info!("Listening on {}", path.display());
+            children.remove_on_exit(path.clone());
+            ready.socket = Some(path);
+            listener
+        } else {
+            let listener = TcpListener::bind((cfg.bind.as_str(), port))?;
+            let local = listener.local_addr()?;
+            info!("Listening on {}", local);
+            if !cfg.tls && !local.ip().is_loopback() {
+                warn!("Listening on {} without TLS; anyone on the network can connect (use --tls)", local.ip());
+            }
+            ready.port = local.port();
+            Listener::Tcp(listener)
+        };
 
-        loop {
-            let (mut stream, peer) = listener.accept()?;
+        let tls = if cfg.tls {
+            let tls = TlsServer::load_or_generate(cfg.tls_dir.as_deref())?;
+            info!("TLS enabled, certificate fingerprint {}", tls.fingerprint);
+            Some(tls)
+        } else {
+            None
+        };
+
+        // Every connection must prove it knows this session's token
+        let token = auth::Token::generate()?;
+
+        // Tell whoever launched us where to connect
+        ready.token = Some(token.clone());
+        println!("{ready}");
+        std::io::stdout().flush()?;
+
+        if let Some(name) = &session {
+            let path = Session::new(name, &cfg.app, &ready).save()?;
+            children.remove_on_exit(path);
+            // Our launcher is gone once it has the ready line; keep later output off its pipe.
+            unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) };
+            info!("Session {name} ready");
+        }
+
+        // Accept on a separate thread, and run each connection's TLS handshake and
+        // token check on a thread of its own, so a peer that connects and says
+        // nothing holds up no one else.
+        let (authed_tx, authed_rx) = flume::unbounded::<(Stream, String)>();
+        let tls = tls.map(Arc::new);
+        std::thread::spawn(move || loop {
+            let (stream, peer) = match listener.accept() {
+                Ok(c) => c,
+                Err(e) => {
+                    warn!("accept failed: {}", e);
+                    std::thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+            };
+            let (tls, token, authed_tx) = (tls.clone(), token.clone(), authed_tx.clone());
+            std::thread::spawn(move || {
+                let mut stream = match (&tls, stream) {
+                    (Some(tls), Stream::Tcp(sock)) => match tls.accept(sock) {
+                        Ok(s) => s,
+                        Err(e) => {
+                            warn!("TLS handshake with {} failed: {}", peer, e);
+                            return;
+                        }
+                    },
+                    (_, stream) => stream,
+                };
+                let _ = stream.set_read_timeout(Some(Duration::from_secs(10)));
+                if let Err(e) = auth::challenge(&mut stream, &token) {
+                    warn!("Rejected {}: {}", peer, e);
+                    return;
+                }
+                let _ = stream.set_read_timeout(None);
+                let _ = authed_tx.send((stream, peer));
+            });
+        });
+
+        // A new client takes over from the current one, whose connection may be dead
+        // without us having noticed (e.g. the client machine went to sleep).
+        let (conn_tx, conn_rx) = flume::bounded::<(Stream, String)>(1);
+        std::thread::spawn(move || {
+            let mut current: Option<Stream> = None;
+            for (stream, peer) in authed_rx.iter() {
+                if let Some(old) = current.take() {
+                    info!("Client {} takes over the session", peer);
+                    let _ = old.shutdown();
+                }
+                current = stream.try_clone().ok();
+                if conn_tx.send((stream, peer)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        for (mut stream, peer) in conn_rx.iter() {
             info!("Client connected: {}", peer);
+            let _present = presence.connect();
 
             // Channels for capture→writer pipeline and capture control
             let (capture_tx, capture_rx) = flume::unbounded::<bool>(); // send 'incremental' flag
-            let (writer_tx, writer_rx) = flume::unbounded::<Vec<Rec>>();
+            let (writer_tx, writer_rx) = flume::unbounded::<ServerEvent>();
+            // Whether the client understands DesktopSize; older ones drop the connection on it
+            let desktop_size = Arc::new(AtomicBool::new(false));
 
             // Create a Capture (xid=0 means screen, non-zero means window)
-            let mut capture = Capture::new(xid.max(0) as u32);
-            //let mut capture = Capture::new(0);
+            let mut capture = Capture::new(xid.max(0) as u32, display, auth);
             let (width, height) = capture.get_geometry();
 
             // Send initial geometry header (u16 BE, twice)
-            stream.write_all(&u16::try_from(width).unwrap_or(0).to_be_bytes())?;
-            stream.write_all(&u16::try_from(height).unwrap_or(0).to_be_bytes())?;
+            let header = [width.to_be_bytes(), height.to_be_bytes()].concat();
+            if let Err(e) = stream.write_all(&header) {
+                info!("Client {} went away: {}", peer, e);
+                continue;
+            }
 
-            // Spawn capture thread
+            // Spawn capture thread, paced by max/idle fps and the writer backlog
+            let pacer_cfg = PacerConfig {
+                max_fps: cfg.max_fps,
+                idle_fps: cfg.idle_fps,
+                idle_after: Duration::from_millis(cfg.idle_after_ms),
+                ..PacerConfig::default()
+            };
+            let capture_desktop_size = Arc::clone(&desktop_size);
             std::thread::spawn(move || {
+                let mut pacer = FramePacer::new(pacer_cfg);
+                // Default to incremental=true unless a request arrives
+                let mut incremental = true;
+                let mut size = (width, height);
                 loop {
-                    // Default to incremental=true unless a request arrives
-                    let mut incremental = true;
-                    while let Ok(inc) = capture_rx.try_recv() {
-                        trace!("capture_rx: set incremental={inc}");
-                        incremental = inc;
-                    }
                     let t0 = Instant::now();
                     let rects = capture.get_image(incremental);
                     trace!("capture.get_image({incremental}) took {:?}", t0.elapsed());
+                    incremental = true;
+
+                    // The screen or window was resized; tell the client before the
+                    // rectangles that assume the new size.
+                    if capture_desktop_size.load(Ordering::Relaxed) && capture.get_geometry() != size {
+                        size = capture.get_geometry();
+                        debug!("capture size now {}x{}", size.0, size.1);
+                        let evt = ServerEvent::DesktopSize { width: size.0, height: size.1 };
+                        if writer_tx.send(evt).is_err() {
+                            break;
+                        }
+                    }
 
-                    if !rects.is_empty() {
+                    let changed = !rects.is_empty();
+                    if changed {
                         debug!("capture produced {} rectangles -> writer", rects.len());
-                        if writer_tx.send(rects).is_err() {
+                        let evt = ServerEvent::FramebufferUpdate { count: rects.len() as u16, rectangles: rects };
+                        if writer_tx.send(evt).is_err() {
                             break;
                         }
-                    } else {
-                        // Avoid busy-spin; sleep a bit if nothing changed
-                        std::thread::sleep(std::time::Duration::from_millis(4));
+                    }
+
+                    // Wait for the next frame; requests and input wake us early from idle,
+                    // but never faster than the active frame interval.
+                    let mut deadline = t0 + pacer.next_delay(changed, writer_tx.len());
+                    trace!("capture: next frame in {:?} (idle={})", deadline - t0, pacer.is_idle());
+                    loop {
+                        match capture_rx.recv_deadline(deadline) {
+                            Ok(inc) => {
+                                trace!("capture_rx: incremental={inc}");
+                                incremental &= inc;
+                                pacer.wake();
+                                deadline = deadline.min(t0 + pacer.active_interval());
+                            }
+                            Err(RecvTimeoutError::Timeout) => break,
+                            Err(RecvTimeoutError::Disconnected) => return,
+                        }
                     }
                 }
             });
 
-            // Spawn writer thread (sends ServerEvent::FramebufferUpdate)
+            // Spawn writer thread (sends what the capture thread produced)
             let writer_stream = stream.try_clone()?;
             std::thread::spawn(move || {
                 let mut writer = writer_stream;
-                while let Ok(rects) = writer_rx.recv() {
-                    let evt = ServerEvent::FramebufferUpdate {
-                        count: rects.len() as u16,
-                        rectangles: rects,
-                    };
+                while let Ok(evt) = writer_rx.recv() {
                     if evt.write_to(&mut writer).is_err() {
                         break;
                     }
@@ -467,16 +722,13 @@ mod linux_impl {
             });
 
             // Set up input injection
-            let mut input = Input::new();
+            let mut input = Input::new(display, auth);
             if !desktop {
                 input.set_window(xid);
                 input.set_server_geometry(geometry);
                 input.focus();
             }
 
-            // Track latest client size (optional)
-            let mut client_w: u16 = width.min(u16::MAX) as u16;
-            let mut client_h: u16 = height.min(u16::MAX) as u16;
             let mut last_buttons: u8 = 0;
             
             // Handle client messages on this connection
@@ -496,6 +748,8 @@ mod linux_impl {
                     }
 
                     ClientEvent::KeyEvent { down, key, mods } => {
+                        // Wake the capture loop so the echo isn't delayed by idle polling
+                        let _ = capture_tx.send(true);
                         if down { 
                             input.key_down(key, mods);
                          } else { 
@@ -504,6 +758,8 @@ mod linux_impl {
                     }
 
                     ClientEvent::PointerEvent { buttons, x, y } => {
+                        let _ = capture_tx.send(true);
+
                         // 1) Always move the pointer first
                         input.mouse_move(x as i32, y as i32, 0);
 
@@ -542,24 +798,38 @@ mod linux_impl {
                         debug!("cut text from client: {}", s);
                     }
 
-                    ClientEvent::SetEncodings(_encs) => {
-                        // Ignored in this simple server
+                    ClientEvent::SetEncodings(encs) => {
+                        // Only raw rectangles are produced for now; just report what both sides allow
+                        let usable: Vec<_> = encodings.iter().filter(|e| encs.contains(e)).collect();
+                        debug!("client encodings {:?}; usable {:?}", encs, usable);
+                        desktop_size.store(encs.contains(&Encoding::DesktopSize), Ordering::Relaxed);
                     }
 
                     ClientEvent::ClientResize { width, height } => {
                         info!("client resize -> {}x{}", width, height);
-                        client_w = width;
-                        client_h = height;
 
-                        // Strategy A (simple): force a full update on next capture tick
+                        // Resize the screen, then the app to fill it; the capture
+                        // thread notices the new size and sends DesktopSize.
+                        if shared {
+                            debug!("not resizing a shared display");
+                        } else {
+                            match display::set_screen_size(display, auth, width, height) {
+                                Ok((w, h)) => {
+                                    info!("Screen resized to {}x{}", w, h);
+                                    if xid != 0 {
+                                        geometry = fit_window(display, auth, xid);
+                                        input.set_server_geometry(geometry);
+                                    }
+                                }
+                                Err(e) => warn!("Could not resize the screen to {}x{}: {:#}", width, height, e),
+                            }
+                        }
                         let _ = capture_tx.send(false);
-
-                        // Try to actually resize the X screen (best-effort; safe to fail)
-                        try_resize_display_best_effort(display, width, height);
                     }
                 }
             }
         }
+        Ok(())
     }
 }
 
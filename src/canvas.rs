@@ -1,3 +1,4 @@
+use std::time::{Duration, Instant};
 use flume::{Receiver, Sender};
 use anyhow::Result;
 use log::debug;
@@ -22,6 +23,16 @@ const VK_RIGHT:  u8 = 0xE6;
 const VK_UP:     u8 = 0xE7;
 const VK_DOWN:   u8 = 0xE8;
 
+// Status overlay: height of the bar along the top edge, and its colour (amber,
+// in the channel order draw() writes)
+const STATUS_BAR_H: u32 = 6;
+const STATUS_BAR_COLOR: u32 = 0x0020A0E0;
+
+// How long the window must keep one size before the server is asked to match
+// it, so dragging an edge doesn't resize the remote screen on every frame
+const RESIZE_DEBOUNCE: Duration = Duration::from_millis(300);
+const MIN_REMOTE_SIZE: usize = 64;
+
 pub struct Canvas {
     window: Window,
 
@@ -35,6 +46,14 @@ pub struct Canvas {
     buttons: u8,
     need_update: bool,
     last_mouse: Option<(u16,u16)>,
+
+    // Connection status shown over the last frame (e.g. while reconnecting); input is paused
+    status: Option<String>,
+    overlay: Vec<u32>,
+
+    // Window size waiting out RESIZE_DEBOUNCE, and the last size sent to the server
+    resize_pending: Option<((u16, u16), Instant)>,
+    resize_sent: Option<(u16, u16)>,
 }
 
 impl Canvas {
@@ -63,6 +82,10 @@ impl Canvas {
             buttons: 0,
             need_update: false,
             last_mouse: None,
+            status: None,
+            overlay: Vec::new(),
+            resize_pending: None,
+            resize_sent: None,
         })
     }
 
@@ -87,13 +110,85 @@ impl Canvas {
         ).expect("Unable to create window");
         window.set_target_fps(60);
         self.window = window;
+        self.set_title();
+        self.resize_pending = None;
+        self.resize_sent = None;
 
         self.need_update = true;
         Ok((self.fb_w, self.fb_h))
     }
 
+    /// The server resized its screen: take the new size without touching the window,
+    /// which minifb stretches the picture into.
+    fn set_desktop_size(&mut self, width: u16, height: u16) {
+        debug!("desktop size now {}x{}", width, height);
+        self.fb_w = u32::from(width).max(1);
+        self.fb_h = u32::from(height).max(1);
+        self.buffer.clear();
+        self.buffer.resize((self.fb_w * self.fb_h) as usize, 0);
+        self.need_update = true;
+    }
+
+    /// Ask the server to resize the remote screen to the window once the user
+    /// has stopped resizing it.
+    pub fn handle_resize(&mut self) -> Result<()> {
+        if self.status.is_some() {
+            return Ok(());
+        }
+        let (w, h) = self.window.get_size();
+        let size = (
+            w.clamp(MIN_REMOTE_SIZE, u16::MAX as usize) as u16,
+            h.clamp(MIN_REMOTE_SIZE, u16::MAX as usize) as u16,
+        );
+        // Nothing to do if it already matches, or the server was asked for this
+        // size and settled on another (e.g. Xvfb can't grow past its start size).
+        if (u32::from(size.0), u32::from(size.1)) == (self.fb_w, self.fb_h) || self.resize_sent == Some(size) {
+            self.resize_pending = None;
+            return Ok(());
+        }
+        match self.resize_pending {
+            Some((pending, since)) if pending == size => {
+                if since.elapsed() >= RESIZE_DEBOUNCE {
+                    debug!("window resized to {}x{}; asking the server to match", size.0, size.1);
+                    self.client_tx.send(ClientEvent::ClientResize { width: size.0, height: size.1 })?;
+                    self.resize_sent = Some(size);
+                    self.resize_pending = None;
+                }
+            }
+            _ => self.resize_pending = Some((size, Instant::now())),
+        }
+        Ok(())
+    }
+
     pub fn is_open(&self) -> bool { self.window.is_open() }
 
+    pub fn framebuffer_size(&self) -> (u32, u32) { (self.fb_w, self.fb_h) }
+
+    /// Show a connection problem over the (frozen) picture, or clear it with `None`.
+    /// While a status is shown, input is not sent to the server.
+    pub fn set_status(&mut self, status: Option<String>) {
+        if self.status == status { return; }
+        self.status = status;
+        self.last_mouse = None;
+        self.set_title();
+        self.need_update = true;
+    }
+
+    fn set_title(&mut self) {
+        match &self.status {
+            Some(s) => self.window.set_title(&format!("Remap - {s}")),
+            None => self.window.set_title("Remap"),
+        }
+    }
+
+    /// The framebuffer dimmed, with a bar along the top.
+    fn render_overlay(&mut self) {
+        self.overlay.clear();
+        self.overlay.extend(self.buffer.iter().map(|px| (px >> 2) & 0x003F3F3F));
+        let bar = (STATUS_BAR_H.min(self.fb_h) * self.fb_w) as usize;
+        self.overlay[..bar].fill(STATUS_BAR_COLOR);
+    }
+
     pub fn draw(&mut self, rec: &Rec) -> Result<()> {
         if self.buffer.is_empty() || rec.width == 0 || rec.height == 0 { return Ok(()); }
 
@@ -148,9 +243,13 @@ impl Canvas {
 
     pub fn update(&mut self) -> Result<()> {
         if self.need_update {
+            if self.status.is_some() {
+                self.render_overlay();
+            }
+            let buffer = if self.status.is_some() { &self.overlay } else { &self.buffer };
             // Always push using framebuffer dimensions; minifb scales to the current window
             self.window
-                .update_with_buffer(&self.buffer, self.fb_w as usize, self.fb_h as usize)
+                .update_with_buffer(buffer, self.fb_w as usize, self.fb_h as usize)
                 .expect("Unable to update screen buffer");
             self.need_update = false;
         } else {
@@ -160,6 +259,9 @@ impl Canvas {
     }
 
     pub fn handle_input(&mut self) -> Result<()> {
+        if self.status.is_some() {
+            return Ok(());
+        }
         if let Some((xf, yf)) = self.window.get_mouse_pos(MouseMode::Discard) {
             let (x, y) = (xf as u16, yf as u16);
 
@@ -220,6 +322,7 @@ impl Canvas {
                         any = true;
                     }
                 }
+                ServerEvent::DesktopSize { width, height } => self.set_desktop_size(width, height),
                 m => debug!("server event: {:?}", m),
             }
         }
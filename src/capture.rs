@@ -1,6 +1,8 @@
 //use anyhow::Result;
+use std::path::Path;
+
 use xcb::x::{Drawable, GetGeometry, GetImage, ImageFormat, Window};
-use xcb::{Connection, XidNew};
+use xcb::{AuthInfo, Connection, XidNew};
 
 use crate::Rec;
 
@@ -17,10 +19,20 @@ pub struct Capture {
 }
 
 impl Capture {
-    /// `xid`: X window id, or 0 for the root window (desktop)
-    pub fn new(xid: u32) -> Self {
+    /// `xid`: X window id, or 0 for the root window (desktop). `auth` is the
+    /// display's cookie file, if it has one.
+    pub fn new(xid: u32, display: u32, auth: Option<&Path>) -> Self {
         let win = unsafe { Window::new(xid) };
-        let (conn, screen_index) = Connection::connect(None).expect("XCB connect failed");
+        let name = format!(":{display}");
+        let (conn, screen_index) = match auth {
+            Some(auth) => {
+                let cookie = crate::xauth::read(auth, display).expect("X cookie");
+                let info = AuthInfo { name: crate::xauth::MIT_MAGIC_COOKIE, data: &cookie };
+                Connection::connect_to_display_with_auth_info(Some(&name), info)
+            }
+            None => Connection::connect(Some(&name)),
+        }
+        .expect("XCB connect failed");
         let setup = conn.get_setup();
 
         // Pick drawable
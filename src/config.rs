@@ -0,0 +1,303 @@
+//! Server configuration file (TOML). Values are layered as
+//! defaults < config file < app preset < command line flags.
+//!
+//! ```toml
+//! app = "xterm"
+//! bind = "127.0.0.1"
+//! width = 1600
+//! height = 900
+//! dpi = 120
+//! max_fps = 60
+//!
+//! [keyboard]
+//! layout = "de"
+//!
+//! [presets.gedit]
+//! app = "gedit --new-window"
+//! width = 1280
+//! height = 1024
+//! ```
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::Encoding;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// X display number (e.g. 100 -> :100), or "auto" for the first free one
+    pub display: DisplaySpec,
+    /// What runs that display
+    pub display_backend: BackendKind,
+    /// App (and args) to run
+    pub app: String,
+    /// Address to listen on
+    pub bind: String,
+    /// TCP port to listen on
+    pub port: u16,
+    /// Virtual screen geometry
+    pub width: u16,
+    pub height: u16,
+    pub depth: u8,
+    pub dpi: u16,
+    /// Extra X server extensions to enable (+extension NAME)
+    pub extensions: Vec<String>,
+    pub keyboard: KeyboardConfig,
+    pub max_fps: u32,
+    pub idle_fps: u32,
+    pub idle_after_ms: u64,
+    /// Encodings the server is willing to use, in order of preference
+    pub encodings: Vec<String>,
+    /// Serve TLS (for direct connections without ssh)
+    pub tls: bool,
+    /// Where the TLS certificate and key live [default: <config dir>/tls]
+    pub tls_dir: Option<PathBuf>,
+    /// Listen on a Unix socket in [`runtime_dir`] instead of a TCP port
+    pub unix_socket: bool,
+    /// Named app presets, selected with `--app NAME`
+    pub presets: HashMap<String, AppPreset>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            display: DisplaySpec::Auto,
+            display_backend: BackendKind::Xvfb,
+            app: "xterm -fa 'Monospace' -fs 14 -geometry 110x24".to_string(),
+            bind: "127.0.0.1".to_string(),
+            port: 10100,
+            width: 1280,
+            height: 800,
+            depth: 24,
+            dpi: 96,
+            extensions: vec!["GLX".to_string(), "Composite".to_string()],
+            keyboard: KeyboardConfig::default(),
+            max_fps: 30,
+            idle_fps: 2,
+            idle_after_ms: 2000,
+            encodings: vec!["raw".to_string()],
+            tls: false,
+            tls_dir: None,
+            unix_socket: false,
+            presets: HashMap::new(),
+        }
+    }
+}
+
+/// Which X display to run on: a fixed number or the first free one from :100.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "DisplayValue")]
+pub enum DisplaySpec {
+    Auto,
+    Number(u32),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DisplayValue {
+    Number(u32),
+    Text(String),
+}
+
+impl TryFrom<DisplayValue> for DisplaySpec {
+    type Error = anyhow::Error;
+    fn try_from(v: DisplayValue) -> Result<Self> {
+        match v {
+            DisplayValue::Number(n) => Ok(DisplaySpec::Number(n)),
+            DisplayValue::Text(s) => s.parse(),
+        }
+    }
+}
+
+impl std::str::FromStr for DisplaySpec {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "auto" {
+            return Ok(DisplaySpec::Auto);
+        }
+        let n = s.trim_start_matches(':').parse::<u32>()
+            .with_context(|| format!("invalid display {s:?}, expected a number or \"auto\""))?;
+        Ok(DisplaySpec::Number(n))
+    }
+}
+
+/// The X server behind the display (see `remap::display`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    /// A virtual framebuffer; it can't grow past the size it started with
+    #[default]
+    Xvfb,
+    /// Xorg with the dummy video driver, which can resize to any size
+    Xorg,
+    /// The display in $DISPLAY, already running (e.g. a desktop session)
+    Existing,
+}
+
+impl std::str::FromStr for BackendKind {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "xvfb" => Ok(BackendKind::Xvfb),
+            "xorg" => Ok(BackendKind::Xorg),
+            "existing" => Ok(BackendKind::Existing),
+            _ => anyhow::bail!("unknown display backend {s:?}, expected xvfb, xorg or existing"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyboardConfig {
+    pub rules: String,
+    pub model: String,
+    pub layout: String,
+    pub variant: String,
+    pub options: String,
+}
+
+impl Default for KeyboardConfig {
+    fn default() -> Self {
+        Self {
+            rules: "base".to_string(),
+            model: "pc105".to_string(),
+            layout: "us".to_string(),
+            variant: String::new(),
+            options: String::new(),
+        }
+    }
+}
+
+/// Per-app overrides; anything left out falls back to the top-level config.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AppPreset {
+    pub app: String,
+    pub width: Option<u16>,
+    pub height: Option<u16>,
+    pub depth: Option<u8>,
+    pub dpi: Option<u16>,
+    pub layout: Option<String>,
+    pub max_fps: Option<u32>,
+}
+
+/// `$XDG_CONFIG_HOME/remap`, falling back to `~/.config/remap` (`%APPDATA%\remap` on Windows).
+pub fn config_dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))?;
+    Some(base.join("remap"))
+}
+
+/// Per-user directory for sockets and other runtime state: `$XDG_RUNTIME_DIR/remap`,
+/// or `remap-$USER` in the temp directory when that is not set.
+pub fn runtime_dir() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(d) => PathBuf::from(d).join("remap"),
+        None => {
+            let user = std::env::var("USER").unwrap_or_else(|_| "user".to_string());
+            std::env::temp_dir().join(format!("remap-{user}"))
+        }
+    }
+}
+
+impl ServerConfig {
+    /// `server.toml` in [`config_dir`].
+    pub fn default_path() -> Option<PathBuf> {
+        Some(config_dir()?.join("server.toml"))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("read config {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("parse config {}", path.display()))
+    }
+
+    /// Load the default config file if it exists, otherwise return the defaults.
+    pub fn load_default() -> Result<Self> {
+        match Self::default_path() {
+            Some(p) if p.exists() => Self::load(&p),
+            _ => Ok(Self::default()),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let cfg: ServerConfig = toml::from_str(text)?;
+        cfg.encodings()?;
+        Ok(cfg)
+    }
+
+    /// If `app` names a preset, switch to its command and apply its overrides.
+    /// Otherwise `app` is taken as the command line to run.
+    pub fn select_app(&mut self, app: &str) {
+        let Some(p) = self.presets.get(app).cloned() else {
+            self.app = app.to_string();
+            return;
+        };
+        if !p.app.is_empty() { self.app = p.app; }
+        if let Some(v) = p.width { self.width = v; }
+        if let Some(v) = p.height { self.height = v; }
+        if let Some(v) = p.depth { self.depth = v; }
+        if let Some(v) = p.dpi { self.dpi = v; }
+        if let Some(v) = p.layout { self.keyboard.layout = v; }
+        if let Some(v) = p.max_fps { self.max_fps = v; }
+    }
+
+    pub fn encodings(&self) -> Result<Vec<Encoding>> {
+        self.encodings.iter().map(|e| e.parse()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_with_preset() {
+        let mut cfg = ServerConfig::parse(
+            r#"
+            width = 1600
+            display = "auto"
+            display_backend = "xorg"
+            encodings = ["raw", "zrle"]
+
+            [keyboard]
+            layout = "de"
+
+            [presets.gedit]
+            app = "gedit --new-window"
+            height = 1024
+            layout = "fr"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(cfg.width, 1600);
+        assert_eq!(cfg.display, DisplaySpec::Auto);
+        assert_eq!(cfg.display_backend, BackendKind::Xorg);
+        assert_eq!(cfg.height, 800);
+        assert_eq!(cfg.keyboard.layout, "de");
+        assert_eq!(cfg.encodings().unwrap(), vec![Encoding::Raw, Encoding::Zrle]);
+
+        cfg.select_app("gedit");
+        assert_eq!(cfg.app, "gedit --new-window");
+        assert_eq!((cfg.width, cfg.height), (1600, 1024));
+        assert_eq!(cfg.keyboard.layout, "fr");
+
+        cfg.select_app("xclock -digital");
+        assert_eq!(cfg.app, "xclock -digital");
+    }
+
+    #[test]
+    fn rejects_unknown_keys_and_encodings() {
+        assert!(ServerConfig::parse("widht = 10").is_err());
+        assert!(ServerConfig::parse(r#"encodings = ["h264"]"#).is_err());
+        assert!(ServerConfig::parse(r#"display = "next""#).is_err());
+        assert!(ServerConfig::parse(r#"display_backend = "wayland""#).is_err());
+        assert_eq!(ServerConfig::parse("display = 7").unwrap().display, DisplaySpec::Number(7));
+    }
+}
@@ -0,0 +1,715 @@
+//! The X server the app runs on. A [`DisplayBackend`] starts it, says when it
+//! accepts connections and stops it again; [`Display`] drives one from start
+//! to teardown. Which backend is used comes from `display_backend` in the
+//! server config.
+
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use log::{debug, info};
+
+use crate::config::{runtime_dir, BackendKind, DisplaySpec, ServerConfig};
+use crate::xauth;
+
+/// Where "auto" starts looking, clear of the :0, :1... of desktop sessions.
+const FIRST_AUTO: u32 = 100;
+/// How often "auto" moves on to the next number when another X server
+/// takes the one it picked before ours is up.
+const AUTO_ATTEMPTS: u32 = 10;
+
+pub trait DisplayBackend: Send {
+    /// For logs, e.g. "Xvfb"
+    fn name(&self) -> &str;
+
+    /// The display number this backend must use, if it has no choice
+    fn fixed_display(&self) -> Option<u32> {
+        None
+    }
+
+    /// Whether the display belongs to someone else, so its keyboard layout
+    /// and screen size are left alone
+    fn shared(&self) -> bool {
+        false
+    }
+
+    /// The Xauthority file clients need, once started; None to use the
+    /// environment's
+    fn xauthority(&self) -> Option<&Path> {
+        None
+    }
+
+    /// Start the X server on `display`, which was free a moment ago.
+    fn start(&mut self, display: u32) -> Result<()>;
+
+    /// True once `display` accepts connections; an error if it never will
+    /// (e.g. the X server exited).
+    fn ready(&mut self, display: u32) -> Result<bool>;
+
+    /// Stop what `start` started. Also called after a failed start.
+    fn stop(&mut self);
+}
+
+/// The backend for `cfg.display_backend`.
+pub fn backend(cfg: &ServerConfig) -> Result<Box<dyn DisplayBackend>> {
+    Ok(match cfg.display_backend {
+        BackendKind::Xvfb => Box::new(Xvfb::new(cfg)),
+        BackendKind::Xorg => Box::new(XorgDummy::new(cfg)),
+        BackendKind::Existing => Box::new(Existing::from_env()?),
+    })
+}
+
+/// A running display; stopped when dropped.
+pub struct Display {
+    number: u32,
+    backend: Box<dyn DisplayBackend>,
+    stopped: bool,
+}
+
+impl Display {
+    /// Start `backend` on `spec` and wait up to `timeout` for it to accept
+    /// connections. With "auto", a number another X server grabs first is
+    /// skipped.
+    pub fn start(backend: Box<dyn DisplayBackend>, spec: DisplaySpec, timeout: Duration) -> Result<Display> {
+        let auto = backend.fixed_display().is_none() && spec == DisplaySpec::Auto;
+        let number = match (backend.fixed_display(), spec) {
+            (Some(n), _) | (None, DisplaySpec::Number(n)) => n,
+            (None, DisplaySpec::Auto) => free_display(FIRST_AUTO)?,
+        };
+        // From here on the display is stopped on every way out.
+        let mut display = Display { number, backend, stopped: false };
+        let mut attempt = 1;
+        loop {
+            info!("Starting {} on :{}", display.name(), display.number);
+            match display.wait_ready(timeout) {
+                Ok(()) => break,
+                Err(e) => {
+                    display.stop();
+                    if !(auto && attempt < AUTO_ATTEMPTS && display_in_use(display.number)) {
+                        return Err(e);
+                    }
+                    debug!("{e:#}");
+                    info!(":{} was taken meanwhile; trying the next one", display.number);
+                    display.number = free_display(display.number + 1)?;
+                    display.stopped = false;
+                    attempt += 1;
+                }
+            }
+        }
+        info!("{} :{} is running.", display.name(), display.number);
+        Ok(display)
+    }
+
+    fn wait_ready(&mut self, timeout: Duration) -> Result<()> {
+        let number = self.number;
+        self.backend.start(number)?;
+        let deadline = Instant::now() + timeout;
+        while !self.backend.ready(number)? {
+            if Instant::now() > deadline {
+                bail!(
+                    "{} :{number} was not ready after {}s; run `server --doctor` to check this host",
+                    self.name(),
+                    timeout.as_secs()
+                );
+            }
+            debug!("Waiting for {} :{number}...", self.name());
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        Ok(())
+    }
+
+    pub fn number(&self) -> u32 {
+        self.number
+    }
+
+    pub fn name(&self) -> &str {
+        self.backend.name()
+    }
+
+    pub fn shared(&self) -> bool {
+        self.backend.shared()
+    }
+
+    /// Cookie file for everything that connects to the display; see
+    /// [`xauth::connect`].
+    pub fn xauthority(&self) -> Option<&Path> {
+        self.backend.xauthority()
+    }
+
+    pub fn stop(&mut self) {
+        if !std::mem::replace(&mut self.stopped, true) {
+            self.backend.stop();
+        }
+    }
+}
+
+impl Drop for Display {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// First display number from `from` on that nobody uses.
+pub fn free_display(from: u32) -> Result<u32> {
+    (from..from + 1000)
+        .find(|&n| !display_in_use(n))
+        .with_context(|| format!("no free X display number from :{from} to :{}", from + 999))
+}
+
+/// Whether display `n` is taken: its lock file names a live process, or
+/// there is no lock file but there is a socket. A lock left by a server
+/// that died doesn't count; the next X server removes it.
+pub fn display_in_use(n: u32) -> bool {
+    match std::fs::read_to_string(format!("/tmp/.X{n}-lock")) {
+        Ok(text) => lock_owner(&text).is_none_or(alive),
+        Err(_) => Path::new(&format!("/tmp/.X11-unix/X{n}")).exists(),
+    }
+}
+
+/// The pid in an X lock file, which X servers write as ten padded digits.
+fn lock_owner(text: &str) -> Option<i32> {
+    text.trim().parse().ok().filter(|&pid| pid > 0)
+}
+
+fn alive(pid: i32) -> bool {
+    let found = unsafe { libc::kill(pid, 0) } == 0;
+    found || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Whether an X server answers on `display`.
+fn accepts_connections(display: u32) -> bool {
+    Path::new(&format!("/tmp/.X11-unix/X{display}")).exists() && x11rb::connect(Some(&format!(":{display}"))).is_ok()
+}
+
+/// An X server we run as a child process. It is started with `-displayfd 1`,
+/// so it prints its display number on stdout once it accepts connections,
+/// and with `-auth`, so only clients with its cookie get in.
+struct Server {
+    name: &'static str,
+    child: Option<Child>,
+    ready: Option<flume::Receiver<u32>>,
+    auth: Option<PathBuf>,
+}
+
+impl Server {
+    fn new(name: &'static str) -> Self {
+        Server { name, child: None, ready: None, auth: None }
+    }
+
+    fn spawn(&mut self, cmd: &mut Command, display: u32) -> Result<()> {
+        let auth = runtime_dir().join(format!("xauth-{display}"));
+        xauth::write(&auth, display)?;
+        self.auth = Some(auth.clone());
+        let mut child = cmd
+            .arg("-auth")
+            .arg(&auth)
+            .args(["-displayfd", "1"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("failed to start {}; run `server --doctor` to check this host", self.name))?;
+        info!("{} pid: {}", self.name, child.id());
+        let stdout = child.stdout.take().context("X server stdout")?;
+        let (tx, rx) = flume::bounded(1);
+        std::thread::spawn(move || {
+            let mut stdout = BufReader::new(stdout);
+            let mut line = String::new();
+            if stdout.read_line(&mut line).is_ok() {
+                if let Ok(n) = line.trim().parse::<u32>() {
+                    let _ = tx.send(n);
+                }
+            }
+            // Keep the pipe open for as long as the server runs.
+            let _ = std::io::copy(&mut stdout, &mut std::io::sink());
+        });
+        self.child = Some(child);
+        self.ready = Some(rx);
+        Ok(())
+    }
+
+    fn ready(&mut self, display: u32) -> Result<bool> {
+        if let Some(child) = &mut self.child {
+            if let Some(status) = child.try_wait()? {
+                self.child = None;
+                bail!("{} :{display} exited ({status}); run `server --doctor` to check this host", self.name);
+            }
+        }
+        match self.ready.as_ref().map(|rx| rx.try_recv()) {
+            Some(Ok(n)) if n != display => bail!("{} came up on :{n}, not :{display}", self.name),
+            Some(Ok(_)) => {
+                self.ready = None;
+                Ok(true)
+            }
+            Some(Err(_)) => Ok(false),
+            // Reported already
+            None => Ok(self.child.is_some()),
+        }
+    }
+
+    fn stop(&mut self) {
+        self.ready = None;
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        if let Some(auth) = self.auth.take() {
+            let _ = std::fs::remove_file(auth);
+        }
+    }
+}
+
+/// Xvfb, the virtual framebuffer X server.
+pub struct Xvfb {
+    server: Server,
+    screen: String,
+    dpi: u16,
+    extensions: Vec<String>,
+}
+
+impl Xvfb {
+    pub fn new(cfg: &ServerConfig) -> Self {
+        Xvfb {
+            server: Server::new("Xvfb"),
+            screen: format!("{}x{}x{}", cfg.width, cfg.height, cfg.depth),
+            dpi: cfg.dpi,
+            extensions: cfg.extensions.clone(),
+        }
+    }
+}
+
+impl DisplayBackend for Xvfb {
+    fn name(&self) -> &str {
+        "Xvfb"
+    }
+
+    fn start(&mut self, display: u32) -> Result<()> {
+        let mut cmd = Command::new("Xvfb");
+        for ext in &self.extensions {
+            cmd.args(["+extension", ext]);
+        }
+        cmd.args(["-screen", "0", &self.screen, "-nolisten", "tcp", "-noreset", "-dpi", &self.dpi.to_string()])
+            .arg(format!(":{display}"));
+        self.server.spawn(&mut cmd, display)
+    }
+
+    fn xauthority(&self) -> Option<&Path> {
+        self.server.auth.as_deref()
+    }
+
+    fn ready(&mut self, display: u32) -> Result<bool> {
+        self.server.ready(display)
+    }
+
+    fn stop(&mut self) {
+        self.server.stop();
+    }
+}
+
+/// Xorg with the dummy video driver. Unlike Xvfb it takes new RANDR modes of
+/// any size, so the screen can follow the client's window.
+pub struct XorgDummy {
+    server: Server,
+    width: u16,
+    height: u16,
+    depth: u8,
+    dpi: u16,
+    extensions: Vec<String>,
+    files: Vec<PathBuf>,
+}
+
+/// Largest screen the dummy driver is set up for.
+const XORG_MAX: (u16, u16) = (7680, 4320);
+
+impl XorgDummy {
+    pub fn new(cfg: &ServerConfig) -> Self {
+        XorgDummy {
+            server: Server::new("Xorg"),
+            width: cfg.width,
+            height: cfg.height,
+            depth: cfg.depth,
+            dpi: cfg.dpi,
+            extensions: cfg.extensions.clone(),
+            files: Vec::new(),
+        }
+    }
+
+    fn config(&self) -> String {
+        let (w, h) = (self.width.max(XORG_MAX.0), self.height.max(XORG_MAX.1));
+        // Enough video memory for the largest screen at 32 bits per pixel.
+        let video_ram = u32::from(w) * u32::from(h) * 4 / 1024 + 1024;
+        let (mode, line) = modeline(self.width, self.height);
+        format!(
+            r#"Section "ServerFlags"
+    Option "DontVTSwitch" "true"
+    Option "AllowMouseOpenFail" "true"
+    Option "PciForceNone" "true"
+    Option "AutoEnableDevices" "false"
+    Option "AutoAddDevices" "false"
+EndSection
+
+Section "Device"
+    Identifier "dummy"
+    Driver "dummy"
+    VideoRam {video_ram}
+EndSection
+
+Section "Monitor"
+    Identifier "monitor"
+    HorizSync 1.0-2000.0
+    VertRefresh 1.0-200.0
+    Modeline {line}
+EndSection
+
+Section "Screen"
+    Identifier "screen"
+    Device "dummy"
+    Monitor "monitor"
+    DefaultDepth {depth}
+    SubSection "Display"
+        Depth {depth}
+        Modes "{mode}"
+        Virtual {w} {h}
+    EndSubSection
+EndSection
+"#,
+            depth = self.depth,
+        )
+    }
+}
+
+impl DisplayBackend for XorgDummy {
+    fn name(&self) -> &str {
+        "Xorg (dummy)"
+    }
+
+    fn start(&mut self, display: u32) -> Result<()> {
+        let dir = runtime_dir();
+        std::fs::create_dir_all(&dir)?;
+        let conf = dir.join(format!("xorg-{display}.conf"));
+        // An empty config directory keeps the system's input and GPU snippets out.
+        let conf_d = dir.join(format!("xorg-{display}.conf.d"));
+        let log = dir.join(format!("xorg-{display}.log"));
+        std::fs::create_dir_all(&conf_d)?;
+        std::fs::write(&conf, self.config()).with_context(|| format!("write {}", conf.display()))?;
+        self.files = vec![conf.clone(), conf_d.clone(), log.clone()];
+
+        let mut cmd = Command::new("Xorg");
+        for ext in &self.extensions {
+            cmd.args(["+extension", ext]);
+        }
+        cmd.args(["-noreset", "-novtswitch", "-nolisten", "tcp", "-dpi", &self.dpi.to_string()])
+            .arg("-config")
+            .arg(&conf)
+            .arg("-configdir")
+            .arg(&conf_d)
+            .arg("-logfile")
+            .arg(&log)
+            .arg(format!(":{display}"))
+            .stderr(Stdio::null());
+        self.server.spawn(&mut cmd, display)
+    }
+
+    fn xauthority(&self) -> Option<&Path> {
+        self.server.auth.as_deref()
+    }
+
+    fn ready(&mut self, display: u32) -> Result<bool> {
+        self.server.ready(display).with_context(|| format!("see {}", self.files[2].display()))
+    }
+
+    fn stop(&mut self) {
+        self.server.stop();
+        for f in self.files.drain(..) {
+            let _ = std::fs::remove_file(&f).or_else(|_| std::fs::remove_dir(&f));
+        }
+    }
+}
+
+/// Mode timings at 60 Hz with reduced blanking, in pixels and lines.
+struct Timings {
+    /// Dot clock in Hz
+    clock: u32,
+    hsync: u32,
+    hsync_end: u32,
+    htotal: u32,
+    vsync: u32,
+    vsync_end: u32,
+    vtotal: u32,
+}
+
+fn timings(w: u16, h: u16) -> Timings {
+    let (w, h) = (u32::from(w), u32::from(h));
+    let vblank = (h * 6 / 1000 + 1) * 5 + 10;
+    let (htotal, vtotal) = (w + 160, h + vblank.max(23));
+    Timings {
+        clock: htotal * vtotal * 60,
+        hsync: w + 48,
+        hsync_end: w + 80,
+        htotal,
+        vsync: h + 3,
+        vsync_end: h + 9,
+        vtotal,
+    }
+}
+
+/// A modeline for `w`x`h` at 60 Hz, with reduced blanking; returns the mode
+/// name and the line for the Monitor section.
+pub fn modeline(w: u16, h: u16) -> (String, String) {
+    let t = timings(w, h);
+    let clock = f64::from(t.clock) / 1e6;
+    let name = format!("{w}x{h}");
+    let line = format!(
+        "\"{name}\" {clock:.2} {w} {} {} {} {h} {} {} {} +hsync -vsync",
+        t.hsync, t.hsync_end, t.htotal, t.vsync, t.vsync_end, t.vtotal
+    );
+    (name, line)
+}
+
+/// Names of the RANDR modes [`set_screen_size`] adds start with this, so it
+/// can tell them from the server's own and remove them when done with them.
+const MODE_PREFIX: &str = "remap-";
+
+/// Resize the screen of `display` to `width`x`height` through RANDR, adding
+/// a mode of that size to the output if it has none. The size is clamped to
+/// what the X server allows (Xvfb can't grow past the size it started with);
+/// returns the size the screen has now.
+pub fn set_screen_size(display: u32, auth: Option<&Path>, width: u16, height: u16) -> Result<(u16, u16)> {
+    use x11rb::connection::Connection as _;
+    use x11rb::protocol::randr::{self, ConnectionExt as _};
+
+    let (conn, screen) = xauth::connect(display, auth)?;
+    let root = conn.setup().roots[screen].clone();
+    conn.randr_query_version(1, 3)?.reply().context("no RANDR")?;
+    let range = conn.randr_get_screen_size_range(root.root)?.reply()?;
+    let w = width.clamp(range.min_width, range.max_width);
+    let h = height.clamp(range.min_height, range.max_height);
+    let current = (root.width_in_pixels, root.height_in_pixels);
+    if (w, h) == current {
+        return Ok(current);
+    }
+
+    let res = conn.randr_get_screen_resources_current(root.root)?.reply()?;
+    // The first output with a CRTC drives the screen.
+    let (output, crtc) = res
+        .outputs
+        .iter()
+        .find_map(|&o| {
+            let info = conn.randr_get_output_info(o, res.config_timestamp).ok()?.reply().ok()?;
+            let crtc = if info.crtc != 0 { info.crtc } else { *info.crtcs.first()? };
+            (info.connection == randr::Connection::CONNECTED).then_some((o, crtc))
+        })
+        .context("no RANDR output to resize")?;
+    let mode = match res.modes.iter().find(|m| (m.width, m.height) == (w, h)) {
+        Some(m) => m.id,
+        None => {
+            let t = timings(w, h);
+            let name = format!("{MODE_PREFIX}{w}x{h}");
+            let info = randr::ModeInfo {
+                id: 0,
+                width: w,
+                height: h,
+                dot_clock: t.clock,
+                hsync_start: t.hsync as u16,
+                hsync_end: t.hsync_end as u16,
+                htotal: t.htotal as u16,
+                hskew: 0,
+                vsync_start: t.vsync as u16,
+                vsync_end: t.vsync_end as u16,
+                vtotal: t.vtotal as u16,
+                name_len: name.len() as u16,
+                mode_flags: randr::ModeFlag::HSYNC_POSITIVE | randr::ModeFlag::VSYNC_NEGATIVE,
+            };
+            let mode = conn.randr_create_mode(root.root, info, name.as_bytes())?.reply()?.mode;
+            conn.randr_add_output_mode(output, mode)?.check()?;
+            debug!("Added RANDR mode {name}");
+            mode
+        }
+    };
+    let rotation = conn.randr_get_crtc_info(crtc, res.config_timestamp)?.reply()?.rotation;
+
+    // Keep the physical size in step so the DPI stays what it was.
+    let mm = |px: u16, px0: u16, mm0: u16| (u32::from(px) * u32::from(mm0) / u32::from(px0.max(1))).max(1);
+    let set_size = |w: u16, h: u16| -> Result<()> {
+        let mm_w = mm(w, root.width_in_pixels, root.width_in_millimeters);
+        let mm_h = mm(h, root.height_in_pixels, root.height_in_millimeters);
+        conn.randr_set_screen_size(root.root, w, h, mm_w, mm_h)?.check()?;
+        Ok(())
+    };
+    // The CRTC has to fit the screen at every step: grow first, shrink last.
+    let grown = (w.max(current.0), h.max(current.1));
+    if grown != current {
+        set_size(grown.0, grown.1)?;
+    }
+    let reply = conn
+        .randr_set_crtc_config(crtc, x11rb::CURRENT_TIME, res.config_timestamp, 0, 0, mode, rotation, &[output])?
+        .reply()?;
+    if reply.status != randr::SetConfig::SUCCESS {
+        bail!("RANDR refused {w}x{h}: {:?}", reply.status);
+    }
+    if grown != (w, h) {
+        set_size(w, h)?;
+    }
+
+    // The CRTC has left the modes earlier resizes added; drop them so they
+    // don't pile up over a long session.
+    let mut names = &res.names[..];
+    for m in &res.modes {
+        let (name, rest) = names.split_at(usize::from(m.name_len).min(names.len()));
+        names = rest;
+        if m.id == mode || !name.starts_with(MODE_PREFIX.as_bytes()) {
+            continue;
+        }
+        let remove = || -> Result<()> {
+            conn.randr_delete_output_mode(output, m.id)?.check()?;
+            conn.randr_destroy_mode(m.id)?.check()?;
+            Ok(())
+        };
+        if let Err(e) = remove() {
+            debug!("Could not remove RANDR mode {}: {e}", String::from_utf8_lossy(name));
+        }
+    }
+    conn.flush()?;
+    Ok((w, h))
+}
+
+/// A display that is already running, e.g. the desktop of whoever is logged
+/// in. We only connect to it.
+pub struct Existing {
+    display: u32,
+}
+
+impl Existing {
+    /// The display in $DISPLAY, which must be a local one.
+    pub fn from_env() -> Result<Self> {
+        let var = std::env::var("DISPLAY").context("the existing display backend needs $DISPLAY")?;
+        let display = parse_display(&var).with_context(|| format!("$DISPLAY={var} is not a local display like :0"))?;
+        Ok(Existing { display })
+    }
+}
+
+impl DisplayBackend for Existing {
+    fn name(&self) -> &str {
+        "existing display"
+    }
+
+    fn fixed_display(&self) -> Option<u32> {
+        Some(self.display)
+    }
+
+    fn shared(&self) -> bool {
+        true
+    }
+
+    fn start(&mut self, _display: u32) -> Result<()> {
+        Ok(())
+    }
+
+    fn ready(&mut self, display: u32) -> Result<bool> {
+        if !accepts_connections(display) {
+            bail!("no X server answers on :{display}");
+        }
+        Ok(true)
+    }
+
+    fn stop(&mut self) {}
+}
+
+/// The display number in a local display name: ":1", ":1.0" or "unix:1".
+fn parse_display(name: &str) -> Option<u32> {
+    let number = name.strip_prefix(':').or_else(|| name.strip_prefix("unix:"))?;
+    number.split('.').next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Records what it was asked to do; ready after `ready_after` polls, or
+    /// fails its readiness check if None.
+    struct Fake {
+        calls: Arc<Mutex<Vec<String>>>,
+        ready_after: Option<u32>,
+    }
+
+    impl DisplayBackend for Fake {
+        fn name(&self) -> &str {
+            "fake"
+        }
+
+        fn start(&mut self, display: u32) -> Result<()> {
+            self.calls.lock().unwrap().push(format!("start :{display}"));
+            Ok(())
+        }
+
+        fn ready(&mut self, _display: u32) -> Result<bool> {
+            self.calls.lock().unwrap().push("ready".to_string());
+            match &mut self.ready_after {
+                Some(0) => Ok(true),
+                Some(n) => {
+                    *n -= 1;
+                    Ok(false)
+                }
+                None => bail!("exited"),
+            }
+        }
+
+        fn stop(&mut self) {
+            self.calls.lock().unwrap().push("stop".to_string());
+        }
+    }
+
+    fn fake(ready_after: Option<u32>) -> (Box<dyn DisplayBackend>, Arc<Mutex<Vec<String>>>) {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        (Box::new(Fake { calls: calls.clone(), ready_after }), calls)
+    }
+
+    #[test]
+    fn display_lifecycle() {
+        let (backend, calls) = fake(Some(2));
+        let mut display = Display::start(backend, DisplaySpec::Number(42), Duration::from_secs(5)).unwrap();
+        assert_eq!(display.number(), 42);
+        display.stop();
+        drop(display);
+        assert_eq!(*calls.lock().unwrap(), ["start :42", "ready", "ready", "ready", "stop"]);
+
+        // A display that dies while starting is still torn down.
+        let (backend, calls) = fake(None);
+        assert!(Display::start(backend, DisplaySpec::Number(42), Duration::from_secs(5)).is_err());
+        assert_eq!(*calls.lock().unwrap(), ["start :42", "ready", "stop"]);
+
+        let (backend, calls) = fake(Some(u32::MAX));
+        assert!(Display::start(backend, DisplaySpec::Number(42), Duration::from_millis(250)).is_err());
+        assert_eq!(calls.lock().unwrap().last().unwrap(), "stop");
+    }
+
+    #[test]
+    fn stale_locks_are_free() {
+        let n = 60_000 + std::process::id() % 1000;
+        let lock = format!("/tmp/.X{n}-lock");
+        std::fs::write(&lock, format!("{:>10}\n", std::process::id())).unwrap();
+        assert!(display_in_use(n));
+        std::fs::write(&lock, format!("{:>10}\n", i32::MAX)).unwrap();
+        assert!(!display_in_use(n));
+        assert_eq!(free_display(n).unwrap(), n);
+        std::fs::remove_file(&lock).unwrap();
+    }
+
+    #[test]
+    fn display_names_and_modelines() {
+        assert_eq!(parse_display(":0"), Some(0));
+        assert_eq!(parse_display(":12.0"), Some(12));
+        assert_eq!(parse_display("unix:3"), Some(3));
+        assert_eq!(parse_display("remote:0"), None);
+        assert_eq!(lock_owner("      4242\n"), Some(4242));
+        assert_eq!(lock_owner("garbage"), None);
+
+        let (name, line) = modeline(1920, 1080);
+        assert_eq!(name, "1920x1080");
+        assert_eq!(line, "\"1920x1080\" 140.40 1920 1968 2000 2080 1080 1083 1089 1125 +hsync -vsync");
+        let t = timings(1920, 1080);
+        assert_eq!((t.clock, t.htotal, t.vtotal), (140_400_000, 2080, 1125));
+    }
+}
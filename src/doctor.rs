@@ -0,0 +1,251 @@
+//! Checks that a host has what the server needs: the X server and helper
+//! programs, the X extensions capture and input rely on, fonts, and a
+//! writable X socket directory. `server --doctor` prints the report; the
+//! client asks for it (`--doctor --json`) before launching a server.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    /// The server runs, but something (the keyboard layout, text) won't work
+    Warn,
+    /// The server won't start
+    Fail,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    pub detail: String,
+    /// What to do about it, for Warn and Fail
+    pub fix: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    /// True unless a check failed.
+    pub fn ok(&self) -> bool {
+        self.checks.iter().all(|c| c.status != Status::Fail)
+    }
+
+    fn push(&mut self, name: &str, status: Status, detail: impl Into<String>, fix: Option<&str>) {
+        self.checks.push(Check { name: name.to_string(), status, detail: detail.into(), fix: fix.map(str::to_string) });
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = self.checks.iter().map(|c| c.name.len()).max().unwrap_or(0);
+        for c in &self.checks {
+            let status = match c.status {
+                Status::Ok => "ok",
+                Status::Warn => "WARN",
+                Status::Fail => "FAIL",
+            };
+            writeln!(f, "{status:<4}  {:<width$}  {}", c.name, c.detail)?;
+            if let Some(fix) = &c.fix {
+                writeln!(f, "{:<4}  {:<width$}  fix: {fix}", "", "")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Helper programs the server runs: (name, what it is for, fix).
+const PROGRAMS: &[(&str, &str, &str)] = &[
+    ("setxkbmap", "keyboard layout", "apt install x11-xkb-utils / dnf install setxkbmap"),
+];
+
+/// Where distributions put Xorg's video drivers.
+const XORG_DRIVERS: &[&str] = &["/usr/lib/xorg/modules/drivers", "/usr/lib64/xorg/modules/drivers", "/usr/local/lib/xorg/modules/drivers"];
+
+/// X extensions capture and input use.
+pub const EXTENSIONS: &[&str] = &["XTEST", "DAMAGE", "MIT-SHM", "RANDR", "XFIXES"];
+
+/// Where `program` is on PATH (or `program` itself if it is a path).
+pub fn find_program(program: &str) -> Option<PathBuf> {
+    if program.contains('/') {
+        return is_executable(Path::new(program)).then(|| PathBuf::from(program));
+    }
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path).map(|d| d.join(program)).find(|p| is_executable(p))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata().is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// Run every check for a server started with `cfg`.
+#[cfg(target_os = "linux")]
+pub fn check(cfg: &crate::config::ServerConfig) -> Report {
+    use crate::config::BackendKind;
+
+    let mut report = Report::default();
+    let mut startable = true;
+    let mut need = |report: &mut Report, name: &str, fix: &str| match find_program(name) {
+        Some(path) => report.push(name, Status::Ok, path.display().to_string(), None),
+        None => {
+            startable = false;
+            report.push(name, Status::Fail, "not found on PATH (needed for the display)", Some(fix));
+        }
+    };
+    match cfg.display_backend {
+        BackendKind::Xvfb => need(&mut report, "Xvfb", "apt install xvfb / dnf install xorg-x11-server-Xvfb"),
+        BackendKind::Xorg => {
+            let fix = "apt install xserver-xorg-core xserver-xorg-video-dummy / dnf install xorg-x11-server-Xorg xorg-x11-drv-dummy";
+            need(&mut report, "Xorg", fix);
+            match XORG_DRIVERS.iter().map(|d| Path::new(d).join("dummy_drv.so")).find(|p| p.exists()) {
+                Some(path) => report.push("dummy driver", Status::Ok, path.display().to_string(), None),
+                None => {
+                    startable = false;
+                    report.push("dummy driver", Status::Fail, "dummy_drv.so not found", Some(fix));
+                }
+            }
+        }
+        BackendKind::Existing => match std::env::var("DISPLAY") {
+            Ok(d) => report.push("DISPLAY", Status::Ok, d, None),
+            Err(_) => {
+                startable = false;
+                report.push("DISPLAY", Status::Fail, "not set", Some("set DISPLAY to the display to share, e.g. DISPLAY=:0"));
+            }
+        },
+    }
+    for &(name, purpose, fix) in PROGRAMS {
+        match find_program(name) {
+            Some(path) => report.push(name, Status::Ok, path.display().to_string(), None),
+            None => report.push(name, Status::Warn, format!("not found on PATH (needed for {purpose})"), Some(fix)),
+        }
+    }
+
+    let app = shell_words::split(&cfg.app).ok().and_then(|w| w.into_iter().next()).unwrap_or_default();
+    if app != "desktop" {
+        match find_program(&app) {
+            Some(path) => report.push("app", Status::Ok, path.display().to_string(), None),
+            None => report.push(
+                "app",
+                Status::Fail,
+                format!("{app:?} not found on PATH"),
+                Some("install it, or pick another app with --app or in server.toml"),
+            ),
+        }
+    }
+
+    check_socket_dir(&mut report);
+    check_fonts(&mut report);
+    if startable {
+        check_extensions(&mut report, cfg);
+    }
+    report
+}
+
+/// Xvfb puts its socket in /tmp/.X11-unix, creating the directory if needed.
+#[cfg(target_os = "linux")]
+fn check_socket_dir(report: &mut Report) {
+    let dir = Path::new("/tmp/.X11-unix");
+    let (probe, what) = if dir.exists() { (dir, "/tmp/.X11-unix") } else { (Path::new("/tmp"), "/tmp") };
+    let Ok(c) = std::ffi::CString::new(probe.as_os_str().as_encoded_bytes()) else { return };
+    if unsafe { libc::access(c.as_ptr(), libc::W_OK) } == 0 {
+        report.push("socket dir", Status::Ok, format!("{what} is writable"), None);
+    } else {
+        report.push(
+            "socket dir",
+            Status::Fail,
+            format!("{what} is not writable"),
+            Some("sudo mkdir -p /tmp/.X11-unix && sudo chmod 1777 /tmp/.X11-unix"),
+        );
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn check_fonts(report: &mut Report) {
+    let fonts = std::process::Command::new("fc-list")
+        .output()
+        .map(|o| String::from_utf8_lossy(&o.stdout).lines().count())
+        .unwrap_or(0);
+    if fonts > 0 {
+        report.push("fonts", Status::Ok, format!("{fonts} fonts installed"), None);
+    } else {
+        report.push(
+            "fonts",
+            Status::Warn,
+            "no fonts found by fc-list; apps may show blank or boxed text",
+            Some("apt install fonts-dejavu-core xfonts-base / dnf install dejavu-sans-mono-fonts xorg-x11-fonts-misc"),
+        );
+    }
+}
+
+/// Start a throwaway display the way the server would and ask it for
+/// [`EXTENSIONS`].
+#[cfg(target_os = "linux")]
+fn check_extensions(report: &mut Report, cfg: &crate::config::ServerConfig) {
+    use crate::config::DisplaySpec;
+    use crate::display::{self, Display};
+    use std::time::Duration;
+    use x11rb::connection::RequestConnection;
+
+    let display = match display::backend(cfg).and_then(|b| Display::start(b, DisplaySpec::Auto, Duration::from_secs(10))) {
+        Ok(d) => d,
+        Err(e) => {
+            let fix = "if the log mentions the font \"fixed\", apt install xfonts-base / dnf install xorg-x11-fonts-misc";
+            return report.push("X server", Status::Fail, format!("{e:#}"), Some(fix));
+        }
+    };
+    let conn = match crate::xauth::connect(display.number(), display.xauthority()) {
+        Ok((conn, _)) => conn,
+        Err(e) => return report.push("X server", Status::Fail, format!("cannot connect to :{}: {e:#}", display.number()), None),
+    };
+    report.push("X server", Status::Ok, format!("{} answers on :{}", display.name(), display.number()), None);
+
+    let missing: Vec<&str> = EXTENSIONS
+        .iter()
+        .copied()
+        .filter(|ext| !matches!(conn.extension_information(ext), Ok(Some(_))))
+        .collect();
+    if missing.is_empty() {
+        report.push("extensions", Status::Ok, EXTENSIONS.join(", "), None);
+    } else {
+        report.push(
+            "extensions",
+            Status::Fail,
+            format!("{} lacks {}", display.name(), missing.join(", ")),
+            Some("use a distribution build of the X server, and don't disable these with -extension"),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_round_trip_as_json() {
+        let mut report = Report::default();
+        report.push("Xvfb", Status::Ok, "/usr/bin/Xvfb", None);
+        report.push("setxkbmap", Status::Warn, "not found on PATH", Some("apt install x11-xkb-utils"));
+        assert!(report.ok());
+        let json = serde_json::to_string(&report).unwrap();
+        assert!(json.contains(r#""status":"warn""#));
+        assert_eq!(serde_json::from_str::<Report>(&json).unwrap(), report);
+
+        report.push("app", Status::Fail, "\"xterm\" not found on PATH", None);
+        assert!(!report.ok());
+        assert!(report.to_string().contains("FAIL  app "));
+    }
+}
@@ -1,6 +1,7 @@
 #![cfg(target_os = "linux")]
 
 use std::collections::HashMap;
+use std::path::Path;
 
 use log::debug;
 use x11rb::connection::Connection;
@@ -76,16 +77,17 @@ pub struct Input {
 }
 
 impl Input {
-    pub fn new() -> Self {
-        let (conn, screen_num) = x11rb::connect(None).expect("X11 connect failed");
+    /// Connect to `display`, with the cookie in `auth` if it has one.
+    pub fn new(display: u32, auth: Option<&Path>) -> Self {
+        let (conn, screen_num) = crate::xauth::connect(display, auth).expect("X11 connect failed");
         let setup = conn.setup();
         let screen = &setup.roots[screen_num];
         let root = screen.root;
 
         let _ = conn.xtest_get_version(2, 2).unwrap().reply().unwrap();
 
-        let min_code = setup.min_keycode.into();
-        let max_code = setup.max_keycode.into();
+        let min_code = setup.min_keycode;
+        let max_code = setup.max_keycode;
         debug!("input: server keycode range = [{min_code}, {max_code}]");
 
         let mapping = fetch_keyboard_mapping(&conn);
@@ -301,7 +303,7 @@ fn fetch_keyboard_mapping(conn: &RustConnection) -> GetKeyboardMappingReply {
     let setup = conn.setup();
     let min = setup.min_keycode;
     let max = setup.max_keycode;
-    let keycode_count = u8::from(max).saturating_sub(u8::from(min)) + 1;
+    let keycode_count = max.saturating_sub(min) + 1;
     conn.get_keyboard_mapping(min, keycode_count).unwrap().reply().unwrap()
 }
 
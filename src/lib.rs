@@ -1,5 +1,12 @@
 pub mod util;
+pub mod auth;
 pub mod canvas;
+pub mod config;
+pub mod doctor;
+pub mod pacer;
+pub mod session;
+pub mod tls;
+pub mod transport;
 
 #[cfg(target_os = "linux")]
 pub mod capture;
@@ -7,6 +14,12 @@ pub mod capture;
 #[cfg(target_os = "linux")]
 pub mod input;
 
+#[cfg(target_os = "linux")]
+pub mod display;
+
+#[cfg(target_os = "linux")]
+pub mod xauth;
+
 use anyhow::Result;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use std::io::{Read, Write};
@@ -139,6 +152,9 @@ pub enum ServerEvent {
     FramebufferUpdate { count: u16, rectangles: Vec<Rec> },
     Bell,
     CutText(String),
+    /// The remote screen is now `width`x`height`; rectangles that follow are
+    /// relative to it
+    DesktopSize { width: u16, height: u16 },
 }
 
 impl Message for ServerEvent {
@@ -162,6 +178,12 @@ impl Message for ServerEvent {
                 reader.read_exact(&mut [0u8; 3])?;
                 Ok(ServerEvent::CutText(String::read_from(reader)?))
             }
+            4 => {
+                reader.read_exact(&mut [0u8; 1])?;
+                let width = reader.read_u16::<BigEndian>()?;
+                let height = reader.read_u16::<BigEndian>()?;
+                Ok(ServerEvent::DesktopSize { width, height })
+            }
             _ => anyhow::bail!("server to client message type"),
         }
     }
@@ -183,11 +205,104 @@ impl Message for ServerEvent {
                 writer.write_all(&[0u8; 3])?;
                 text.write_to(writer)?;
             }
+            ServerEvent::DesktopSize { width, height } => {
+                writer.write_u8(4)?;
+                writer.write_all(&[0u8; 1])?;
+                writer.write_u16::<BigEndian>(*width)?;
+                writer.write_u16::<BigEndian>(*height)?;
+            }
         }
         Ok(())
     }
 }
 
+/* ===== Server ready line ===== */
+
+/// Line the server prints on stdout once it accepts connections, e.g.
+/// `REMAP_READY port=10100 display=100`. Launchers parse it to find the server.
+/// Unknown keys are ignored so fields can be added later.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ReadyInfo {
+    pub port: u16,
+    pub display: u32,
+    /// Session token the client must prove it knows (see [`auth`])
+    pub token: Option<auth::Token>,
+    /// Unix socket the server listens on instead of `port`
+    pub socket: Option<std::path::PathBuf>,
+}
+
+pub const READY_PREFIX: &str = "REMAP_READY";
+
+impl std::fmt::Display for ReadyInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{READY_PREFIX} port={} display={}", self.port, self.display)?;
+        if let Some(token) = &self.token {
+            write!(f, " token={token}")?;
+        }
+        if let Some(socket) = &self.socket {
+            write!(f, " socket={}", escape_path(socket))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for ReadyInfo {
+    type Err = anyhow::Error;
+    fn from_str(line: &str) -> Result<ReadyInfo> {
+        let mut words = line.split_whitespace();
+        if words.next() != Some(READY_PREFIX) {
+            anyhow::bail!("not a ready line: {line}");
+        }
+        let mut info = ReadyInfo::default();
+        for (key, value) in words.filter_map(|w| w.split_once('=')) {
+            match key {
+                "port" => info.port = value.parse()?,
+                "display" => info.display = value.parse()?,
+                "token" => info.token = Some(value.parse()?),
+                "socket" => info.socket = Some(unescape_path(value)?),
+                _ => {}
+            }
+        }
+        if info.port == 0 && info.socket.is_none() {
+            anyhow::bail!("ready line has neither a port nor a socket: {line}");
+        }
+        Ok(info)
+    }
+}
+
+/// Percent-encodes `%`, whitespace and non-printable bytes so a path stays one
+/// word of the ready line.
+fn escape_path(path: &std::path::Path) -> String {
+    use std::os::unix::ffi::OsStrExt;
+    let mut out = String::new();
+    for &b in path.as_os_str().as_bytes() {
+        if b.is_ascii_graphic() && b != b'%' {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{b:02X}"));
+        }
+    }
+    out
+}
+
+fn unescape_path(word: &str) -> Result<std::path::PathBuf> {
+    use std::os::unix::ffi::OsStringExt;
+    let mut bytes = Vec::with_capacity(word.len());
+    let mut rest = word.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = tail.get(..2).and_then(|h| std::str::from_utf8(h).ok());
+            let byte = hex.and_then(|h| u8::from_str_radix(h, 16).ok());
+            bytes.push(byte.ok_or_else(|| anyhow::anyhow!("bad escape in path: {word}"))?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    Ok(std::ffi::OsString::from_vec(bytes).into())
+}
+
 /* ===== Raw pixel rectangles ===== */
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Rec {
@@ -284,3 +399,65 @@ impl Message for Encoding {
         Ok(())
     }
 }
+
+impl std::str::FromStr for Encoding {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Encoding> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "raw" => Encoding::Raw,
+            "copyrect" => Encoding::CopyRect,
+            "rre" => Encoding::Rre,
+            "hextile" => Encoding::Hextile,
+            "zrle" => Encoding::Zrle,
+            "cursor" => Encoding::Cursor,
+            "desktopsize" => Encoding::DesktopSize,
+            _ => anyhow::bail!("unknown encoding: {s}"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ready_lines_round_trip() {
+        let tcp = ReadyInfo { port: 10100, display: 100, token: Some(auth::Token::generate().unwrap()), socket: None };
+        assert_eq!(tcp.to_string().parse::<ReadyInfo>().unwrap(), tcp);
+        let unix = ReadyInfo { port: 0, display: 101, token: None, socket: Some("/run/user/1000/remap/101.sock".into()) };
+        assert_eq!(unix.to_string().parse::<ReadyInfo>().unwrap(), unix);
+        let spaced = ReadyInfo { port: 0, display: 102, token: None, socket: Some("/tmp/my dir/100%.sock".into()) };
+        assert!(spaced.to_string().ends_with(" socket=/tmp/my%20dir/100%25.sock"));
+        assert_eq!(spaced.to_string().parse::<ReadyInfo>().unwrap(), spaced);
+        let later = "REMAP_READY port=10100 display=100 codec=zrle".parse::<ReadyInfo>().unwrap();
+        assert_eq!(later.port, 10100);
+    }
+
+    #[test]
+    fn desktop_size_round_trip() {
+        let mut buf = Vec::new();
+        ServerEvent::DesktopSize { width: 1920, height: 1080 }.write_to(&mut buf).unwrap();
+        assert_eq!(buf, [4, 0, 0x07, 0x80, 0x04, 0x38]);
+        match ServerEvent::read_from(&mut &buf[..]).unwrap() {
+            ServerEvent::DesktopSize { width, height } => assert_eq!((width, height), (1920, 1080)),
+            other => panic!("read back {other:?}"),
+        }
+
+        // Clients ask for it with the DesktopSize pseudo-encoding.
+        let mut buf = Vec::new();
+        ClientEvent::SetEncodings(vec![Encoding::Raw, Encoding::DesktopSize]).write_to(&mut buf).unwrap();
+        match ClientEvent::read_from(&mut &buf[..]).unwrap() {
+            ClientEvent::SetEncodings(encs) => assert_eq!(encs, [Encoding::Raw, Encoding::DesktopSize]),
+            other => panic!("read back {other:?}"),
+        }
+    }
+
+    #[test]
+    fn malformed_ready_lines() {
+        assert!("REMAP_READY display=100".parse::<ReadyInfo>().is_err());
+        assert!("REMAP_READY port=0 display=100".parse::<ReadyInfo>().is_err());
+        assert!("REMAP_READY port=http display=100".parse::<ReadyInfo>().is_err());
+        assert!("port=10100 display=100".parse::<ReadyInfo>().is_err());
+        assert!("REMAP_READY display=100 socket=/tmp/a%2".parse::<ReadyInfo>().is_err());
+    }
+}
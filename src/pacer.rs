@@ -0,0 +1,139 @@
+//! Frame pacing for the server capture loop: caps the frame rate, backs off
+//! while the client is behind, and drops to a slow poll when nothing changes.
+
+use std::time::{Duration, Instant};
+
+/// Largest backoff exponent (interval grows up to 2^MAX_BACKOFF times).
+const MAX_BACKOFF: u32 = 4;
+
+#[derive(Debug, Clone, Copy)]
+pub struct PacerConfig {
+    /// Upper bound on captures per second while the screen is changing.
+    pub max_fps: u32,
+    /// Captures per second once the screen has been idle for `idle_after`.
+    pub idle_fps: u32,
+    /// Time without changes before switching to `idle_fps`.
+    pub idle_after: Duration,
+    /// Frames queued for the writer above which we consider the client behind.
+    pub max_pending: usize,
+}
+
+impl Default for PacerConfig {
+    fn default() -> Self {
+        Self {
+            max_fps: 30,
+            idle_fps: 2,
+            idle_after: Duration::from_millis(2000),
+            max_pending: 2,
+        }
+    }
+}
+
+pub struct FramePacer {
+    frame_interval: Duration,
+    idle_interval: Duration,
+    idle_after: Duration,
+    max_pending: usize,
+    backoff: u32,
+    last_change: Instant,
+}
+
+impl FramePacer {
+    pub fn new(cfg: PacerConfig) -> Self {
+        Self {
+            frame_interval: interval(cfg.max_fps),
+            idle_interval: interval(cfg.idle_fps).max(interval(cfg.max_fps)),
+            idle_after: cfg.idle_after,
+            max_pending: cfg.max_pending,
+            backoff: 0,
+            last_change: Instant::now(),
+        }
+    }
+
+    /// Leave idle mode right away (client input or an explicit update request).
+    pub fn wake(&mut self) {
+        self.last_change = Instant::now();
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.last_change.elapsed() >= self.idle_after
+    }
+
+    /// Interval between captures in active mode, including any backoff.
+    pub fn active_interval(&self) -> Duration {
+        self.frame_interval * (1 << self.backoff)
+    }
+
+    /// Record the outcome of a capture and return how long to wait before the next one.
+    ///  - `changed`: the capture produced at least one rectangle
+    ///  - `pending`: frames still queued for the writer thread
+    pub fn next_delay(&mut self, changed: bool, pending: usize) -> Duration {
+        if changed {
+            self.last_change = Instant::now();
+        }
+
+        if pending > self.max_pending {
+            self.backoff = (self.backoff + 1).min(MAX_BACKOFF);
+        } else if pending == 0 && self.backoff > 0 {
+            self.backoff -= 1;
+        }
+
+        if self.is_idle() {
+            self.idle_interval.max(self.active_interval())
+        } else {
+            self.active_interval()
+        }
+    }
+}
+
+fn interval(fps: u32) -> Duration {
+    Duration::from_secs(1) / fps.max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pacer(idle_after_ms: u64) -> FramePacer {
+        FramePacer::new(PacerConfig {
+            max_fps: 50,
+            idle_fps: 5,
+            idle_after: Duration::from_millis(idle_after_ms),
+            max_pending: 2,
+        })
+    }
+
+    #[test]
+    fn caps_frame_rate() {
+        let mut p = pacer(10_000);
+        assert_eq!(p.next_delay(true, 0), Duration::from_millis(20));
+    }
+
+    #[test]
+    fn backs_off_and_recovers() {
+        let mut p = pacer(10_000);
+        assert_eq!(p.next_delay(true, 3), Duration::from_millis(40));
+        assert_eq!(p.next_delay(true, 3), Duration::from_millis(80));
+        for _ in 0..10 {
+            p.next_delay(true, 3);
+        }
+        assert_eq!(p.active_interval(), Duration::from_millis(20 << MAX_BACKOFF));
+        // still queued but under the limit: hold the current rate
+        assert_eq!(p.next_delay(true, 1), Duration::from_millis(320));
+        assert_eq!(p.next_delay(true, 0), Duration::from_millis(160));
+    }
+
+    #[test]
+    fn idles_and_wakes() {
+        let mut p = pacer(0);
+        assert!(p.is_idle());
+        assert_eq!(p.next_delay(false, 0), Duration::from_millis(200));
+
+        let mut p = pacer(10_000);
+        p.last_change -= Duration::from_secs(20);
+        assert!(p.is_idle());
+        p.wake();
+        assert!(!p.is_idle());
+        assert_eq!(p.next_delay(false, 0), Duration::from_millis(20));
+    }
+}
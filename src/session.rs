@@ -0,0 +1,210 @@
+//! Named server sessions. A session server runs detached from the ssh
+//! connection that started it and records itself in
+//! `<runtime dir>/sessions/NAME.toml`, so later connections can list it and
+//! reattach to the same display and app.
+
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::config::runtime_dir;
+use crate::ReadyInfo;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub name: String,
+    pub pid: u32,
+    pub display: u32,
+    pub app: String,
+    pub port: u16,
+    pub socket: Option<PathBuf>,
+    /// Connection token (hex); the file is only readable by its owner
+    pub token: Option<String>,
+    /// Start time, seconds since the epoch
+    pub started: u64,
+    /// When the server process started, in clock ticks after boot; tells it
+    /// apart from a later process that was given the same pid
+    #[serde(default)]
+    pub pid_start: Option<u64>,
+}
+
+/// Session names end up in file names, so keep them simple.
+pub fn check_name(name: &str) -> Result<()> {
+    let ok = !name.is_empty()
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        && !name.starts_with('.');
+    if !ok {
+        bail!("invalid session name {name:?}: use letters, digits, '-', '_' and '.'");
+    }
+    Ok(())
+}
+
+pub fn sessions_dir() -> PathBuf {
+    runtime_dir().join("sessions")
+}
+
+impl Session {
+    pub fn new(name: &str, app: &str, ready: &ReadyInfo) -> Session {
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        Session {
+            name: name.to_string(),
+            pid: std::process::id(),
+            display: ready.display,
+            app: app.to_string(),
+            port: ready.port,
+            socket: ready.socket.clone(),
+            token: ready.token.as_ref().map(|t| t.to_string()),
+            started,
+            pid_start: pid_start(std::process::id()),
+        }
+    }
+
+    pub fn path(name: &str) -> PathBuf {
+        sessions_dir().join(format!("{name}.toml"))
+    }
+
+    /// Write the session file (mode 0600). Returns its path.
+    pub fn save(&self) -> Result<PathBuf> {
+        self.save_in(&sessions_dir())
+    }
+
+    fn save_in(&self, dir: &Path) -> Result<PathBuf> {
+        std::fs::create_dir_all(dir).with_context(|| format!("create {}", dir.display()))?;
+        let path = dir.join(format!("{}.toml", self.name));
+        let text = toml::to_string(self)?;
+
+        let mut opts = std::fs::OpenOptions::new();
+        opts.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            opts.mode(0o600);
+        }
+        std::io::Write::write_all(&mut opts.open(&path)?, text.as_bytes())
+            .with_context(|| format!("write {}", path.display()))?;
+        Ok(path)
+    }
+
+    fn load(path: &Path) -> Result<Session> {
+        let text = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&text)?)
+    }
+
+    /// The live session called `name`, if any.
+    pub fn find(name: &str) -> Result<Option<Session>> {
+        Self::find_in(&sessions_dir(), name)
+    }
+
+    fn find_in(dir: &Path, name: &str) -> Result<Option<Session>> {
+        check_name(name)?;
+        Ok(list_in(dir)?.into_iter().find(|s| s.name == name))
+    }
+
+    /// Whether the server that wrote the file still runs, and not just some
+    /// process that has its pid now.
+    pub fn is_alive(&self) -> bool {
+        match pid_start(self.pid) {
+            Some(now) => self.pid_start.is_none_or(|then| then == now),
+            None => false,
+        }
+    }
+
+    /// Ready line a client uses to connect to this session.
+    pub fn ready_info(&self) -> Result<ReadyInfo> {
+        Ok(ReadyInfo {
+            port: self.port,
+            display: self.display,
+            token: self.token.as_deref().map(str::parse).transpose()?,
+            socket: self.socket.clone(),
+        })
+    }
+
+    /// How long the session has been running, e.g. "2h05m".
+    pub fn age(&self) -> String {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let secs = now.saturating_sub(self.started);
+        match secs {
+            s if s < 60 => format!("{s}s"),
+            s if s < 3600 => format!("{}m", s / 60),
+            s if s < 86400 => format!("{}h{:02}m", s / 3600, s / 60 % 60),
+            s => format!("{}d{:02}h", s / 86400, s / 3600 % 24),
+        }
+    }
+}
+
+/// Start time of process `pid` in clock ticks after boot, from
+/// /proc/PID/stat; None if there is no such process.
+fn pid_start(pid: u32) -> Option<u64> {
+    let stat = std::fs::read_to_string(Path::new("/proc").join(pid.to_string()).join("stat")).ok()?;
+    // The command name in parentheses may hold spaces; field 22 is the start time.
+    let rest = &stat[stat.rfind(')')? + 1..];
+    rest.split_whitespace().nth(19)?.parse().ok()
+}
+
+/// All live sessions, oldest first. Files left behind by dead servers are removed.
+pub fn list() -> Result<Vec<Session>> {
+    list_in(&sessions_dir())
+}
+
+fn list_in(dir: &Path) -> Result<Vec<Session>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("read {}", dir.display())),
+    };
+    let mut sessions = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().is_none_or(|e| e != "toml") {
+            continue;
+        }
+        match Session::load(&path) {
+            Ok(s) if s.is_alive() => sessions.push(s),
+            Ok(s) => {
+                log::debug!("Removing stale session {} (pid {} is gone)", s.name, s.pid);
+                let _ = std::fs::remove_file(&path);
+            }
+            Err(e) => log::warn!("Ignoring {}: {e}", path.display()),
+        }
+    }
+    sessions.sort_by_key(|s| s.started);
+    Ok(sessions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_names() {
+        assert!(check_name("work-1.dev_x").is_ok());
+        for bad in ["", "../etc", "a/b", ".hidden", "with space"] {
+            assert!(check_name(bad).is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn stale_sessions_are_dropped() {
+        let dir = std::env::temp_dir().join(format!("remap-sessions-{}", std::process::id()));
+        let ready = ReadyInfo { port: 10100, display: 100, ..ReadyInfo::default() };
+
+        let live = Session::new("live", "xterm", &ready);
+        assert!(live.pid_start.is_some());
+        live.save_in(&dir).unwrap();
+        // Our pid, but recorded with another start time: the pid was reused.
+        let reused = Session { name: "reused".into(), pid_start: live.pid_start.map(|t| t + 1), ..live.clone() };
+        let reused_path = reused.save_in(&dir).unwrap();
+        let gone = Session { name: "gone".into(), pid: u32::MAX, ..live.clone() };
+        gone.save_in(&dir).unwrap();
+
+        assert_eq!(Session::find_in(&dir, "live").unwrap().unwrap().port, 10100);
+        assert!(Session::find_in(&dir, "reused").unwrap().is_none());
+        assert!(!reused_path.exists());
+        let names: Vec<_> = list_in(&dir).unwrap().into_iter().map(|s| s.name).collect();
+        assert_eq!(names, ["live"]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
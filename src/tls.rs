@@ -0,0 +1,281 @@
+//! TLS for direct (non-ssh) connections: a self-signed server certificate
+//! generated on first run, and clients that pin its SHA-256 fingerprint.
+
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, ClientConnection, DigitallySignedStruct, ServerConfig, ServerConnection, SignatureScheme};
+use sha2::{Digest, Sha256};
+
+use crate::config::config_dir;
+use crate::transport::{Stream, TlsStream};
+
+const CERT_FILE: &str = "cert.pem";
+const KEY_FILE: &str = "key.pem";
+const KNOWN_SERVERS: &str = "known_servers";
+
+/// `sha256:<hex>` of a DER certificate.
+pub fn fingerprint(cert: &[u8]) -> String {
+    let digest = Sha256::digest(cert);
+    let hex: String = digest.iter().map(|b| format!("{b:02x}")).collect();
+    format!("sha256:{hex}")
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// Server side TLS settings plus the certificate fingerprint to show users.
+pub struct TlsServer {
+    pub config: Arc<ServerConfig>,
+    pub fingerprint: String,
+}
+
+impl TlsServer {
+    /// Load the certificate from `dir` (default `<config dir>/tls`), creating a
+    /// self-signed one for this host the first time.
+    pub fn load_or_generate(dir: Option<&Path>) -> Result<Self> {
+        let dir = match dir {
+            Some(d) => d.to_path_buf(),
+            None => config_dir().context("no config directory")?.join("tls"),
+        };
+        let (cert_path, key_path) = (dir.join(CERT_FILE), dir.join(KEY_FILE));
+        if !cert_path.exists() || !key_path.exists() {
+            generate_cert(&dir, &cert_path, &key_path)?;
+        }
+
+        let cert = CertificateDer::from_pem_file(&cert_path)
+            .with_context(|| format!("read {}", cert_path.display()))?;
+        let key = PrivateKeyDer::from_pem_file(&key_path)
+            .with_context(|| format!("read {}", key_path.display()))?;
+        let fingerprint = fingerprint(&cert);
+
+        let config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(vec![cert], key)?;
+        Ok(Self { config: Arc::new(config), fingerprint })
+    }
+
+    pub fn accept(&self, sock: TcpStream) -> std::io::Result<Stream> {
+        let conn = ServerConnection::new(Arc::clone(&self.config)).map_err(std::io::Error::other)?;
+        Ok(Stream::Tls(TlsStream::handshake(sock, conn)?))
+    }
+}
+
+fn generate_cert(dir: &Path, cert_path: &Path, key_path: &Path) -> Result<()> {
+    let host = hostname();
+    info!("Generating TLS certificate for {host} in {}", dir.display());
+    let names = vec![host, "localhost".to_string()];
+    let rcgen::CertifiedKey { cert, key_pair } = rcgen::generate_simple_self_signed(names)?;
+
+    std::fs::create_dir_all(dir)?;
+    write_private(key_path, key_pair.serialize_pem().as_bytes())?;
+    std::fs::write(cert_path, cert.pem())?;
+    Ok(())
+}
+
+fn hostname() -> String {
+    std::fs::read_to_string("/etc/hostname")
+        .ok()
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .unwrap_or_else(|| "localhost".to_string())
+}
+
+/// Write a file only the owner can read.
+fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut opts = std::fs::OpenOptions::new();
+    opts.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(0o600);
+    }
+    std::io::Write::write_all(&mut opts.open(path)?, data)
+}
+
+/// Connect to `addr` over TLS. The server certificate must match `pinned`; without
+/// a pin, the fingerprint recorded in `known_servers` on first use is required.
+pub fn connect(addr: &str, pinned: Option<&str>) -> Result<Stream> {
+    let known = known_servers_path();
+    let expected = match pinned {
+        Some(fp) => Some(fp.to_ascii_lowercase()),
+        None => known.as_deref().and_then(|p| lookup_known(p, addr)),
+    };
+
+    let verifier = Arc::new(PinnedCert { expected: expected.clone(), seen: Default::default(), provider: provider() });
+    let config = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(verifier.clone())
+        .with_no_client_auth();
+
+    // Certificates are pinned, so the name only matters for SNI.
+    let host = addr.rsplit_once(':').map(|(h, _)| h).unwrap_or(addr).trim_matches(['[', ']']);
+    let name = ServerName::try_from(host.to_string()).unwrap_or_else(|_| ServerName::try_from("remap").unwrap());
+    let conn = ClientConnection::new(Arc::new(config), name)?;
+
+    let sock = TcpStream::connect(addr).with_context(|| format!("connect to {addr} failed"))?;
+    let stream = TlsStream::handshake(sock, conn).with_context(|| format!("TLS handshake with {addr} failed"))?;
+
+    if expected.is_none() {
+        let fp = verifier.seen.lock().unwrap().clone().unwrap_or_default();
+        warn!("First TLS connection to {addr}; trusting certificate {fp}");
+        if let Some(p) = known {
+            remember_known(&p, addr, &fp)?;
+        }
+    }
+    Ok(Stream::Tls(stream))
+}
+
+fn known_servers_path() -> Option<PathBuf> {
+    Some(config_dir()?.join(KNOWN_SERVERS))
+}
+
+/// `known_servers` lines are `host:port sha256:<hex>`.
+fn lookup_known(path: &Path, addr: &str) -> Option<String> {
+    let text = std::fs::read_to_string(path).ok()?;
+    text.lines()
+        .filter_map(|l| l.split_once(' '))
+        .find(|(a, _)| *a == addr)
+        .map(|(_, fp)| fp.trim().to_string())
+}
+
+fn remember_known(path: &Path, addr: &str, fp: &str) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut f = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    std::io::Write::write_all(&mut f, format!("{addr} {fp}\n").as_bytes())?;
+    Ok(())
+}
+
+/// Accepts exactly one certificate, identified by fingerprint (or any, on first use).
+#[derive(Debug)]
+struct PinnedCert {
+    expected: Option<String>,
+    seen: std::sync::Mutex<Option<String>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fp = fingerprint(end_entity);
+        if let Some(expected) = &self.expected {
+            if *expected != fp {
+                return Err(rustls::Error::General(format!(
+                    "server certificate {fp} does not match pinned {expected}"
+                )));
+            }
+        }
+        *self.seen.lock().unwrap() = Some(fp);
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    #[test]
+    fn pinned_round_trip() {
+        let dir = std::env::temp_dir().join(format!("remap-tls-{}", std::process::id()));
+        let server = TlsServer::load_or_generate(Some(&dir)).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let t = std::thread::spawn(move || {
+            let (sock, _) = listener.accept().unwrap();
+            assert!(server.accept(sock).is_err());
+            let (sock, _) = listener.accept().unwrap();
+            let mut s = server.accept(sock).unwrap();
+            let mut buf = [0u8; 5];
+            s.read_exact(&mut buf).unwrap();
+            s.write_all(&buf).unwrap();
+            server.fingerprint
+        });
+
+        // A wrong pin is refused before any data flows.
+        assert!(connect(&addr, Some("sha256:00")).is_err());
+
+        let fp = TlsServer::load_or_generate(Some(&dir)).unwrap().fingerprint;
+        let mut s = connect(&addr, Some(&fp)).unwrap();
+        s.write_all(b"hello").unwrap();
+        let mut buf = [0u8; 5];
+        s.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        assert_eq!(t.join().unwrap(), fp);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// Both ends send more than the socket buffers hold at the same time, each
+    /// reading on one thread and writing on another as client and server do.
+    #[test]
+    fn writes_both_ways_at_once() {
+        const LEN: usize = 8 << 20;
+        let dir = std::env::temp_dir().join(format!("remap-tls-both-{}", std::process::id()));
+        let server = TlsServer::load_or_generate(Some(&dir)).unwrap();
+        let fp = server.fingerprint.clone();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let accept = std::thread::spawn(move || server.accept(listener.accept().unwrap().0).unwrap());
+        let client = connect(&addr, Some(&fp)).unwrap();
+        let server = accept.join().unwrap();
+
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        for mut s in [client, server] {
+            let mut w = s.try_clone().unwrap();
+            std::thread::spawn(move || w.write_all(&vec![7u8; LEN]).unwrap());
+            let done_tx = done_tx.clone();
+            std::thread::spawn(move || {
+                let mut buf = vec![0u8; LEN];
+                s.read_exact(&mut buf).unwrap();
+                done_tx.send(buf.iter().all(|&b| b == 7)).unwrap();
+            });
+        }
+        for _ in 0..2 {
+            assert!(done_rx.recv_timeout(std::time::Duration::from_secs(60)).unwrap());
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,319 @@
+//! Byte streams carrying the remap protocol: plain TCP (behind an ssh tunnel),
+//! a Unix socket (forwarded by ssh), or TLS for direct connections. All can be
+//! cloned so one thread reads `Message`s while another writes them.
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+#[cfg(target_os = "linux")]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(target_os = "linux")]
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rustls::Connection;
+
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(target_os = "linux")]
+    Unix(UnixStream),
+    Tls(TlsStream),
+}
+
+impl Stream {
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        Ok(match self {
+            Stream::Tcp(s) => Stream::Tcp(s.try_clone()?),
+            #[cfg(target_os = "linux")]
+            Stream::Unix(s) => Stream::Unix(s.try_clone()?),
+            Stream::Tls(s) => Stream::Tls(s.try_clone()?),
+        })
+    }
+
+    /// No-op on Unix sockets.
+    pub fn set_nodelay(&self, on: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.set_nodelay(on),
+            #[cfg(target_os = "linux")]
+            Stream::Unix(_) => Ok(()),
+            Stream::Tls(s) => s.sock.set_nodelay(on),
+        }
+    }
+
+    pub fn set_read_timeout(&self, t: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.set_read_timeout(t),
+            #[cfg(target_os = "linux")]
+            Stream::Unix(s) => s.set_read_timeout(t),
+            Stream::Tls(s) => s.sock.set_read_timeout(t),
+        }
+    }
+
+    pub fn set_write_timeout(&self, t: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.set_write_timeout(t),
+            #[cfg(target_os = "linux")]
+            Stream::Unix(s) => s.set_write_timeout(t),
+            Stream::Tls(s) => s.sock.set_write_timeout(t),
+        }
+    }
+
+    pub fn shutdown(&self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.shutdown(Shutdown::Both),
+            #[cfg(target_os = "linux")]
+            Stream::Unix(s) => s.shutdown(Shutdown::Both),
+            Stream::Tls(s) => s.sock.shutdown(Shutdown::Both),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.read(buf),
+            #[cfg(target_os = "linux")]
+            Stream::Unix(s) => s.read(buf),
+            Stream::Tls(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.write(buf),
+            #[cfg(target_os = "linux")]
+            Stream::Unix(s) => s.write(buf),
+            Stream::Tls(s) => s.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.flush(),
+            #[cfg(target_os = "linux")]
+            Stream::Unix(s) => s.flush(),
+            Stream::Tls(s) => s.flush(),
+        }
+    }
+}
+
+/// Where the server accepts clients.
+pub enum Listener {
+    Tcp(TcpListener),
+    /// Socket file only the owner can use; removed on drop.
+    #[cfg(target_os = "linux")]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    /// Bind a Unix socket at `path` with mode 0600, replacing a stale socket file.
+    #[cfg(target_os = "linux")]
+    pub fn bind_unix(path: &Path) -> io::Result<Listener> {
+        use std::os::unix::fs::PermissionsExt;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+            std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))?;
+        }
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is in use", path.display())));
+        }
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        Ok(Listener::Unix(listener, path.to_path_buf()))
+    }
+
+    /// Wait for the next client. Returns the raw stream and a description of the peer.
+    /// Unix socket peers running as another user are turned away here.
+    pub fn accept(&self) -> io::Result<(Stream, String)> {
+        match self {
+            Listener::Tcp(l) => {
+                let (s, peer) = l.accept()?;
+                Ok((Stream::Tcp(s), peer.to_string()))
+            }
+            #[cfg(target_os = "linux")]
+            Listener::Unix(l, _) => loop {
+                let (s, _) = l.accept()?;
+                let (uid, pid) = peer_cred(&s)?;
+                let me = unsafe { libc::getuid() };
+                if uid != me {
+                    log::warn!("Refused unix socket peer pid {pid}: uid {uid} is not {me}");
+                    continue;
+                }
+                return Ok((Stream::Unix(s), format!("pid {pid}")));
+            },
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Uid and pid of the process on the other end (SO_PEERCRED).
+#[cfg(target_os = "linux")]
+fn peer_cred(s: &UnixStream) -> io::Result<(u32, i32)> {
+    use std::os::fd::AsRawFd;
+    let mut cred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let rc = unsafe {
+        libc::getsockopt(
+            s.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((cred.uid, cred.pid))
+}
+
+/// How long a TLS peer may stay silent during the handshake.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// TLS over TCP. Clones share one rustls session; the socket is read and
+/// written without holding the session lock, so a reader or writer blocked on
+/// the socket never stalls the other.
+pub struct TlsStream {
+    sock: TcpStream,
+    conn: Arc<Mutex<Connection>>,
+    // held by a writer from encrypting until its records are on the socket,
+    // so records go out in the order rustls made them
+    send: Arc<Mutex<()>>,
+    // ciphertext read from the socket but not yet accepted by rustls
+    pending: Vec<u8>,
+}
+
+impl TlsStream {
+    /// Run the handshake to completion on `sock`, giving up on a peer that
+    /// stalls for longer than [`HANDSHAKE_TIMEOUT`].
+    pub fn handshake(mut sock: TcpStream, conn: impl Into<Connection>) -> io::Result<Self> {
+        let mut conn = conn.into();
+        let timeouts = (sock.read_timeout()?, sock.write_timeout()?);
+        sock.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        sock.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut sock)?;
+        }
+        sock.set_read_timeout(timeouts.0)?;
+        sock.set_write_timeout(timeouts.1)?;
+        Ok(Self { sock, conn: Arc::new(Mutex::new(conn)), send: Arc::default(), pending: Vec::new() })
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            sock: self.sock.try_clone()?,
+            conn: Arc::clone(&self.conn),
+            send: Arc::clone(&self.send),
+            pending: Vec::new(),
+        })
+    }
+
+    /// Run `f` on the session, then write whatever records it queued to the
+    /// socket once the session lock is released.
+    fn send<T>(&self, f: impl FnOnce(&mut Connection) -> io::Result<T>) -> io::Result<T> {
+        let _send = self.send.lock().map_err(|_| io::Error::other("tls session poisoned"))?;
+        let (result, records) = {
+            let mut conn = lock(&self.conn)?;
+            let result = f(&mut conn)?;
+            let mut records = Vec::new();
+            while conn.wants_write() {
+                conn.write_tls(&mut records)?;
+            }
+            (result, records)
+        };
+        (&self.sock).write_all(&records)?;
+        Ok(result)
+    }
+}
+
+fn lock(conn: &Mutex<Connection>) -> io::Result<std::sync::MutexGuard<'_, Connection>> {
+    conn.lock().map_err(|_| io::Error::other("tls session poisoned"))
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            {
+                let mut conn = lock(&self.conn)?;
+                match conn.reader().read(buf) {
+                    Ok(n) => return Ok(n),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => return Err(e),
+                }
+                // Plaintext drained: hand rustls the next piece of buffered ciphertext.
+                if !self.pending.is_empty() {
+                    let mut rd = &self.pending[..];
+                    let used = conn.read_tls(&mut rd)?;
+                    if used == 0 {
+                        return Err(io::Error::other("tls: record buffer full"));
+                    }
+                    self.pending.drain(..used);
+                    // Anything rustls wants to send back (e.g. a key update)
+                    // goes out with the next write.
+                    conn.process_new_packets().map_err(io::Error::other)?;
+                    continue;
+                }
+            }
+
+            // Need more records: block on the socket without holding the session.
+            let mut raw = [0u8; 16 * 1024];
+            let n = self.sock.read(&mut raw)?;
+            if n == 0 {
+                let mut conn = lock(&self.conn)?;
+                conn.read_tls(&mut io::empty())?; // tell rustls the peer is gone
+                conn.process_new_packets().map_err(io::Error::other)?;
+                return conn.reader().read(buf);
+            }
+            self.pending.extend_from_slice(&raw[..n]);
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.send(|conn| conn.writer().write(buf))
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.send(|conn| conn.writer().flush())
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unix_listener_is_private_and_cleaned_up() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir()
+            .join(format!("remap-test-{}", std::process::id()))
+            .join("s.sock");
+        let listener = Listener::bind_unix(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        let (mut server, peer) = listener.accept().unwrap();
+        assert_eq!(peer, format!("pid {}", std::process::id()));
+        client.write_all(b"hi").unwrap();
+        let mut buf = [0u8; 2];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hi");
+
+        // a live socket is not clobbered by a second server
+        assert!(Listener::bind_unix(&path).is_err());
+        drop(listener);
+        assert!(!path.exists());
+        let _ = std::fs::remove_dir(path.parent().unwrap());
+    }
+}
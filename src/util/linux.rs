@@ -4,7 +4,6 @@
 #![allow(unused_assignments)]
 
 use std::collections::VecDeque;
-use std::env;
 use std::path::Path;
 
 use anyhow::Result;
@@ -25,10 +24,9 @@ use x11rb::protocol::xproto::{
 };
 
 // ---------- Public API ----------
-pub fn force_move_resize(display: u32, xid: u32, x: i32, y: i32, w: u16, h: u16) -> anyhow::Result<()> {
+pub fn force_move_resize(display: u32, auth: Option<&Path>, xid: u32, x: i32, y: i32, w: u16, h: u16) -> anyhow::Result<()> {
     // Use a short-lived connection so we don't fight the util connection.
-    std::env::set_var("DISPLAY", format!(":{display}"));
-    let (conn, _screen_num) = x11rb::connect(None)?;
+    let (conn, _screen_num) = crate::xauth::connect(display, auth)?;
     // Move to 0,0 and resize to the requested screen size
     let aux = ConfigureWindowAux::new()
         .x(x)
@@ -41,8 +39,8 @@ pub fn force_move_resize(display: u32, xid: u32, x: i32, y: i32, w: u16, h: u16)
 }
 
 /// Return the best window id for `pid` on `:{display}` (0 if not found).
-pub fn get_window_id(pid: u32, _needle: &str, display: u32) -> i32 {
-    match find_window_bfs(pid, display) {
+pub fn get_window_id(pid: u32, _needle: &str, display: u32, auth: Option<&Path>) -> i32 {
+    match find_window_bfs(pid, display, auth) {
         Ok(Some(w)) => {
             debug!("get_window_id: selected window=0x{w:08x} for pid={pid}");
             w as i32
@@ -59,8 +57,8 @@ pub fn get_window_id(pid: u32, _needle: &str, display: u32) -> i32 {
 }
 
 /// Return the window geometry for `xid` on `:{display}` (0x0 on error).
-pub fn get_window_geometry(xid: i32, display: u32) -> Geometry {
-    match geometry_x11rb(xid as u32, display) {
+pub fn get_window_geometry(xid: i32, display: u32, auth: Option<&Path>) -> Geometry {
+    match geometry_x11rb(xid as u32, display, auth) {
         Ok(g) => g,
         Err(e) => {
             debug!("get_window_geometry error: {e:?}");
@@ -76,13 +74,10 @@ pub fn fix_path<P: AsRef<Path>>(p: P) -> String {
 
 // ---------- Internals ----------
 
-fn connect_display(display: u32) -> Result<(RustConnection, Window)> {
-    let d = format!(":{display}");
-    env::set_var("DISPLAY", &d);
-    debug!("connect_display: DISPLAY={d}");
-    let (conn, screen_num) = x11rb::connect(None)?;
+fn connect_display(display: u32, auth: Option<&Path>) -> Result<(RustConnection, Window)> {
+    let (conn, screen_num) = crate::xauth::connect(display, auth)?;
     let root = conn.setup().roots[screen_num].root;
-    debug!("connect_display: screen={screen_num}, root=0x{root:08x}");
+    debug!("connect_display: :{display}, screen={screen_num}, root=0x{root:08x}");
     Ok((conn, root))
 }
 
@@ -115,8 +110,8 @@ fn window_area(conn: &RustConnection, w: Window) -> Option<(u32, i32, i32)> {
 
 /// Breadth-first search; choose the largest mapped window that matches `target_pid`.
 /// Follows WM_CLIENT_LEADER → _NET_WM_PID for clients that don’t expose PID on the leaf.
-fn find_window_bfs(target_pid: u32, display: u32) -> Result<Option<Window>> {
-    let (conn, root) = connect_display(display)?;
+fn find_window_bfs(target_pid: u32, display: u32, auth: Option<&Path>) -> Result<Option<Window>> {
+    let (conn, root) = connect_display(display, auth)?;
 
     // Common atoms (created if missing)
     let net_wm_pid = intern(&conn, "_NET_WM_PID")?;
@@ -217,8 +212,8 @@ fn is_mapped_normal(conn: &RustConnection, w: Window, wm_state: Atom) -> Result<
     Ok(true)
 }
 
-fn geometry_x11rb(xid: u32, display: u32) -> Result<Geometry> {
-    let (conn, _root) = connect_display(display)?;
+fn geometry_x11rb(xid: u32, display: u32, auth: Option<&Path>) -> Result<Geometry> {
+    let (conn, _root) = connect_display(display, auth)?;
     let reply: GetGeometryReply = conn.get_geometry(xid)?.reply()?;
     Ok(Geometry {
         width: reply.width as i32,
@@ -234,14 +229,14 @@ fn get_prop_string(conn: &RustConnection, win: Window, prop: Atom) -> Result<Opt
     Ok(Some(String::from_utf8_lossy(&r.value).to_string()))
 }
 
-pub fn screen_size(display: u32) -> anyhow::Result<(u16, u16)> {
-    let (conn, root) = connect_display(display)?;
+pub fn screen_size(display: u32, auth: Option<&Path>) -> anyhow::Result<(u16, u16)> {
+    let (conn, root) = connect_display(display, auth)?;
     let geo = conn.get_geometry(root)?.reply()?;
     Ok((geo.width, geo.height))
 }
 
-pub fn resize_window_to(display: u32, xid: u32, w: u16, h: u16) -> anyhow::Result<()> {
-    let (conn, _root) = connect_display(display)?;
+pub fn resize_window_to(display: u32, auth: Option<&Path>, xid: u32, w: u16, h: u16) -> anyhow::Result<()> {
+    let (conn, _root) = connect_display(display, auth)?;
 
     // If your x11rb has builder methods:
     let aux = ConfigureWindowAux::new()
@@ -269,7 +264,7 @@ pub fn resize_window_to(display: u32, xid: u32, w: u16, h: u16) -> anyhow::Resul
     Ok(())
 }
 
-pub fn maximize_window(display: u32, xid: u32) -> anyhow::Result<()> {
-    let (sw, sh) = screen_size(display)?;
-    resize_window_to(display, xid, sw, sh)
+pub fn maximize_window(display: u32, auth: Option<&Path>, xid: u32) -> anyhow::Result<()> {
+    let (sw, sh) = screen_size(display, auth)?;
+    resize_window_to(display, auth, xid, sw, sh)
 }
@@ -39,6 +39,11 @@ pub fn port_is_listening(port: u16) -> bool {
     TcpListener::bind(("127.0.0.1", port)).is_err()
 }
 
+/// Ask the OS for a currently unused port on 127.0.0.1.
+pub fn free_local_port() -> std::io::Result<u16> {
+    Ok(TcpListener::bind(("127.0.0.1", 0))?.local_addr()?.port())
+}
+
 /// Convert a slice of bits (0/1) to a number (MSB-first).
 pub fn bits_to_number(bits: &[u8]) -> u8 {
     let mut result: u8 = 0;
@@ -60,17 +65,6 @@ pub fn fix_path<P: AsRef<Path>>(p: P) -> String {
     }
 }
 
-// pub fn is_display_server_running(display: u32) -> bool {
-//     let path = format!("/tmp/.X11-unix/X{display}");
-//     std::path::Path::new(&path).exists()
-// }
-pub fn is_display_server_running(display: u32) -> bool {
-    let cmd = format!("ps aux |grep Xvfb |grep \":{display}\" >/dev/null");
-    let r = Command::new("sh").arg("-c").arg(cmd).output()
-        .expect("Could not run ps command");
-    r.status.code().unwrap() == 0
-}
-
 pub fn vec_equal(va: &[u8], vb: &[u8]) -> bool {
     va.len() == vb.len() && va.iter().zip(vb).all(|(a, b)| *a == *b)
 }
@@ -93,7 +87,7 @@ pub fn get_rectangles(bytes: &[u8], swidth: u16, sheight: u16) -> Vec<Rec> {
             let mut index = 0;
             for j in 0..side {
                 let mut sindex =
-                    ((x as usize + ((y + j) as usize * swidth as usize)) * 4) as usize;
+                    (x as usize + ((y + j) as usize * swidth as usize)) * 4;
                 for _ in 0..side {
                     buffer[index] = bytes[sindex];
                     buffer[index + 1] = bytes[sindex + 1];
@@ -121,7 +115,7 @@ pub fn get_rectangles(bytes: &[u8], swidth: u16, sheight: u16) -> Vec<Rec> {
             let mut index = 0;
             for j in 0..side {
                 let mut sindex =
-                    ((pwidth as usize + ((y + j) as usize * swidth as usize)) * 4) as usize;
+                    (pwidth as usize + ((y + j) as usize * swidth as usize)) * 4;
                 for _ in 0..rwidth {
                     buffer[index] = bytes[sindex];
                     buffer[index + 1] = bytes[sindex + 1];
@@ -149,7 +143,7 @@ pub fn get_rectangles(bytes: &[u8], swidth: u16, sheight: u16) -> Vec<Rec> {
             let mut index = 0;
             for j in 0..rheight {
                 let mut sindex =
-                    ((x as usize + ((pheight + j) as usize * swidth as usize)) * 4) as usize;
+                    (x as usize + ((pheight + j) as usize * swidth as usize)) * 4;
                 for _ in 0..side {
                     buffer[index] = bytes[sindex];
                     buffer[index + 1] = bytes[sindex + 1];
@@ -176,7 +170,7 @@ pub fn get_rectangles(bytes: &[u8], swidth: u16, sheight: u16) -> Vec<Rec> {
         let mut index = 0;
         for j in 0..rheight {
             let mut sindex =
-                ((pwidth as usize + ((pheight + j) as usize * swidth as usize)) * 4) as usize;
+                (pwidth as usize + ((pheight + j) as usize * swidth as usize)) * 4;
             for _ in 0..rwidth {
                 buffer[index] = bytes[sindex];
                 buffer[index + 1] = bytes[sindex + 1];
@@ -0,0 +1,121 @@
+//! Xauthority files for the displays we start. Each display gets a random
+//! MIT-MAGIC-COOKIE-1 in a file only we can read; the X server is started
+//! with `-auth FILE` and refuses connections that don't present the cookie,
+//! so other users on the host can't watch the screen or type into it.
+//!
+//! Our own X connections present the cookie from the file they are given
+//! (see [`connect`]); XAUTHORITY is only set for the child processes we start.
+
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use x11rb::rust_connection::{DefaultStream, RustConnection};
+
+/// Matches any host, so the entry works however the display is named.
+const FAMILY_WILD: u16 = 0xffff;
+pub(crate) const MIT_MAGIC_COOKIE: &str = "MIT-MAGIC-COOKIE-1";
+
+/// Write a new Xauthority file at `path` for `display`, readable by us only,
+/// replacing any file left there.
+pub fn write(path: &Path, display: u32) -> Result<()> {
+    let mut random = [0u8; 16];
+    getrandom::getrandom(&mut random).context("no randomness for the X cookie")?;
+    // Hex, because libxcb's auth info (used by capture) takes a C string.
+    let cookie: String = random.iter().map(|b| format!("{b:02x}")).collect();
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))?;
+    }
+    let _ = std::fs::remove_file(path);
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("create {}", path.display()))?;
+    file.write_all(&entry(display, cookie.as_bytes()))?;
+    Ok(())
+}
+
+/// The MIT-MAGIC-COOKIE-1 for `display` in an Xauthority file we wrote.
+pub fn read(path: &Path, display: u32) -> Result<String> {
+    let data = std::fs::read(path).with_context(|| format!("read {}", path.display()))?;
+    let number = display.to_string();
+    let mut rest = &data[..];
+    while !rest.is_empty() {
+        let entry = (|| {
+            rest = rest.get(2..)?; // family
+            Some([field(&mut rest)?, field(&mut rest)?, field(&mut rest)?, field(&mut rest)?])
+        })();
+        let Some([_, num, name, cookie]) = entry else { bail!("{} is truncated", path.display()) };
+        if (num.is_empty() || num == number.as_bytes()) && name == MIT_MAGIC_COOKIE.as_bytes() {
+            return String::from_utf8(cookie.to_vec()).with_context(|| format!("cookie in {} is not text", path.display()));
+        }
+    }
+    bail!("{} has no cookie for :{display}", path.display())
+}
+
+/// The next length-prefixed field of an entry.
+fn field<'a>(rest: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = u16::from_be_bytes(rest.get(..2)?.try_into().ok()?) as usize;
+    let value = rest.get(2..2 + len)?;
+    *rest = &rest[2 + len..];
+    Some(value)
+}
+
+/// Connect to local display `display`, presenting the cookie from `auth`.
+/// Without one, x11rb looks for credentials the usual way.
+pub fn connect(display: u32, auth: Option<&Path>) -> Result<(RustConnection, usize)> {
+    let Some(auth) = auth else {
+        return x11rb::connect(Some(&format!(":{display}"))).with_context(|| format!("connect to :{display}"));
+    };
+    let cookie = read(auth, display)?;
+    let socket = std::os::unix::net::UnixStream::connect(format!("/tmp/.X11-unix/X{display}"))
+        .with_context(|| format!("connect to :{display}"))?;
+    let (stream, _) = DefaultStream::from_unix_stream(socket)?;
+    let conn = RustConnection::connect_to_stream_with_auth_info(stream, 0, MIT_MAGIC_COOKIE.into(), cookie.into_bytes())
+        .with_context(|| format!("connect to :{display}"))?;
+    Ok((conn, 0))
+}
+
+/// One Xauthority entry: family, then address, display number, auth name
+/// and data, each with a big-endian length.
+fn entry(display: u32, cookie: &[u8]) -> Vec<u8> {
+    let mut out = FAMILY_WILD.to_be_bytes().to_vec();
+    let number = display.to_string();
+    for field in [&[][..], number.as_bytes(), MIT_MAGIC_COOKIE.as_bytes(), cookie] {
+        out.extend_from_slice(&(field.len() as u16).to_be_bytes());
+        out.extend_from_slice(field);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries() {
+        let e = entry(101, &[7; 16]);
+        assert_eq!(&e[..8], &[0xff, 0xff, 0, 0, 0, 3, b'1', b'0']);
+        assert_eq!(&e[9..11], &[0, 18]);
+        assert_eq!(&e[11..29], MIT_MAGIC_COOKIE.as_bytes());
+        assert_eq!(&e[29..31], &[0, 16]);
+        assert_eq!(e.len(), 31 + 16);
+    }
+
+    #[test]
+    fn cookies_read_back() {
+        let dir = std::env::temp_dir().join(format!("remap-xauth-{}", std::process::id()));
+        let path = dir.join("xauth-101");
+        write(&path, 101).unwrap();
+        let cookie = read(&path, 101).unwrap();
+        assert_eq!(cookie.len(), 32);
+        assert!(cookie.bytes().all(|b| b.is_ascii_hexdigit()));
+        assert_eq!(std::fs::read(&path).unwrap(), entry(101, cookie.as_bytes()));
+        assert!(read(&path, 102).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,88 @@
+//! Local ends of libssh2 channels. The session runs in non-blocking mode so
+//! several channels (forwards, remote commands) can share it; the driver
+//! thread does their I/O and these halves talk to it over tokio channels.
+
+use std::sync::Arc;
+
+use polling::Poller;
+use ssh2::ErrorCode;
+use tokio::sync::mpsc;
+
+/// libssh2's LIBSSH2_ERROR_EAGAIN
+const EAGAIN: i32 = -37;
+
+pub(crate) fn would_block(e: &ssh2::Error) -> bool {
+    e.code() == ErrorCode::Session(EAGAIN)
+}
+
+/// Wakes the driver thread out of its poll.
+#[derive(Clone)]
+pub(crate) struct Wake(pub(crate) Arc<Poller>);
+
+impl Wake {
+    pub(crate) fn wake(&self) {
+        let _ = self.0.notify();
+    }
+}
+
+/// Sending half: bytes for the remote side. Dropping it sends EOF.
+pub(crate) struct ChannelWriter {
+    tx: Option<mpsc::Sender<Vec<u8>>>,
+    wake: Wake,
+}
+
+impl ChannelWriter {
+    pub(crate) fn new(tx: mpsc::Sender<Vec<u8>>, wake: Wake) -> Self {
+        Self { tx: Some(tx), wake }
+    }
+
+    /// Queue `data`; waits while the channel is backed up. False once the
+    /// channel is gone.
+    pub(crate) async fn send(&self, data: Vec<u8>) -> bool {
+        let Some(tx) = &self.tx else { return false };
+        let ok = tx.send(data).await.is_ok();
+        self.wake.wake();
+        ok
+    }
+
+    /// Resolves once the driver has dropped the channel.
+    pub(crate) async fn closed(&self) {
+        if let Some(tx) = &self.tx {
+            tx.closed().await;
+        }
+    }
+}
+
+impl Drop for ChannelWriter {
+    fn drop(&mut self) {
+        // Close first so the driver sees the disconnect when it wakes.
+        self.tx = None;
+        self.wake.wake();
+    }
+}
+
+/// Receiving half: bytes from the remote side, None after its EOF.
+pub(crate) struct ChannelReader {
+    rx: Option<mpsc::Receiver<Vec<u8>>>,
+    wake: Wake,
+}
+
+impl ChannelReader {
+    pub(crate) fn new(rx: mpsc::Receiver<Vec<u8>>, wake: Wake) -> Self {
+        Self { rx: Some(rx), wake }
+    }
+
+    pub(crate) async fn recv(&mut self) -> Option<Vec<u8>> {
+        let data = self.rx.as_mut()?.recv().await;
+        // There is room again; the driver may have stopped reading for us.
+        self.wake.wake();
+        data
+    }
+}
+
+impl Drop for ChannelReader {
+    fn drop(&mut self) {
+        self.rx = None;
+        self.wake.wake();
+    }
+}
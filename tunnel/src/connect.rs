@@ -0,0 +1,466 @@
+//! Opening and authenticating the SSH session.
+
+use std::io::{BufRead, IsTerminal, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ssh2::{ErrorCode, KeyboardInteractivePrompt, Prompt, Session};
+
+use crate::error::{Error, Result};
+use crate::known_hosts::{fingerprint, host_name, key_label, Check, HostKeyPolicy, KnownHosts};
+use crate::ssh_config::SshConfig;
+
+/// How to authenticate.
+#[derive(Clone)]
+pub enum Auth {
+    /// Keys held by ssh-agent
+    Agent,
+    /// A private key file (OpenSSH or PEM format). Without a passphrase an
+    /// encrypted key's passphrase is asked for on the terminal.
+    KeyFile { path: PathBuf, passphrase: Option<String> },
+    /// A password, for password or keyboard-interactive login
+    Password(String),
+    /// What ssh does: ssh-agent, then the identity files, then
+    /// keyboard-interactive or password, asking on the terminal
+    Auto,
+}
+
+impl std::fmt::Debug for Auth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Auth::Agent => f.write_str("Agent"),
+            Auth::KeyFile { path, .. } => f.debug_struct("KeyFile").field("path", path).finish_non_exhaustive(),
+            Auth::Password(_) => f.write_str("Password(..)"),
+            Auth::Auto => f.write_str("Auto"),
+        }
+    }
+}
+
+/// Where and as whom to connect.
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    pub auth: Auth,
+    /// Keys tried by [`Auth::Auto`], in order [default: ~/.ssh/id_ed25519, id_ecdsa, id_rsa]
+    pub identity_files: Vec<PathBuf>,
+    pub timeout: Duration,
+    /// Known hosts file [default: ~/.ssh/known_hosts]
+    pub known_hosts: Option<PathBuf>,
+    /// What to do when the host is not in known_hosts
+    pub host_key_policy: HostKeyPolicy,
+    /// Seconds between keepalives, and between checks that the server still
+    /// answers; 0 turns both off
+    pub keepalive_interval: u32,
+    /// Checks in a row that may go unanswered before the session is taken
+    /// to be dead
+    pub keepalive_count_max: u32,
+    /// Log in again when the session dies, and keep the forwards going
+    pub reconnect: bool,
+    /// Reach the host through a session to this one (ProxyJump)
+    pub proxy_jump: Option<Box<ConnectOptions>>,
+}
+
+impl ConnectOptions {
+    pub fn new(host: &str, user: &str) -> Self {
+        Self {
+            host: host.to_string(),
+            port: 22,
+            user: user.to_string(),
+            auth: Auth::Auto,
+            identity_files: Vec::new(),
+            timeout: Duration::from_secs(15),
+            known_hosts: None,
+            host_key_policy: HostKeyPolicy::default(),
+            keepalive_interval: 30,
+            keepalive_count_max: 3,
+            reconnect: true,
+            proxy_jump: None,
+        }
+    }
+
+    /// Options for `host` the way `ssh host` would connect: `user` and `port`
+    /// from the command line, then ~/.ssh/config, then the defaults.
+    pub fn from_ssh_config(host: &str, user: Option<&str>, port: Option<u16>) -> Result<Self> {
+        SshConfig::load_default().options(host, user, port)
+    }
+}
+
+/// Default key files tried by [`Auth::Auto`], most preferred first.
+const DEFAULT_KEYS: &[&str] = &["id_ed25519", "id_ecdsa", "id_rsa"];
+
+/// An authenticated session.
+pub(crate) struct Login {
+    /// In non-blocking mode
+    pub(crate) session: Session,
+    /// A clone of the session's socket, to wait on
+    pub(crate) socket: TcpStream,
+    /// How we got in, e.g. "publickey (~/.ssh/id_ed25519)"
+    pub(crate) method: String,
+}
+
+/// Connect, check the host key against known_hosts and authenticate. Blocking.
+/// `transport` is a socket already connected to the server, e.g. through a
+/// jump host; without it we connect directly.
+pub(crate) fn open_session(opts: &ConnectOptions, transport: Option<TcpStream>) -> Result<Login> {
+    let addr = format!("{}:{}", opts.host, opts.port);
+    let tcp = match transport {
+        Some(tcp) => tcp,
+        None => {
+            log::info!("Connecting to SSH server at {addr}");
+            connect_tcp(&addr, opts.timeout).map_err(|error| Error::Connect { addr: addr.clone(), error })?
+        }
+    };
+
+    let socket = tcp.try_clone()?;
+    let mut sess = Session::new()?;
+    sess.set_tcp_stream(tcp);
+    sess.set_timeout(opts.timeout.as_millis() as u32);
+    sess.handshake().map_err(|error| Error::Handshake { host: opts.host.clone(), error })?;
+    check_host_key(&sess, opts)?;
+
+    let method = authenticate(&sess, opts)?;
+    log::info!("Authenticated as {} with {method}", opts.user);
+
+    sess.set_timeout(0);
+    sess.set_keepalive(true, opts.keepalive_interval);
+    sess.set_blocking(false);
+    Ok(Login { session: sess, socket, method })
+}
+
+fn connect_tcp(addr: &str, timeout: Duration) -> std::io::Result<TcpStream> {
+    let mut last = None;
+    for a in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&a, timeout) {
+            Ok(s) => {
+                s.set_nodelay(true)?;
+                return Ok(s);
+            }
+            Err(e) => last = Some(e),
+        }
+    }
+    Err(last.unwrap_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no addresses")))
+}
+
+fn check_host_key(sess: &Session, opts: &ConnectOptions) -> Result<()> {
+    let host = opts.host.as_str();
+    let key = sess.host_key().map(|(k, _)| k.to_vec()).ok_or_else(|| Error::HostKey {
+        host: host.to_string(),
+        reason: "was not sent by the server".to_string(),
+    })?;
+    let path = opts
+        .known_hosts
+        .clone()
+        .or_else(|| dirs::home_dir().map(|h| h.join(".ssh/known_hosts")))
+        .ok_or_else(|| Error::HostKey { host: host.to_string(), reason: "cannot be checked: no home directory".to_string() })?;
+    let known = KnownHosts::load(&path)?;
+    let fp = fingerprint(&key);
+
+    match known.check(host, opts.port, &key) {
+        Check::Match => return Ok(()),
+        Check::Mismatch { line } => {
+            return Err(Error::HostKeyChanged { host: host.to_string(), fingerprint: fp, file: path, line });
+        }
+        Check::Revoked { line } => {
+            let reason = format!("is marked as revoked in {}:{line}", path.display());
+            return Err(Error::HostKey { host: host.to_string(), reason });
+        }
+        Check::NotFound => {}
+    }
+
+    let name = host_name(host, opts.port);
+    let accepted = match opts.host_key_policy {
+        HostKeyPolicy::Strict => false,
+        HostKeyPolicy::AcceptNew => true,
+        // Without a terminal there is nobody to ask.
+        HostKeyPolicy::Ask if !std::io::stdin().is_terminal() => false,
+        HostKeyPolicy::Ask => {
+            ask(&format!(
+                "The authenticity of host '{name}' can't be established.\n{} key fingerprint is {fp}.\n\
+                 Are you sure you want to continue connecting (yes/no)? ",
+                key_label(&key)
+            ))?
+        }
+    };
+    if !accepted {
+        return Err(Error::UnknownHost { host: name, fingerprint: fp });
+    }
+    known.add(host, opts.port, &key)?;
+    eprintln!(
+        "Warning: Permanently added '{name}' ({} {fp}) to the list of known hosts ({}).",
+        key_label(&key),
+        known.path().display()
+    );
+    Ok(())
+}
+
+/// Ask a yes/no question on the terminal.
+fn ask(question: &str) -> Result<bool> {
+    let mut stderr = std::io::stderr();
+    stderr.write_all(question.as_bytes())?;
+    stderr.flush()?;
+    let mut answer = String::new();
+    loop {
+        answer.clear();
+        if std::io::stdin().lock().read_line(&mut answer)? == 0 {
+            return Ok(false);
+        }
+        match answer.trim() {
+            "yes" => return Ok(true),
+            "no" => return Ok(false),
+            _ => {
+                stderr.write_all(b"Please type 'yes' or 'no': ")?;
+                stderr.flush()?;
+            }
+        }
+    }
+}
+
+/// Try the methods `opts.auth` allows, in ssh's order, and say which one worked.
+fn authenticate(sess: &Session, opts: &ConnectOptions) -> Result<String> {
+    let user = opts.user.as_str();
+    let fail = |reason: String| Error::Auth { user: user.to_string(), reason };
+    // Asking for the methods is also how a server that needs no credentials lets us in.
+    let offered = match sess.auth_methods(user) {
+        Ok(methods) => methods.split(',').map(str::to_string).collect::<Vec<_>>(),
+        Err(_) if sess.authenticated() => return Ok("none".to_string()),
+        Err(e) => return Err(fail(e.to_string())),
+    };
+    let offers = |method: &str| offered.iter().any(|m| m == method);
+    let interactive = std::io::stdin().is_terminal();
+    let mut tried = Vec::new();
+
+    let method = match &opts.auth {
+        Auth::Agent => try_agent(sess, user, &mut tried),
+        Auth::KeyFile { path, passphrase } => {
+            if !path.exists() {
+                return Err(Error::KeyNotFound(path.clone()));
+            }
+            try_key(sess, user, path, passphrase.as_deref(), interactive, &mut tried)
+        }
+        Auth::Password(password) => try_password(sess, opts, &offers, Some(password), &mut tried),
+        Auth::Auto => 'auto: {
+            if offers("publickey") {
+                if let Some(m) = try_agent(sess, user, &mut tried) {
+                    break 'auto Some(m);
+                }
+                let ssh_dir = dirs::home_dir().map(|h| h.join(".ssh")).unwrap_or_default();
+                let keys = match opts.identity_files.is_empty() {
+                    true => DEFAULT_KEYS.iter().map(|k| ssh_dir.join(k)).filter(|p| p.exists()).collect(),
+                    false => opts.identity_files.clone(),
+                };
+                for path in &keys {
+                    if !path.exists() {
+                        tried.push(format!("{}: no such file", path.display()));
+                    } else if let Some(m) = try_key(sess, user, path, None, interactive, &mut tried) {
+                        break 'auto Some(m);
+                    }
+                }
+            }
+            if interactive {
+                try_password(sess, opts, &offers, None, &mut tried)
+            } else {
+                None
+            }
+        }
+    };
+
+    match method {
+        Some(m) if sess.authenticated() => Ok(m),
+        Some(m) => Err(fail(format!("{m} was accepted but the server wants more (multi-factor login is not supported)"))),
+        None => {
+            if tried.is_empty() {
+                tried.push(format!("nothing to try; the server accepts {}", offered.join(", ")));
+            }
+            Err(fail(tried.join("; ")))
+        }
+    }
+}
+
+fn try_agent(sess: &Session, user: &str, tried: &mut Vec<String>) -> Option<String> {
+    let mut agent = sess.agent().ok()?;
+    if agent.connect().is_err() {
+        tried.push("ssh-agent: not running".to_string());
+        return None;
+    }
+    let identities = agent.list_identities().and_then(|_| agent.identities()).unwrap_or_default();
+    for id in &identities {
+        if agent.userauth(user, id).is_ok() {
+            let _ = agent.disconnect();
+            return Some(format!("publickey (ssh-agent: {})", id.comment()));
+        }
+    }
+    let _ = agent.disconnect();
+    tried.push(format!("ssh-agent: none of its {} keys was accepted", identities.len()));
+    None
+}
+
+/// libssh2's LIBSSH2_ERROR_FILE: the key file could not be read, which for an
+/// encrypted key means a wrong passphrase.
+const ERROR_FILE: i32 = -16;
+
+fn try_key(
+    sess: &Session,
+    user: &str,
+    path: &Path,
+    passphrase: Option<&str>,
+    interactive: bool,
+    tried: &mut Vec<String>,
+) -> Option<String> {
+    let public = PathBuf::from(format!("{}.pub", path.display()));
+    let public = public.exists().then_some(public);
+    let login = |passphrase: Option<&str>| sess.userauth_pubkey_file(user, public.as_deref(), path, passphrase);
+    let method = format!("publickey ({})", path.display());
+
+    if passphrase.is_some() || !key_is_encrypted(path) {
+        return match login(passphrase) {
+            Ok(()) => Some(method),
+            Err(e) => {
+                tried.push(format!("{}: {e}", path.display()));
+                None
+            }
+        };
+    }
+    if !interactive {
+        tried.push(format!("{}: encrypted, and no terminal to ask for its passphrase", path.display()));
+        return None;
+    }
+    for _ in 0..3 {
+        let prompt = format!("Enter passphrase for key '{}': ", path.display());
+        let Ok(passphrase) = rpassword::prompt_password(prompt) else { break };
+        // As with ssh, an empty passphrase skips the key.
+        if passphrase.is_empty() {
+            break;
+        }
+        match login(Some(&passphrase)) {
+            Ok(()) => return Some(method),
+            Err(e) if e.code() == ErrorCode::Session(ERROR_FILE) => eprintln!("Wrong passphrase."),
+            Err(e) => {
+                tried.push(format!("{}: {e}", path.display()));
+                return None;
+            }
+        }
+    }
+    tried.push(format!("{}: no passphrase", path.display()));
+    None
+}
+
+/// Whether a private key file needs a passphrase: PEM keys say so in a
+/// header, OpenSSH keys name their cipher right after the magic.
+fn key_is_encrypted(path: &Path) -> bool {
+    let Ok(text) = std::fs::read_to_string(path) else { return false };
+    if text.contains("ENCRYPTED") {
+        return true;
+    }
+    let body: String = text.lines().filter(|l| !l.starts_with("-----")).collect();
+    let Ok(blob) = STANDARD.decode(body.trim()) else { return false };
+    let Some(rest) = blob.strip_prefix(b"openssh-key-v1\0") else { return false };
+    let Some(len) = rest.get(..4).map(|b| u32::from_be_bytes(b.try_into().unwrap()) as usize) else { return false };
+    rest.get(4..4 + len).is_some_and(|cipher| cipher != b"none")
+}
+
+/// Keyboard-interactive if the server offers it, else password. With no
+/// password given, ask on the terminal (up to three times, like ssh).
+fn try_password(
+    sess: &Session,
+    opts: &ConnectOptions,
+    offers: &dyn Fn(&str) -> bool,
+    password: Option<&str>,
+    tried: &mut Vec<String>,
+) -> Option<String> {
+    let user = opts.user.as_str();
+    if offers("keyboard-interactive") {
+        let attempts = if password.is_some() { 1 } else { 3 };
+        for _ in 0..attempts {
+            let mut prompter = Prompter { password };
+            match sess.userauth_keyboard_interactive(user, &mut prompter) {
+                Ok(()) => return Some("keyboard-interactive".to_string()),
+                Err(e) => tried.push(format!("keyboard-interactive: {e}")),
+            }
+        }
+        tried.dedup();
+        return None;
+    }
+    if offers("password") {
+        for _ in 0..3 {
+            let answer = match password {
+                Some(p) => p.to_string(),
+                None => rpassword::prompt_password(format!("{user}@{}'s password: ", opts.host)).ok()?,
+            };
+            match sess.userauth_password(user, &answer) {
+                Ok(()) => return Some("password".to_string()),
+                Err(e) => tried.push(format!("password: {e}")),
+            }
+            if password.is_some() {
+                break;
+            }
+        }
+        tried.dedup();
+        return None;
+    }
+    tried.push("password: not accepted by the server".to_string());
+    None
+}
+
+/// Answers keyboard-interactive prompts: hidden ones with the password if we
+/// have one, everything else on the terminal.
+struct Prompter<'a> {
+    password: Option<&'a str>,
+}
+
+impl KeyboardInteractivePrompt for Prompter<'_> {
+    fn prompt<'b>(&mut self, _username: &str, instructions: &str, prompts: &[Prompt<'b>]) -> Vec<String> {
+        if !instructions.is_empty() && self.password.is_none() {
+            eprintln!("{instructions}");
+        }
+        prompts
+            .iter()
+            .map(|p| match (p.echo, self.password) {
+                (false, Some(password)) => password.to_string(),
+                (false, None) => rpassword::prompt_password(p.text.as_ref()).unwrap_or_default(),
+                (true, _) => {
+                    eprint!("{}", p.text);
+                    let _ = std::io::stderr().flush();
+                    let mut line = String::new();
+                    let _ = std::io::stdin().lock().read_line(&mut line);
+                    line.trim_end().to_string()
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn detects_encrypted_keys() {
+        let dir = std::env::temp_dir().join(format!("connect-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, passphrase, format) in [
+            ("plain", "", "RFC4716"),
+            ("secret", "hunter2", "RFC4716"),
+            ("plain_pem", "", "PEM"),
+            ("secret_pem", "hunter2", "PEM"),
+        ] {
+            let path = dir.join(name);
+            let _ = std::fs::remove_file(&path);
+            let generated = Command::new("ssh-keygen")
+                .args(["-q", "-t", "ecdsa", "-N", passphrase, "-m", format, "-f"])
+                .arg(&path)
+                .status();
+            if !generated.is_ok_and(|s| s.success()) {
+                eprintln!("ssh-keygen not available; skipping");
+                return;
+            }
+            assert_eq!(key_is_encrypted(&path), !passphrase.is_empty(), "{name}");
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
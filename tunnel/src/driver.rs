@@ -0,0 +1,508 @@
+//! The I/O driver: one thread per session owns all libssh2 calls on it. It
+//! opens channels one at a time, in the order they were asked for, accepts
+//! connections on remote (tcpip-forward) listeners and moves the data of
+//! every open channel. It sleeps until the session socket is ready in the
+//! direction libssh2 is waiting on, or until a local end has sent data, made
+//! room, or gone away, and batches whatever is queued into as few channel
+//! writes as the remote window allows.
+
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::{mpsc as std_mpsc, Arc};
+use std::time::{Duration, Instant};
+
+use polling::{Event, Events, Poller};
+use ssh2::{BlockDirections, Channel, Listener, Session};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::channel::{would_block, ChannelReader, ChannelWriter, Wake};
+use crate::error::{Error, Result};
+use crate::tunnel::Target;
+
+/// Local bytes coalesced into one channel write.
+const BATCH: usize = 256 * 1024;
+/// Largest single read from a channel.
+const READ_CHUNK: usize = 64 * 1024;
+/// Chunks queued in each direction before the other side has to wait.
+const QUEUE: usize = 32;
+
+const SOCKET: usize = 0;
+
+/// A channel to open.
+pub(crate) enum Open {
+    /// A forwarded connection to `Target`
+    Forward(Target),
+    /// A remote command
+    Exec(String),
+    /// A bare session channel, only to see that the server answers; its ends
+    /// are dropped, which closes it
+    Ping,
+}
+
+type Opened = Result<(ChannelWriter, ChannelReader)>;
+
+/// Local ends of the channels a remote listener accepts.
+pub(crate) type Accepted = mpsc::UnboundedReceiver<(ChannelWriter, ChannelReader)>;
+
+/// A remote port to listen on.
+struct Listen {
+    id: u64,
+    host: String,
+    port: u16,
+    reply: oneshot::Sender<Result<(u16, Accepted)>>,
+}
+
+enum Command {
+    Open(Open, oneshot::Sender<Opened>),
+    Listen(Listen),
+    Unlisten(u64),
+    Disconnect(oneshot::Sender<()>),
+}
+
+/// A remote listener and where its connections go.
+struct Listening {
+    id: u64,
+    listener: Listener,
+    accepted: mpsc::UnboundedSender<(ChannelWriter, ChannelReader)>,
+}
+
+/// An open in progress. libssh2 keeps the state of one non-blocking open per
+/// session, so they are attempted strictly one after the other.
+struct Opening {
+    what: Open,
+    /// Exec: the session channel, once open, waiting for the command to start
+    channel: Option<Channel>,
+    reply: oneshot::Sender<Opened>,
+}
+
+/// Handle to a session's driver thread. The thread keeps running after the
+/// handle is dropped until its last channel is done.
+pub(crate) struct Driver {
+    cmds: Option<std_mpsc::Sender<Command>>,
+    wake: Wake,
+}
+
+impl Driver {
+    /// Start driving `session`, whose transport is `socket` (a clone of the
+    /// stream the session was given).
+    pub(crate) fn start(session: Session, socket: TcpStream) -> std::io::Result<Self> {
+        let poller = Arc::new(Poller::new()?);
+        // SAFETY: the socket is owned by the driver thread and deleted from the poller before it is dropped
+        unsafe { poller.add(&socket, Event::none(SOCKET))? };
+        let (cmds, cmd_rx) = std_mpsc::channel();
+        let wake = Wake(Arc::clone(&poller));
+
+        let state = State {
+            session,
+            socket,
+            wake: wake.clone(),
+            poller,
+            cmds: cmd_rx,
+            opening: VecDeque::new(),
+            listens: VecDeque::new(),
+            listeners: Vec::new(),
+            chans: Vec::new(),
+            closing: Vec::new(),
+        };
+        std::thread::Builder::new()
+            .name("ssh-driver".to_string())
+            .spawn(move || state.run())?;
+        Ok(Self { cmds: Some(cmds), wake })
+    }
+
+    /// Open a channel and get its local ends. Any number of these may be in
+    /// flight; the driver queues them.
+    pub(crate) async fn open(&self, what: Open) -> Opened {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        if let Some(cmds) = &self.cmds {
+            let _ = cmds.send(Command::Open(what, reply_tx));
+        }
+        self.wake.wake();
+        reply_rx.await.unwrap_or(Err(Error::Closed))
+    }
+
+    /// Ask the server to listen on `host:port` (port 0 lets it pick) and
+    /// forward what connects there to us. Resolves to the bound port and the
+    /// accepted connections; `id` names the listener for [`Driver::unlisten`].
+    pub(crate) async fn listen(&self, id: u64, host: &str, port: u16) -> Result<(u16, Accepted)> {
+        let (reply, reply_rx) = oneshot::channel();
+        if let Some(cmds) = &self.cmds {
+            let _ = cmds.send(Command::Listen(Listen { id, host: host.to_string(), port, reply }));
+        }
+        self.wake.wake();
+        reply_rx.await.unwrap_or(Err(Error::Closed))
+    }
+
+    /// Stop a remote listener. Connections it already accepted carry on.
+    pub(crate) fn unlisten(&self, id: u64) {
+        if let Some(cmds) = &self.cmds {
+            let _ = cmds.send(Command::Unlisten(id));
+        }
+        self.wake.wake();
+    }
+
+    /// Close every channel and end the session. Resolves once the server has
+    /// been told, or the driver is already gone.
+    pub(crate) fn disconnect(&self) -> oneshot::Receiver<()> {
+        let (done_tx, done_rx) = oneshot::channel();
+        if let Some(cmds) = &self.cmds {
+            let _ = cmds.send(Command::Disconnect(done_tx));
+        }
+        self.wake.wake();
+        done_rx
+    }
+}
+
+impl Drop for Driver {
+    fn drop(&mut self) {
+        self.cmds = None;
+        self.wake.wake();
+    }
+}
+
+struct State {
+    session: Session,
+    socket: TcpStream,
+    poller: Arc<Poller>,
+    wake: Wake,
+    cmds: std_mpsc::Receiver<Command>,
+    opening: VecDeque<Opening>,
+    listens: VecDeque<Listen>,
+    listeners: Vec<Listening>,
+    chans: Vec<Chan>,
+    /// Finished channels whose close has not gone through yet
+    closing: Vec<Channel>,
+}
+
+impl State {
+    fn run(mut self) {
+        let mut buf = vec![0u8; READ_CHUNK];
+        let mut events = Events::new();
+        let mut orphaned = false;
+
+        loop {
+            loop {
+                match self.cmds.try_recv() {
+                    Ok(Command::Open(what, reply)) => self.opening.push_back(Opening { what, channel: None, reply }),
+                    Ok(Command::Listen(listen)) => self.listens.push_back(listen),
+                    Ok(Command::Unlisten(id)) => self.unlisten(id),
+                    Ok(Command::Disconnect(done)) => {
+                        self.disconnect();
+                        let _ = done.send(());
+                        return;
+                    }
+                    Err(std_mpsc::TryRecvError::Empty) => break,
+                    Err(std_mpsc::TryRecvError::Disconnected) => {
+                        orphaned = true;
+                        break;
+                    }
+                }
+            }
+
+            let mut progress = false;
+            while let Some(op) = self.opening.front_mut() {
+                let Some(result) = try_open(&self.session, op) else { break };
+                let op = self.opening.pop_front().expect("front exists");
+                let _ = op.reply.send(result.map(|channel| self.attach(channel)));
+                progress = true;
+            }
+            while let Some(listen) = self.listens.front() {
+                let result = match self.session.channel_forward_listen(listen.port, Some(&listen.host), None) {
+                    Err(e) if would_block(&e) => break,
+                    r => r,
+                };
+                let listen = self.listens.pop_front().expect("front exists");
+                let _ = listen.reply.send(match result {
+                    Ok((listener, port)) => {
+                        let (accepted, accepted_rx) = mpsc::unbounded_channel();
+                        self.listeners.push(Listening { id: listen.id, listener, accepted });
+                        Ok((port, accepted_rx))
+                    }
+                    Err(error) => Err(Error::Listen { addr: format!("{}:{}", listen.host, listen.port), error }),
+                });
+                progress = true;
+            }
+            progress |= self.accept();
+            for chan in &mut self.chans {
+                progress |= chan.step(&mut buf);
+            }
+            let mut i = 0;
+            while i < self.chans.len() {
+                if self.chans[i].finished() {
+                    self.closing.push(self.chans.swap_remove(i).channel);
+                } else {
+                    i += 1;
+                }
+            }
+            self.closing.retain_mut(|c| matches!(c.close(), Err(e) if would_block(&e)));
+
+            if orphaned && self.opening.is_empty() && self.chans.is_empty() && self.closing.is_empty() {
+                self.listeners.clear();
+                break;
+            }
+            if progress {
+                continue;
+            }
+
+            // Nothing moved: sleep until the socket or a local end has news.
+            let keepalive = match self.session.keepalive_send() {
+                Ok(secs) => secs.max(1),
+                Err(_) => 1,
+            };
+            // A readable socket is only worth waking for if some channel will read
+            // (a write stuck on the remote window waits for an adjust, which is
+            // read too); otherwise we would spin on bytes nobody reads yet.
+            let readable = self.chans.iter().any(|c| c.can_receive() || !c.outbox.is_empty())
+                || !self.opening.is_empty()
+                || !self.listens.is_empty()
+                || !self.listeners.is_empty()
+                || !self.closing.is_empty();
+            let writable = matches!(self.session.block_directions(), BlockDirections::Outbound | BlockDirections::Both);
+            let interest = match (readable, writable) {
+                (true, true) => Event::all(SOCKET),
+                (true, false) => Event::readable(SOCKET),
+                (false, true) => Event::writable(SOCKET),
+                (false, false) => Event::none(SOCKET),
+            };
+            if let Err(e) = self.poller.modify(&self.socket, interest) {
+                log::warn!("ssh driver: {e}");
+                break;
+            }
+            events.clear();
+            if let Err(e) = self.poller.wait(&mut events, Some(Duration::from_secs(keepalive as u64)))
+                && e.kind() != ErrorKind::Interrupted
+            {
+                log::warn!("ssh driver: {e}");
+                break;
+            }
+        }
+        let _ = self.poller.delete(&self.socket);
+    }
+
+    /// Start moving data for a freshly opened channel.
+    fn attach(&mut self, channel: Channel) -> (ChannelWriter, ChannelReader) {
+        let (in_tx, in_rx) = mpsc::channel(QUEUE);
+        let (out_tx, out_rx) = mpsc::channel(QUEUE);
+        self.chans.push(Chan::new(channel, in_rx, out_tx));
+        (ChannelWriter::new(in_tx, self.wake.clone()), ChannelReader::new(out_rx, self.wake.clone()))
+    }
+
+    /// Take the connections waiting on remote listeners.
+    fn accept(&mut self) -> bool {
+        let mut progress = false;
+        let mut i = 0;
+        while i < self.listeners.len() {
+            match self.listeners[i].listener.accept() {
+                Ok(channel) => {
+                    let ends = self.attach(channel);
+                    // Nobody takes them once the forward is removed; the ends
+                    // are dropped and the channel closes.
+                    let _ = self.listeners[i].accepted.send(ends);
+                    progress = true;
+                }
+                Err(e) if would_block(&e) => i += 1,
+                Err(e) => {
+                    log::warn!("remote listener stopped: {e}");
+                    self.listeners.swap_remove(i);
+                }
+            }
+        }
+        progress
+    }
+
+    fn unlisten(&mut self, id: u64) {
+        if let Some(i) = self.listeners.iter().position(|l| l.id == id) {
+            // Dropping a listener cancels the forward on the server; in
+            // non-blocking mode that request could be cut short.
+            self.session.set_blocking(true);
+            drop(self.listeners.swap_remove(i));
+            self.session.set_blocking(false);
+        }
+    }
+
+    fn disconnect(&mut self) {
+        self.listeners.clear();
+        for mut chan in self.chans.drain(..) {
+            let _ = chan.channel.close();
+        }
+        // A dead connection never takes the message; don't wait for it forever.
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while let Err(e) = self.session.disconnect(None, "Tunnel shutdown", None)
+            && would_block(&e)
+            && Instant::now() < deadline
+        {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let _ = self.poller.delete(&self.socket);
+    }
+}
+
+/// One step of an open; None while libssh2 would block.
+fn try_open(session: &Session, op: &mut Opening) -> Option<Result<Channel>> {
+    let attempt = match &op.what {
+        Open::Forward(Target::Tcp { host, port }) => session.channel_direct_tcpip(host, *port, None),
+        Open::Forward(Target::Unix(path)) => session.channel_direct_streamlocal(&path.to_string_lossy(), None),
+        Open::Exec(command) => start_command(session, &mut op.channel, command),
+        Open::Ping => session.channel_session(),
+    };
+    match attempt {
+        Ok(channel) => Some(Ok(channel)),
+        Err(e) if would_block(&e) => None,
+        Err(error) => Some(Err(match &op.what {
+            Open::Forward(target) => Error::Channel { target: target.to_string(), error },
+            Open::Exec(command) => Error::Exec { command: command.clone(), error },
+            Open::Ping => Error::Channel { target: "session".to_string(), error },
+        })),
+    }
+}
+
+/// Open a session channel and run `command` on it. The channel is kept in
+/// `pending` while the exec request would block.
+fn start_command(session: &Session, pending: &mut Option<Channel>, command: &str) -> std::result::Result<Channel, ssh2::Error> {
+    let mut channel = match pending.take() {
+        Some(channel) => channel,
+        None => session.channel_session()?,
+    };
+    match channel.exec(command) {
+        Ok(()) => Ok(channel),
+        Err(e) => {
+            if would_block(&e) {
+                *pending = Some(channel);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// One channel's state on the driver side.
+struct Chan {
+    channel: Channel,
+    input: mpsc::Receiver<Vec<u8>>,
+    /// None once the remote side is done or nobody reads any more
+    output: Option<mpsc::Sender<Vec<u8>>>,
+    /// Local bytes waiting for room in the remote window
+    outbox: Vec<u8>,
+    /// Remote bytes waiting for room in `output`
+    stalled: Option<Vec<u8>>,
+    input_done: bool,
+    eof_sent: bool,
+    remote_eof: bool,
+    failed: bool,
+}
+
+impl Chan {
+    fn new(channel: Channel, input: mpsc::Receiver<Vec<u8>>, output: mpsc::Sender<Vec<u8>>) -> Self {
+        Self {
+            channel,
+            input,
+            output: Some(output),
+            outbox: Vec::new(),
+            stalled: None,
+            input_done: false,
+            eof_sent: false,
+            remote_eof: false,
+            failed: false,
+        }
+    }
+
+    fn finished(&self) -> bool {
+        self.failed || (self.output.is_none() && self.eof_sent)
+    }
+
+    fn can_receive(&self) -> bool {
+        self.output.is_some() && self.stalled.is_none()
+    }
+
+    /// Move whatever can move without blocking. Returns whether anything did.
+    fn step(&mut self, buf: &mut [u8]) -> bool {
+        let mut progress = false;
+
+        // local -> remote
+        while !self.input_done && self.outbox.len() < BATCH {
+            match self.input.try_recv() {
+                Ok(data) => self.outbox.extend_from_slice(&data),
+                Err(mpsc::error::TryRecvError::Empty) => break,
+                Err(mpsc::error::TryRecvError::Disconnected) => self.input_done = true,
+            }
+        }
+        while !self.outbox.is_empty() {
+            match self.channel.write(&self.outbox) {
+                Ok(n) => {
+                    self.outbox.drain(..n);
+                    progress = true;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    log::debug!("channel write failed: {e}");
+                    self.failed = true;
+                    return true;
+                }
+            }
+        }
+        if self.input_done && self.outbox.is_empty() && !self.eof_sent {
+            match self.channel.send_eof() {
+                Ok(()) => {
+                    self.eof_sent = true;
+                    progress = true;
+                }
+                Err(e) if would_block(&e) => {}
+                Err(e) => {
+                    log::debug!("channel eof failed: {e}");
+                    self.failed = true;
+                    return true;
+                }
+            }
+        }
+
+        // remote -> local, until the reader falls behind
+        while let Some(output) = &self.output {
+            if let Some(data) = self.stalled.take() {
+                match output.try_send(data) {
+                    Ok(()) => progress = true,
+                    Err(mpsc::error::TrySendError::Full(data)) => {
+                        self.stalled = Some(data);
+                        break;
+                    }
+                    Err(mpsc::error::TrySendError::Closed(_)) => {
+                        self.output = None;
+                        progress = true;
+                        break;
+                    }
+                }
+            }
+            if self.remote_eof {
+                self.output = None;
+                progress = true;
+                break;
+            }
+            match self.channel.read(buf) {
+                Ok(0) if self.channel.eof() => self.remote_eof = true,
+                Ok(0) => break,
+                Ok(n) => {
+                    self.stalled = Some(buf[..n].to_vec());
+                    progress = true;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    log::debug!("channel read failed: {e}");
+                    self.failed = true;
+                    return true;
+                }
+            }
+        }
+
+        // Standard error of remote commands goes to ours.
+        loop {
+            match self.channel.stderr().read(buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    let _ = std::io::stderr().write_all(&buf[..n]);
+                    progress = true;
+                }
+                Err(_) => break,
+            }
+        }
+        progress
+    }
+}
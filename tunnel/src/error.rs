@@ -0,0 +1,72 @@
+use std::io;
+use std::path::PathBuf;
+
+/// Everything that can go wrong setting up or running a tunnel.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("ssh config: {0}")]
+    Config(String),
+
+    #[error("connect to {addr} failed: {error}")]
+    Connect { addr: String, error: io::Error },
+
+    #[error("SSH handshake with {host} failed: {error}")]
+    Handshake { host: String, error: ssh2::Error },
+
+    #[error("host key for {host} {reason}")]
+    HostKey { host: String, reason: String },
+
+    #[error(
+        "host key for {host} has changed! The server sent {fingerprint}, which does not match {}:{line}. \
+         This could be a man-in-the-middle attack; if the key really changed, remove that line",
+        file.display()
+    )]
+    HostKeyChanged { host: String, fingerprint: String, file: PathBuf, line: usize },
+
+    #[error("{host} is not a known host (key {fingerprint}) and the host key policy does not allow adding it")]
+    UnknownHost { host: String, fingerprint: String },
+
+    #[error("private key not found: {}", .0.display())]
+    KeyNotFound(PathBuf),
+
+    #[error("authentication as {user} failed: {reason}")]
+    Auth { user: String, reason: String },
+
+    #[error("bind {addr} failed: {error}")]
+    Bind { addr: String, error: io::Error },
+
+    #[error("remote bind {addr} failed: {error}")]
+    Listen { addr: String, error: ssh2::Error },
+
+    #[error("opening channel to {target} failed: {error}")]
+    Channel { target: String, error: ssh2::Error },
+
+    #[error("running {command:?} failed: {error}")]
+    Exec { command: String, error: ssh2::Error },
+
+    #[error("sftp {op} {}: {reason}", path.display())]
+    Sftp { op: &'static str, path: PathBuf, reason: String },
+
+    #[error("{op} {}: {error}", path.display())]
+    File { op: &'static str, path: PathBuf, error: io::Error },
+
+    #[error("ssh: {0}")]
+    Ssh(#[from] ssh2::Error),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("tunnel task stopped unexpectedly")]
+    Closed,
+
+    #[error("the SSH session is still in use elsewhere")]
+    InUse,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl From<tokio::task::JoinError> for Error {
+    fn from(_: tokio::task::JoinError) -> Self {
+        Error::Closed
+    }
+}
@@ -0,0 +1,43 @@
+//! Commands run on the remote host over the tunnel's session.
+
+use crate::channel::{ChannelReader, ChannelWriter};
+
+/// A running remote command. Its standard error goes to ours; dropping this
+/// closes its standard input.
+pub struct RemoteCommand {
+    pub(crate) stdin: Option<ChannelWriter>,
+    pub(crate) stdout: ChannelReader,
+    pub(crate) buf: Vec<u8>,
+}
+
+impl RemoteCommand {
+    /// Next line of standard output (without the newline), or None once the
+    /// command has closed it.
+    pub async fn next_line(&mut self) -> Option<String> {
+        loop {
+            if let Some(i) = self.buf.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.buf.drain(..=i).collect();
+                let line = String::from_utf8_lossy(&line[..i]);
+                return Some(line.trim_end_matches('\r').to_string());
+            }
+            match self.stdout.recv().await {
+                Some(data) => self.buf.extend_from_slice(&data),
+                None if self.buf.is_empty() => return None,
+                None => return Some(String::from_utf8_lossy(&std::mem::take(&mut self.buf)).into_owned()),
+            }
+        }
+    }
+
+    /// Send bytes to the command's standard input.
+    pub async fn write(&mut self, data: &[u8]) -> bool {
+        match &self.stdin {
+            Some(tx) => tx.send(data.to_vec()).await,
+            None => false,
+        }
+    }
+
+    /// Close standard input; the command sees EOF.
+    pub fn close_stdin(&mut self) {
+        self.stdin = None;
+    }
+}
@@ -0,0 +1,336 @@
+//! OpenSSH known_hosts files: lookup (plain, wildcard and hashed host
+//! patterns, `@revoked` markers) and recording newly accepted hosts.
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+/// What to do with a host key that is not in known_hosts yet. A key that
+/// differs from a recorded one is always refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HostKeyPolicy {
+    /// Only connect to hosts already in known_hosts
+    Strict,
+    /// Record unknown hosts without asking
+    AcceptNew,
+    /// Ask on the terminal, showing the key fingerprint
+    #[default]
+    Ask,
+}
+
+impl FromStr for HostKeyPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "strict" | "yes" => Ok(Self::Strict),
+            "accept-new" => Ok(Self::AcceptNew),
+            "ask" => Ok(Self::Ask),
+            _ => Err(format!("expected strict, accept-new or ask, got: {s}")),
+        }
+    }
+}
+
+impl std::fmt::Display for HostKeyPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Strict => "strict",
+            Self::AcceptNew => "accept-new",
+            Self::Ask => "ask",
+        })
+    }
+}
+
+/// OpenSSH-style fingerprint of a public key blob: `SHA256:` and unpadded base64.
+pub fn fingerprint(key: &[u8]) -> String {
+    format!("SHA256:{}", STANDARD_NO_PAD.encode(Sha256::digest(key)))
+}
+
+/// Algorithm name a public key blob starts with, e.g. `ssh-ed25519`.
+pub(crate) fn key_type(key: &[u8]) -> Option<&str> {
+    let len = u32::from_be_bytes(key.get(..4)?.try_into().ok()?) as usize;
+    std::str::from_utf8(key.get(4..4 + len)?).ok()
+}
+
+/// Short name as ssh shows it: ED25519, ECDSA, RSA.
+pub(crate) fn key_label(key: &[u8]) -> String {
+    match key_type(key) {
+        Some("ssh-ed25519") => "ED25519".to_string(),
+        Some("ssh-rsa") => "RSA".to_string(),
+        Some(t) if t.starts_with("ecdsa-") => "ECDSA".to_string(),
+        Some(t) => t.to_uppercase(),
+        None => "unknown".to_string(),
+    }
+}
+
+/// How known_hosts names a host: plain for port 22, `[host]:port` otherwise.
+pub(crate) fn host_name(host: &str, port: u16) -> String {
+    if port == 22 { host.to_string() } else { format!("[{host}]:{port}") }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Marker {
+    None,
+    Revoked,
+    CertAuthority,
+}
+
+#[derive(Debug)]
+enum Hosts {
+    /// Comma-separated patterns; `*` and `?` wildcards, `!` negates
+    Patterns(Vec<String>),
+    /// `|1|salt|hash`: HMAC-SHA1 of the host name keyed by the salt
+    Hashed { salt: Vec<u8>, hash: Vec<u8> },
+}
+
+#[derive(Debug)]
+struct Entry {
+    line: usize,
+    marker: Marker,
+    hosts: Hosts,
+    key: Vec<u8>,
+}
+
+/// Result of looking a host key up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Check {
+    Match,
+    /// A different key of the same type is recorded on this line
+    Mismatch { line: usize },
+    /// The key is marked `@revoked` on this line
+    Revoked { line: usize },
+    NotFound,
+}
+
+#[derive(Debug)]
+pub(crate) struct KnownHosts {
+    path: PathBuf,
+    entries: Vec<Entry>,
+}
+
+impl KnownHosts {
+    /// Read `path`; a missing file is an empty list.
+    pub(crate) fn load(path: &Path) -> io::Result<Self> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(t) => t,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self::parse(path, &text))
+    }
+
+    /// Parse known_hosts text; lines that make no sense are skipped, as ssh does.
+    pub(crate) fn parse(path: &Path, text: &str) -> Self {
+        let entries = text
+            .lines()
+            .enumerate()
+            .filter_map(|(i, line)| parse_line(i + 1, line))
+            .collect();
+        Self { path: path.to_path_buf(), entries }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(crate) fn check(&self, host: &str, port: u16, key: &[u8]) -> Check {
+        let name = host_name(host, port).to_lowercase();
+        let kind = key_type(key);
+        let mut mismatch = None;
+        for e in self.entries.iter().filter(|e| e.marker != Marker::CertAuthority && e.matches(&name)) {
+            match e.marker {
+                Marker::Revoked if e.key == key => return Check::Revoked { line: e.line },
+                Marker::Revoked => {}
+                _ if e.key == key => return Check::Match,
+                _ if key_type(&e.key) == kind => {
+                    mismatch.get_or_insert(e.line);
+                }
+                _ => {}
+            }
+        }
+        match mismatch {
+            Some(line) => Check::Mismatch { line },
+            None => Check::NotFound,
+        }
+    }
+
+    /// Append an entry for `host`, creating the file (and ~/.ssh) if needed.
+    pub(crate) fn add(&self, host: &str, port: u16, key: &[u8]) -> io::Result<()> {
+        if let Some(dir) = self.path.parent()
+            && !dir.exists()
+        {
+            std::fs::create_dir_all(dir)?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))?;
+            }
+        }
+        let needs_newline = std::fs::read(&self.path).map(|t| !t.is_empty() && !t.ends_with(b"\n")).unwrap_or(false);
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        if needs_newline {
+            file.write_all(b"\n")?;
+        }
+        let kind = key_type(key).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed host key"))?;
+        writeln!(file, "{} {} {}", host_name(host, port), kind, STANDARD.encode(key))
+    }
+}
+
+impl Entry {
+    fn matches(&self, name: &str) -> bool {
+        match &self.hosts {
+            Hosts::Hashed { salt, hash } => {
+                let mut mac = Hmac::<Sha1>::new_from_slice(salt).expect("HMAC takes any key length");
+                mac.update(name.as_bytes());
+                mac.verify_slice(hash).is_ok()
+            }
+            Hosts::Patterns(patterns) => {
+                let mut matched = false;
+                for p in patterns {
+                    match p.strip_prefix('!') {
+                        Some(neg) if glob(neg, name) => return false,
+                        Some(_) => {}
+                        None => matched |= glob(p, name),
+                    }
+                }
+                matched
+            }
+        }
+    }
+}
+
+fn parse_line(line: usize, text: &str) -> Option<Entry> {
+    let text = text.trim();
+    if text.is_empty() || text.starts_with('#') {
+        return None;
+    }
+    let mut fields = text.split_whitespace();
+    let mut first = fields.next()?;
+    let marker = match first {
+        "@revoked" => Marker::Revoked,
+        "@cert-authority" => Marker::CertAuthority,
+        m if m.starts_with('@') => return None,
+        _ => Marker::None,
+    };
+    if marker != Marker::None {
+        first = fields.next()?;
+    }
+    let hosts = match first.strip_prefix("|1|") {
+        Some(hashed) => {
+            let (salt, hash) = hashed.split_once('|')?;
+            Hosts::Hashed { salt: STANDARD.decode(salt).ok()?, hash: STANDARD.decode(hash).ok()? }
+        }
+        None => Hosts::Patterns(first.split(',').map(|p| p.to_lowercase()).collect()),
+    };
+    let kind = fields.next()?;
+    let key = STANDARD.decode(fields.next()?).ok()?;
+    if key_type(&key) != Some(kind) {
+        return None;
+    }
+    Some(Entry { line, marker, hosts, key })
+}
+
+/// Shell-style match with `*` and `?`.
+pub(crate) fn glob(pattern: &str, text: &str) -> bool {
+    let (p, t): (Vec<char>, Vec<char>) = (pattern.chars().collect(), text.chars().collect());
+    let (mut pi, mut ti) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ti));
+            pi += 1;
+        } else if let Some((sp, st)) = star {
+            pi = sp + 1;
+            ti = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIDmWARfLhsnL+zsTf9P9sBEyC/iKpUA1W8cmMdaCy0gw";
+    const OTHER: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIK9gWCKkwnZ68scvq73WCNgmRbzy923nUiqgll7c/rI/";
+
+    fn key(b64: &str) -> Vec<u8> {
+        STANDARD.decode(b64).unwrap()
+    }
+
+    fn known(text: &str) -> KnownHosts {
+        KnownHosts::parse(Path::new("known_hosts"), text)
+    }
+
+    #[test]
+    fn fingerprint_matches_ssh_keygen() {
+        assert_eq!(fingerprint(&key(KEY)), "SHA256:vKHn+T2LCchVkN7NAwsehpsCJksmmjvgVmEn1QDXvbs");
+        assert_eq!(key_label(&key(KEY)), "ED25519");
+    }
+
+    #[test]
+    fn plain_and_wildcard_entries() {
+        let kh = known(&format!(
+            "# comment\n\
+             host1.example.com,10.0.0.1 ssh-ed25519 {KEY}\n\
+             [host2.example.com]:2222 ssh-ed25519 {KEY}\n\
+             *.lab,!bad.lab ssh-ed25519 {KEY}\n"
+        ));
+        let k = key(KEY);
+        assert_eq!(kh.check("host1.example.com", 22, &k), Check::Match);
+        assert_eq!(kh.check("HOST1.example.com", 22, &k), Check::Match);
+        assert_eq!(kh.check("10.0.0.1", 22, &k), Check::Match);
+        assert_eq!(kh.check("host2.example.com", 2222, &k), Check::Match);
+        assert_eq!(kh.check("host2.example.com", 22, &k), Check::NotFound);
+        assert_eq!(kh.check("a.lab", 22, &k), Check::Match);
+        assert_eq!(kh.check("bad.lab", 22, &k), Check::NotFound);
+        assert_eq!(kh.check("host1.example.com", 22, &key(OTHER)), Check::Mismatch { line: 2 });
+    }
+
+    #[test]
+    fn hashed_entries() {
+        // from `ssh-keygen -H`
+        let kh = known(&format!(
+            "|1|AxqSO1VFAHIvKjQDjxKncFmMfzM=|GLztcEq4dCL1fjWnM8E3lLK8Jpg= ssh-ed25519 {KEY}\n\
+             |1|omfZ7HKc/lPoNhmabURqeJ9ssUY=|2TFxqvnFEFGeyB0jYDCRFyK0CxE= ssh-ed25519 {KEY}\n"
+        ));
+        let k = key(KEY);
+        assert_eq!(kh.check("example.com", 2222, &k), Check::Match);
+        assert_eq!(kh.check("example.com", 22, &k), Check::Match);
+        assert_eq!(kh.check("example.org", 22, &k), Check::NotFound);
+        assert_eq!(kh.check("example.com", 22, &key(OTHER)), Check::Mismatch { line: 2 });
+    }
+
+    #[test]
+    fn revoked_keys() {
+        let kh = known(&format!("@revoked * ssh-ed25519 {KEY}\nhost ssh-ed25519 {KEY}\n"));
+        assert_eq!(kh.check("host", 22, &key(KEY)), Check::Revoked { line: 1 });
+    }
+
+    #[test]
+    fn add_then_match() {
+        let dir = std::env::temp_dir().join(format!("known-hosts-test-{}", std::process::id()));
+        let path = dir.join("known_hosts");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&path, format!("other ssh-ed25519 {OTHER}")).unwrap();
+
+        KnownHosts::load(&path).unwrap().add("new.example.com", 2200, &key(KEY)).unwrap();
+        let kh = KnownHosts::load(&path).unwrap();
+        assert_eq!(kh.check("new.example.com", 2200, &key(KEY)), Check::Match);
+        assert_eq!(kh.check("other", 22, &key(OTHER)), Check::Match);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
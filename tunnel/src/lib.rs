@@ -0,0 +1,32 @@
+//! SSH port forwarding on top of libssh2, without an OpenSSH client.
+//!
+//! [`SshTunnel::connect`] opens one authenticated session; any number of
+//! local and remote forwards, SOCKS proxies ([`SshTunnel::add_forward`]) and
+//! remote commands ([`SshTunnel::exec`]) then share it.
+//! [`ConnectOptions::from_ssh_config`] resolves host aliases from
+//! ~/.ssh/config, including ProxyJump chains. [`Ssh`] copies files over SFTP
+//! and runs commands.
+
+mod channel;
+mod connect;
+mod driver;
+mod error;
+mod exec;
+mod known_hosts;
+mod socks;
+mod ssh;
+mod ssh_config;
+mod supervisor;
+#[cfg(test)]
+mod test_server;
+mod tunnel;
+
+pub use connect::{Auth, ConnectOptions};
+pub use error::{Error, Result};
+pub use exec::RemoteCommand;
+pub use known_hosts::{fingerprint, HostKeyPolicy};
+pub use ssh::{Output, Progress, Ssh};
+pub use ssh2::FileStat;
+pub use ssh_config::{HostConfig, SshConfig};
+pub use supervisor::Status;
+pub use tunnel::{Forward, ForwardId, SshTunnel, Target};
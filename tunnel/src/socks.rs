@@ -0,0 +1,129 @@
+//! The server side of SOCKS5 (RFC 1928), as much as `ssh -D` offers: no
+//! authentication, CONNECT only, to IPv4, IPv6 or a host name. Names are
+//! resolved by the remote host.
+
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::tunnel::Target;
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_NAME: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// Reply codes.
+pub(crate) const SUCCEEDED: u8 = 0;
+pub(crate) const GENERAL_FAILURE: u8 = 1;
+pub(crate) const CONNECTION_REFUSED: u8 = 5;
+const COMMAND_NOT_SUPPORTED: u8 = 7;
+const ADDRESS_NOT_SUPPORTED: u8 = 8;
+
+/// Read the greeting and the request; the destination of a CONNECT. Requests
+/// we can't serve are answered here and come back as errors.
+pub(crate) async fn accept<S: AsyncRead + AsyncWrite + Unpin>(s: &mut S) -> io::Result<Target> {
+    let [version, n] = read_array(s).await?;
+    if version != VERSION {
+        return Err(invalid(format!("not SOCKS5 (version {version})")));
+    }
+    let mut methods = vec![0u8; n as usize];
+    s.read_exact(&mut methods).await?;
+    if !methods.contains(&NO_AUTH) {
+        s.write_all(&[VERSION, NO_ACCEPTABLE_METHOD]).await?;
+        return Err(invalid("client requires authentication".to_string()));
+    }
+    s.write_all(&[VERSION, NO_AUTH]).await?;
+
+    let [version, command, _reserved, atyp] = read_array(s).await?;
+    if version != VERSION {
+        return Err(invalid(format!("not SOCKS5 (version {version})")));
+    }
+    let host = match atyp {
+        ATYP_IPV4 => Ipv4Addr::from(read_array::<4, _>(s).await?).to_string(),
+        ATYP_IPV6 => Ipv6Addr::from(read_array::<16, _>(s).await?).to_string(),
+        ATYP_NAME => {
+            let [len] = read_array(s).await?;
+            let mut name = vec![0u8; len as usize];
+            s.read_exact(&mut name).await?;
+            String::from_utf8(name).map_err(|_| invalid("host name is not UTF-8".to_string()))?
+        }
+        _ => {
+            reply(s, ADDRESS_NOT_SUPPORTED).await?;
+            return Err(invalid(format!("address type {atyp}")));
+        }
+    };
+    let port = u16::from_be_bytes(read_array(s).await?);
+    if command != CONNECT {
+        reply(s, COMMAND_NOT_SUPPORTED).await?;
+        return Err(invalid(format!("command {command} to {host}:{port}; only CONNECT is supported")));
+    }
+    Ok(Target::Tcp { host, port })
+}
+
+/// Answer the request. The bound address is not ours to know (it is on the
+/// remote host), so it is reported as 0.0.0.0:0, as ssh does.
+pub(crate) async fn reply<S: AsyncWrite + Unpin>(s: &mut S, code: u8) -> io::Result<()> {
+    s.write_all(&[VERSION, code, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0]).await
+}
+
+async fn read_array<const N: usize, S: AsyncRead + Unpin>(s: &mut S) -> io::Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    s.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("SOCKS: {message}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn request(bytes: &[u8]) -> (io::Result<Target>, Vec<u8>) {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(bytes).await.unwrap();
+        let result = accept(&mut server).await;
+        drop(server);
+        let mut answer = Vec::new();
+        client.read_to_end(&mut answer).await.unwrap();
+        (result, answer)
+    }
+
+    #[tokio::test]
+    async fn connect_requests() {
+        let (target, answer) = request(&[5, 1, 0, 5, 1, 0, 3, 9, b'l', b'o', b'c', b'a', b'l', b'h', b'o', b's', b't', 0x1f, 0x90]).await;
+        assert!(matches!(target.unwrap(), Target::Tcp { host, port: 8080 } if host == "localhost"));
+        assert_eq!(answer, [5, 0]);
+
+        let (target, _) = request(&[5, 1, 0, 5, 1, 0, 1, 10, 0, 0, 5, 0, 80]).await;
+        assert!(matches!(target.unwrap(), Target::Tcp { host, port: 80 } if host == "10.0.0.5"));
+
+        let mut v6 = vec![5, 1, 0, 5, 1, 0, 4];
+        v6.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        v6.extend_from_slice(&[1, 187]);
+        let (target, _) = request(&v6).await;
+        assert!(matches!(target.unwrap(), Target::Tcp { host, port: 443 } if host == "::1"));
+    }
+
+    #[tokio::test]
+    async fn refused_requests() {
+        // username/password only
+        let (target, answer) = request(&[5, 1, 2]).await;
+        assert!(target.is_err());
+        assert_eq!(answer, [5, 0xff]);
+
+        // BIND
+        let (target, answer) = request(&[5, 1, 0, 5, 2, 0, 1, 127, 0, 0, 1, 0, 80]).await;
+        assert!(target.is_err());
+        assert_eq!(answer, [5, 0, 5, COMMAND_NOT_SUPPORTED, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+        // SOCKS4
+        assert!(request(&[4, 1, 0, 80, 127, 0, 0, 1, 0]).await.0.is_err());
+    }
+}
@@ -1,616 +1,745 @@
-use std::io::{Read, Write};
-use std::net::{TcpStream, TcpListener, SocketAddr, ToSocketAddrs};
-use socket2::{Socket, Domain, Type};
-use std::time::Duration;
-use ssh2::{Session, Sftp, FileStat};
-use std::path::{PathBuf, Path};
-//use super::command;
-
-#[derive(Default)]
-pub struct Ssh {
-    session : Option<Session>,
-    sftp : Option<Sftp>,
-    host : String,
-    user : String,
-    password : String,
-    private_key : String,
+//! Files and commands on the remote host over SFTP and exec channels, for
+//! deploying binaries and moving files around. [`Ssh`] has its own session in
+//! blocking mode; each call runs on Tokio's blocking pool.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use polling::{Event, Events, Poller};
+use ssh2::{BlockDirections, FileStat, OpenFlags, OpenType, RenameFlags, Session, Sftp};
+
+use crate::connect::{open_session, ConnectOptions, Login};
+use crate::error::{Error, Result};
+use crate::supervisor::{through_jumps, Connection};
+
+/// Bytes per SFTP read or write; libssh2 splits larger ones anyway.
+const CHUNK: usize = 32 * 1024;
+/// Poller key of the session socket
+const SOCKET: usize = 0;
+
+/// How far a transfer has got, summed over all its files.
+#[derive(Debug, Clone)]
+pub struct Progress {
+    /// The file being copied now
+    pub path: PathBuf,
+    pub bytes: u64,
+    pub total: u64,
 }
 
-#[derive(Clone, serde::Serialize)]
-struct Payload {
-    percent: f32,
+impl Progress {
+    pub fn percent(&self) -> f32 {
+        if self.total == 0 { 100.0 } else { self.bytes as f32 * 100.0 / self.total as f32 }
+    }
 }
 
-#[allow(dead_code)]
-impl Ssh {
-    pub fn new() -> Self {
-        Self { ..Default::default() }
-    }
-
-    
-    pub fn supported_algs() -> String {
-        let ssh = Session::new().unwrap();
-        println!("hostKey: {:?}", ssh.supported_algs(ssh2::MethodType::HostKey).unwrap());
-        println!("CryptCs: {:?}", ssh.supported_algs(ssh2::MethodType::CryptCs).unwrap());
-        println!("Kex: {:?}", ssh.supported_algs(ssh2::MethodType::Kex).unwrap());
-        println!("MacCs: {:?}", ssh.supported_algs(ssh2::MethodType::MacCs).unwrap());
-        println!("CompCs: {:?}", ssh.supported_algs(ssh2::MethodType::CompCs).unwrap());
-
-        "supported flags above".to_string()
-    }
-    pub fn private_key_path() -> PathBuf {
-        let home = dirs::home_dir().unwrap();
-        let prikey = home.join(".ssh").join("id_rsa");
-        PathBuf::from(&prikey)
-        
-    }
-    pub fn public_key_path() -> PathBuf {
-        let home = dirs::home_dir().unwrap();
-        let pubkey = home.join(".ssh").join("id_rsa.pub").clone();
-        PathBuf::from(&pubkey)
-    }    
-    pub fn has_private_key() -> bool {     
-        Ssh::private_key_path().exists()
-        
-    }
-    pub fn has_public_key() -> bool {      
-        Ssh::public_key_path().exists()
-    }
-    fn generate_public_key() -> Result<(), String> {
-        let seckey = Ssh::private_key_path();
-        let pubkey = Ssh::public_key_path();
-        
-        let cmd = format!("ssh-keygen -f {} -y > {}", seckey.display(), pubkey.display());
-        let (_,e,_) = command::run(&cmd);
-        
-        if e.len()>0 {
-            Err(e)
-        } else {
-            Ok(())
-        }
-    }
-    fn generate_keys() -> Result<(), String> {
-        let seckey = Ssh::private_key_path();
-        
-        let cmd = format!("ssh-keygen -m PEM -N \"\" -f {}", seckey.display());
-        let (_,e,_) = command::run(&cmd);
-        
-        if e.len()>0 {
-            Err(e)
-        } else {
-            Ok(())
-        }
-    }
-    fn transfer_public_key(host: &str, port: i16, user: &str, password: &str) -> Result<(), String> {
-        let pubkeytext = std::fs::read_to_string(&Ssh::public_key_path()).unwrap().trim().to_string();
-        let cmd = format!("exec sh -c \"cd; umask 077; mkdir -p .ssh; echo '{}' >> .ssh/authorized_keys\"",
-                        pubkeytext);
-        println!("{cmd}");
-        let mut ssh = Ssh::new();
-        if let Err(e) = ssh.connect_with_password(host, port, user, password) {
-            println!("Error transfering keys, login with password: {e}");
-            return Err(e);
-        }
-        if let Err(e) = ssh.run(&cmd) {
-            println!("Error transfering keys, running command: {e}");            
-            Err(e)
-        } else {
-            Ok(())
-        }
+/// What a remote command printed and how it exited.
+#[derive(Debug, Clone)]
+pub struct Output {
+    pub status: i32,
+    pub stdout: String,
+    pub stderr: String,
+}
 
+impl Output {
+    pub fn success(&self) -> bool {
+        self.status == 0
     }
-    fn test_ssh(host: &str, port: i16, user: &str) -> Result<(), String> {
-        if !Ssh::has_private_key() {
-            return Err("No private key".to_string());
-        }
-        let pkey = Ssh::private_key_path();
-        let mut ssh = Ssh::new();
-        if let Err(e) = ssh.connect_with_key(host, port, user, pkey.to_str().unwrap()) {
-            Err(e)
-        } else {        
-            Ok(())
-        }
+}
+
+struct Inner {
+    /// Held for the whole of each call
+    turn: Mutex<()>,
+    session: Session,
+    sftp: Sftp,
+    /// A clone of the session's socket, for handing it to a tunnel
+    socket: std::net::TcpStream,
+    /// How we got in, e.g. "publickey (~/.ssh/id_ed25519)"
+    method: String,
+    /// Jump host sessions this one runs through
+    via: Option<Arc<Connection>>,
+}
+
+/// An SFTP and command session on one host. Cheap to clone; clones share the
+/// session, and calls on it take turns.
+#[derive(Clone)]
+pub struct Ssh {
+    inner: Arc<Inner>,
+}
+
+impl Ssh {
+    /// Log in like [`SshTunnel::connect`](crate::SshTunnel::connect) and start SFTP.
+    pub async fn connect(mut opts: ConnectOptions) -> Result<Self> {
+        let (via, transport) = through_jumps(&mut opts).await?;
+        let inner = tokio::task::spawn_blocking(move || {
+            let login = open_session(&opts, transport)?;
+            login.session.set_blocking(true);
+            let sftp = login.session.sftp()?;
+            Ok::<_, Error>(Inner {
+                turn: Mutex::new(()),
+                session: login.session,
+                sftp,
+                socket: login.socket,
+                method: login.method,
+                via,
+            })
+        })
+        .await??;
+        Ok(Self { inner: Arc::new(inner) })
     }
-    pub fn setup_ssh(host: &str, port: i16, user: &str, password: &str) -> Result<(), String> {
-        if !Ssh::has_private_key() {
-            if let Err(e) = Ssh::generate_keys() {
-                return Err(format!("Could not generate private key: {e}"));
+
+    /// How the session authenticated, e.g. "publickey (ssh-agent: me@laptop)".
+    pub fn auth_method(&self) -> &str {
+        &self.inner.method
+    }
+
+    /// Give the session to a driver, for [`SshTunnel::from_ssh`](crate::SshTunnel::from_ssh).
+    pub(crate) async fn into_connection(self) -> Result<Connection> {
+        let Inner { session, sftp, socket, method, via, .. } = Arc::try_unwrap(self.inner).map_err(|_| Error::InUse)?;
+        let session = tokio::task::spawn_blocking(move || {
+            drop(sftp);
+            session.set_blocking(false);
+            session
+        })
+        .await?;
+        Connection::start(Login { session, socket, method }, via)
+    }
+
+    async fn blocking<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Inner) -> Result<T> + Send + 'static,
+    {
+        let inner = Arc::clone(&self.inner);
+        tokio::task::spawn_blocking(move || {
+            let _turn = inner.turn.lock().unwrap_or_else(|e| e.into_inner());
+            f(&inner)
+        })
+        .await?
+    }
+
+    /// Run `command` through the remote shell and wait for it to exit.
+    pub async fn run(&self, command: &str) -> Result<Output> {
+        let command = command.to_string();
+        self.blocking(move |inner| inner.run(&command)).await
+    }
+
+    /// Attributes of `path`, without following a final symlink. None if it
+    /// does not exist.
+    pub async fn stat(&self, path: impl AsRef<Path>) -> Result<Option<FileStat>> {
+        let path = path.as_ref().to_path_buf();
+        self.blocking(move |inner| inner.lstat(&path)).await
+    }
+
+    pub async fn mkdir(&self, path: impl AsRef<Path>, mode: i32) -> Result<()> {
+        let path = path.as_ref().to_path_buf();
+        self.blocking(move |inner| inner.sftp.mkdir(&path, mode).map_err(sftp_error("mkdir", &path))).await
+    }
+
+    /// Create `path` and any missing parents, like `mkdir -p`.
+    pub async fn mkdir_all(&self, path: impl AsRef<Path>, mode: i32) -> Result<()> {
+        let path = path.as_ref().to_path_buf();
+        self.blocking(move |inner| inner.mkdir_all(&path, mode)).await
+    }
+
+    pub async fn rmdir(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref().to_path_buf();
+        self.blocking(move |inner| inner.sftp.rmdir(&path).map_err(sftp_error("rmdir", &path))).await
+    }
+
+    /// Remove a file, a symlink, or a directory and everything in it.
+    pub async fn remove(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref().to_path_buf();
+        self.blocking(move |inner| inner.remove(&path)).await
+    }
+
+    /// Rename `from` to `to`, replacing `to` if it exists.
+    pub async fn rename(&self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<()> {
+        let (from, to) = (from.as_ref().to_path_buf(), to.as_ref().to_path_buf());
+        self.blocking(move |inner| inner.rename(&from, &to)).await
+    }
+
+    /// The entries of a directory, as full paths, without `.` and `..`.
+    pub async fn read_dir(&self, path: impl AsRef<Path>) -> Result<Vec<(PathBuf, FileStat)>> {
+        let path = path.as_ref().to_path_buf();
+        self.blocking(move |inner| inner.sftp.readdir(&path).map_err(sftp_error("readdir", &path))).await
+    }
+
+    pub async fn read_link(&self, path: impl AsRef<Path>) -> Result<PathBuf> {
+        let path = path.as_ref().to_path_buf();
+        self.blocking(move |inner| inner.sftp.readlink(&path).map_err(sftp_error("readlink", &path))).await
+    }
+
+    /// The absolute path of `path`, e.g. "." for the home directory.
+    pub async fn real_path(&self, path: impl AsRef<Path>) -> Result<PathBuf> {
+        let path = path.as_ref().to_path_buf();
+        self.blocking(move |inner| inner.sftp.realpath(&path).map_err(sftp_error("realpath", &path))).await
+    }
+
+    /// Replace `path` with `data` in one step: readers see the old file or
+    /// the new one, never part of it, and a running binary is not disturbed.
+    pub async fn write_atomic(&self, path: impl AsRef<Path>, data: Vec<u8>, mode: i32) -> Result<()> {
+        let path = path.as_ref().to_path_buf();
+        self.blocking(move |inner| {
+            let total = data.len() as u64;
+            let mut progress = Progress { path: path.clone(), bytes: 0, total };
+            inner.put(&mut data.as_slice(), &path, mode, &mut progress, &mut |_| {})
+        })
+        .await
+    }
+
+    pub async fn read(&self, path: impl AsRef<Path>) -> Result<Vec<u8>> {
+        let path = path.as_ref().to_path_buf();
+        self.blocking(move |inner| {
+            let mut data = Vec::new();
+            let mut file = inner.sftp.open(&path).map_err(sftp_error("open", &path))?;
+            file.read_to_end(&mut data).map_err(io_error("read", &path))?;
+            Ok(data)
+        })
+        .await
+    }
+
+    /// Copy a local file to `remote`, atomically and with the same mode.
+    /// `progress` is called after every chunk.
+    pub async fn upload(
+        &self,
+        local: impl AsRef<Path>,
+        remote: impl AsRef<Path>,
+        mut progress: impl FnMut(&Progress) + Send + 'static,
+    ) -> Result<u64> {
+        let (local, remote) = (local.as_ref().to_path_buf(), remote.as_ref().to_path_buf());
+        self.blocking(move |inner| {
+            let total = local_metadata(&local)?.len();
+            let mut p = Progress { path: local.clone(), bytes: 0, total };
+            inner.upload_file(&local, &remote, &mut p, &mut progress)?;
+            Ok(p.bytes)
+        })
+        .await
+    }
+
+    /// Copy a remote file to `local`, atomically and with the same mode.
+    pub async fn download(
+        &self,
+        remote: impl AsRef<Path>,
+        local: impl AsRef<Path>,
+        mut progress: impl FnMut(&Progress) + Send + 'static,
+    ) -> Result<u64> {
+        let (remote, local) = (remote.as_ref().to_path_buf(), local.as_ref().to_path_buf());
+        self.blocking(move |inner| {
+            let stat = inner.sftp.stat(&remote).map_err(sftp_error("stat", &remote))?;
+            let mut p = Progress { path: remote.clone(), bytes: 0, total: stat.size.unwrap_or(0) };
+            inner.download_file(&remote, &stat, &local, &mut p, &mut progress)?;
+            Ok(p.bytes)
+        })
+        .await
+    }
+
+    /// Copy a local directory tree into `remote` (created if missing).
+    /// Symlinks are followed.
+    pub async fn upload_dir(
+        &self,
+        local: impl AsRef<Path>,
+        remote: impl AsRef<Path>,
+        mut progress: impl FnMut(&Progress) + Send + 'static,
+    ) -> Result<u64> {
+        let (local, remote) = (local.as_ref().to_path_buf(), remote.as_ref().to_path_buf());
+        self.blocking(move |inner| {
+            let files = local_tree(&local)?;
+            let total = files.iter().map(|(_, len)| len).sum();
+            let mut p = Progress { path: local.clone(), bytes: 0, total };
+            inner.mkdir_all(&remote, 0o755)?;
+            for (file, _) in files {
+                let relative = file.strip_prefix(&local).expect("walked from local");
+                let target = remote.join(relative);
+                if let Some(parent) = target.parent() {
+                    inner.mkdir_all(parent, 0o755)?;
+                }
+                inner.upload_file(&file, &target, &mut p, &mut progress)?;
             }
-        }
-        if !Ssh::has_public_key() {
-            if let Err(e) = Ssh::generate_public_key() {
-                return Err(format!("Could not generate public key: {e}"));
-            }         
-        }
-        if Ssh::test_ssh(host, port, user).is_err() {
-            if let Err(e) = Ssh::transfer_public_key(host, port, user, password) {
-                return Err(format!("Could not transfer public key: {e}"));
+            Ok(p.bytes)
+        })
+        .await
+    }
+
+    /// Copy a remote directory tree into `local` (created if missing).
+    /// Remote symlinks are skipped.
+    pub async fn download_dir(
+        &self,
+        remote: impl AsRef<Path>,
+        local: impl AsRef<Path>,
+        mut progress: impl FnMut(&Progress) + Send + 'static,
+    ) -> Result<u64> {
+        let (remote, local) = (remote.as_ref().to_path_buf(), local.as_ref().to_path_buf());
+        self.blocking(move |inner| {
+            let files = inner.remote_tree(&remote)?;
+            let total = files.iter().map(|(_, stat)| stat.size.unwrap_or(0)).sum();
+            let mut p = Progress { path: remote.clone(), bytes: 0, total };
+            fs::create_dir_all(&local).map_err(io_error("mkdir", &local))?;
+            for (file, stat) in files {
+                let relative = file.strip_prefix(&remote).expect("walked from remote");
+                let target = local.join(relative);
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent).map_err(io_error("mkdir", parent))?;
+                }
+                inner.download_file(&file, &stat, &target, &mut p, &mut progress)?;
             }
-            if let Err(e) = Ssh::test_ssh(host, port, user) {
-                return Err(format!("Test ssh failed: {e}"));
+            Ok(p.bytes)
+        })
+        .await
+    }
+
+    /// Add `public_key` (the contents of a .pub file) to the remote
+    /// ~/.ssh/authorized_keys unless it is there already.
+    pub async fn authorize_key(&self, public_key: &str) -> Result<()> {
+        let key = public_key.trim().to_string();
+        self.blocking(move |inner| {
+            let home = inner.sftp.realpath(Path::new(".")).map_err(sftp_error("realpath", Path::new(".")))?;
+            let dir = home.join(".ssh");
+            inner.mkdir_all(&dir, 0o700)?;
+            let path = dir.join("authorized_keys");
+            let mut keys = String::new();
+            if let Ok(mut file) = inner.sftp.open(&path) {
+                file.read_to_string(&mut keys).map_err(io_error("read", &path))?;
             }
-        }
-        Ok(())
-    }
-    fn _get_tcp(&mut self, host: &str, port: i16) -> Result<TcpStream, String> {
-        let timeout = Duration::new(5, 0); // 5 secs
-        let addresses: Vec<_> = match format!("{}:{}", host, port).to_socket_addrs() {
-            Err(e) => {
-                println!("Unable to resolve address: {}:{}  {:?}",host, port, e);
-                return Err(e.to_string())
-            },
-            Ok(o) => o.collect(),
-        };
-        let mut tcp = None;
-        let mut error = String::new();
-
-        for addr in addresses {
-            match TcpStream::connect_timeout(&addr, timeout) {
-                Err(e) => {
-                    //error.push_str(&format!("tcp error: {:?}\n", e));
-                    error = String::from(&format!("tcp error: {:?}", e));
-                    continue;
-                },
-                Ok(o) => {
-                    println!("connected to: {:?}", addr);
-                    tcp = Some(o);
-                    break;  
-                },
-            };
-        }
-
-        if tcp.is_none() {
-            return Err(error);
-        }
-
-        Ok(tcp.unwrap())
+            if keys.lines().any(|line| line.trim() == key) {
+                return Ok(());
+            }
+            if !keys.is_empty() && !keys.ends_with('\n') {
+                keys.push('\n');
+            }
+            keys.push_str(&key);
+            keys.push('\n');
+            let mut p = Progress { path: path.clone(), bytes: 0, total: keys.len() as u64 };
+            inner.put(&mut keys.as_bytes(), &path, 0o600, &mut p, &mut |_| {})
+        })
+        .await
     }
-    pub fn connect_with_password(&mut self, 
-        host: &str, port: i16, user: &str, password: &str) -> Result<(), String> {
-        
-        let tcp = match self._get_tcp(host, port) {
-            Err(e) => return Err(e),
-            Ok(o) => o,
-        };
 
-        let mut session = Session::new().unwrap();
-        session.set_tcp_stream(tcp);
+    /// End the session.
+    pub async fn disconnect(self) -> Result<()> {
+        self.blocking(|inner| Ok(inner.session.disconnect(None, "Bye", None)?)).await
+    }
+}
 
-        if let Err(e) = session.handshake() {                
-            return Err(format!("SSH handshake error: {}", e));
+impl Inner {
+    fn run(&self, command: &str) -> Result<Output> {
+        let exec_error = |error| Error::Exec { command: command.to_string(), error };
+        let mut channel = self.session.channel_session().map_err(exec_error)?;
+        channel.exec(command).map_err(exec_error)?;
+
+        // Read both streams as data comes, so neither can fill the window
+        // while we wait on the other. When neither has any, sleep until the
+        // socket does.
+        let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+        let poller = Poller::new()?;
+        // SAFETY: the socket lives as long as `self` and is deleted from the poller below
+        unsafe { poller.add(&self.socket, Event::none(SOCKET))? };
+        self.session.set_blocking(false);
+        let read = (|| {
+            let mut buf = vec![0u8; CHUNK];
+            let mut events = Events::new();
+            loop {
+                let out = read_some(&mut channel, &mut buf, &mut stdout)?;
+                let err = read_some(&mut channel.stderr(), &mut buf, &mut stderr)?;
+                if out || err {
+                    continue;
+                }
+                if channel.eof() {
+                    return Ok::<_, io::Error>(());
+                }
+                // A window adjust we owe the server may be stuck on a full socket.
+                let interest = match self.session.block_directions() {
+                    BlockDirections::Outbound | BlockDirections::Both => Event::all(SOCKET),
+                    _ => Event::readable(SOCKET),
+                };
+                poller.modify(&self.socket, interest)?;
+                events.clear();
+                if let Err(e) = poller.wait(&mut events, None)
+                    && e.kind() != io::ErrorKind::Interrupted
+                {
+                    return Err(e);
+                }
+            }
+        })();
+        self.session.set_blocking(true);
+        let _ = poller.delete(&self.socket);
+        read?;
+        channel.wait_close().map_err(exec_error)?;
+        Ok(Output {
+            status: channel.exit_status().map_err(exec_error)?,
+            stdout: String::from_utf8_lossy(&stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&stderr).into_owned(),
+        })
+    }
+
+    fn lstat(&self, path: &Path) -> Result<Option<FileStat>> {
+        match self.sftp.lstat(path) {
+            Ok(stat) => Ok(Some(stat)),
+            Err(e) if is_not_found(&e) => Ok(None),
+            Err(e) => Err(sftp_error("stat", path)(e)),
         }
+    }
 
-        if let Err(e) = session.userauth_password(user, password) {
-            return Err(format!("Authentication error: {e}"));
+    fn mkdir_all(&self, path: &Path, mode: i32) -> Result<()> {
+        match self.sftp.stat(path) {
+            Ok(stat) if stat.is_dir() => return Ok(()),
+            Ok(_) => return Err(Error::Sftp { op: "mkdir", path: path.to_path_buf(), reason: "not a directory".to_string() }),
+            Err(e) if is_not_found(&e) => {}
+            Err(e) => return Err(sftp_error("stat", path)(e)),
         }
-
-        assert!(session.authenticated());
-        let sftp = match session.sftp() {
-            Err(e) => return Err(format!("Cannot create sftp channel {e}")),
-            Ok(o) => o,
-        };
-
-        self.session = Some(session);
-        self.sftp = Some(sftp);
-        self.host = host.to_string();
-        self.user = user.to_string();
-        self.password = password.to_string();
-        Ok(())
-    }
-    pub fn connect_with_key(&mut self, 
-        host: &str, port: i16, user: &str, pkey: &str) -> Result<(), String> {
-        let tcp = match self._get_tcp(host, port) {
-            Err(e) => return Err(e),
-            Ok(o) => o,
-        };
-        let mut session = Session::new().unwrap();
-        session.set_tcp_stream(tcp);
-
-        if let Err(e) = session.handshake() {
-            return Err(format!("SSH handshake error: {}", e));
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            self.mkdir_all(parent, mode)?;
         }
-
-        let private_key = std::path::Path::new(pkey);
-
-        if let Err(e) = session.userauth_pubkey_file(user, None, private_key, None) {
-            return Err(format!("Authentication error: {e}"));
+        match self.sftp.mkdir(path, mode) {
+            Ok(()) => Ok(()),
+            // Someone else made it meanwhile.
+            Err(_) if self.sftp.stat(path).is_ok_and(|s| s.is_dir()) => Ok(()),
+            Err(e) => Err(sftp_error("mkdir", path)(e)),
         }
+    }
 
-        assert!(session.authenticated());
-
-        let sftp = match session.sftp() {
-            Err(e) => return Err(format!("Cannot create sftp channel {e}")),
-            Ok(o) => o,
-        };
-
-        self.session = Some(session);
-        self.sftp = Some(sftp);
-        self.host = host.to_string();
-        self.user = user.to_string();
-        self.private_key = pkey.to_string();
-        Ok(())
-    }
-    pub fn direct_tcpip(&mut self, 
-        shost: &str, sport: u16, rhost: &str, rport: u16) -> Result<(), String> {
-        
-        //let listener = TcpListener::bind(format!("{shost}:{sport}")).unwrap();
-        let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
-        socket.set_reuse_address(true);
-        let address: SocketAddr = format!("{shost}:{sport}").parse().unwrap();
-        let address = address.into();
-        socket.bind(&address).unwrap();
-        socket.listen(2).unwrap();        
-        let listener: TcpListener = socket.into();
-        
-        println!("Waiting for connections in {shost}:{sport}...");
-        let mut forwarder = match listener.accept() {
-            Ok((socket, addr)) => {
-                println!("new client: {addr:?}");
-                socket
-            },
-            Err(e) => return Err(format!("couldn't get client: {e:?}"))
-        };
-
-        println!("Forwarding connections from {shost}:{sport} to {rhost}:{rport}...");
-        
-        let mut channel = match self.session.as_ref().unwrap()
-            .channel_direct_tcpip(rhost, rport, Some((shost, sport))) {
-            Err(e) => return Err(format!("Error creating channel for direct tcpip: {}", e)),
-            Ok(o) => o,
-        };  
-        let mut buf = [0u8; 3000];
-        
-        // loop {            
-        //     let len = forwarder.read(&mut buf).unwrap();
-        //     println!("bytes recieved: {len}");
-        //     let mut wr = 0 as usize;
-        //     while wr < len {
-        //         let i = match channel.write(&buf[wr..]) {
-        //             Ok(i) => i,
-        //             Err(e) => return Err(format!("channel_write: {:?}",e))
-        //         };
-        //         wr += i;
-        //     }
-        //     loop {
-        //         let len = match channel.read(&mut buf) {
-        //             Ok(len) => len,
-        //             Err(e) => {
-        //                 println!("channel_write: {:?}",e);
-        //                 break;
-        //             },
-        //         };
-        //         wr = 0 as usize;
-        //         while wr < len {
-        //             let i = match forwarder.write(&buf[wr..]) { 
-        //                 Ok(i) => i,
-        //                 Err(e) => return Err(format!("forwarder write: {:?}",e)),
-        //             };
-        //             wr += i;
-        //         }
-        //         if channel.eof() {
-        //             return Err(format!("server disconnected"));
-        //         }
-        //     }
-
-
-        // }
-        
-    
-
-        
-        Ok(())
-    }
-    pub fn disconnect(&mut self) -> Result<(), String> {
-        if let Err(e) = self.session.as_ref().unwrap().disconnect(None,"",None) {
-            return Err(e.to_string());
+    fn remove(&self, path: &Path) -> Result<()> {
+        let stat = self.sftp.lstat(path).map_err(sftp_error("stat", path))?;
+        if !stat.is_dir() {
+            return self.sftp.unlink(path).map_err(sftp_error("unlink", path));
         }
-        Ok(())
-    }
-    pub fn run(&mut self, cmd: &str) -> Result<String, String> {
-        println!("running CMD: {}", cmd);
-        let mut channel = match self.session.as_ref().unwrap().channel_session() {
-            Err(e) => return Err(format!("Error: {}", e)),
-            Ok(o) => o,
-        };
-        channel.exec(cmd).unwrap();
-        let mut s = String::new();
-        channel.stderr().read_to_string(&mut s).unwrap();
-        if !s.trim().is_empty() {
-            return Err(format!("stderr: {}",s));
-        };
-        channel.read_to_string(&mut s).unwrap();
-        channel.wait_close().unwrap();
-        Ok(s.trim().to_string())
-    }
-    pub fn sftp_stat(&mut self, filename: &str) -> Result<FileStat, String> {
-        match self.sftp.as_ref().unwrap().lstat(Path::new(filename)) {
-            Err(e) => Err(format!("Cannot stat {filename}: {e}")),
-            Ok(o) => Ok(o)
+        for (entry, _) in self.sftp.readdir(path).map_err(sftp_error("readdir", path))? {
+            self.remove(&entry)?;
         }
-    }
-    pub fn sftp_mkdir(&mut self, dirname: &str) -> Result<(), String> {
-        match self.sftp.as_ref().unwrap().mkdir(Path::new(dirname), 0o755) {
-            Err(e) => Err(format!("Cannot make dir {dirname}: {e}")),
-            Ok(_) => Ok(())
-        }
-    }
-    pub fn sftp_rmdir(&mut self, dirname: &str) -> Result<(), String> {
-        match self.sftp.as_ref().unwrap().rmdir(Path::new(dirname)) {
-            Err(e) => Err(format!("Cannot delete dir {dirname}: {e}")),
-            Ok(_) => Ok(())
+        self.sftp.rmdir(path).map_err(sftp_error("rmdir", path))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let flags = RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE;
+        match self.sftp.rename(from, to, Some(flags)) {
+            Ok(()) => Ok(()),
+            // SFTP v3 servers (OpenSSH included) refuse to rename over an
+            // existing file; mv does it in one step.
+            Err(e) if self.lstat(to)?.is_some() => {
+                let command = format!("mv -f -- {} {}", shell_quote(from), shell_quote(to));
+                let out = self.run(&command)?;
+                if out.success() {
+                    Ok(())
+                } else {
+                    log::debug!("sftp rename: {e}");
+                    Err(Error::Sftp { op: "rename", path: from.to_path_buf(), reason: out.stderr.trim().to_string() })
+                }
+            }
+            Err(e) => Err(sftp_error("rename", from)(e)),
         }
     }
-    pub fn sftp_create(&mut self, filename: &str) -> Result<ssh2::File, String> {
-        let f = match self.sftp.as_ref().unwrap().create(Path::new(filename)) {
-            Err(e) => return Err(format!("Cannot create file {filename}: {e}")),
-            Ok(o) => o,
-        };
-        Ok(f)
-    }
-    pub fn sftp_open(&mut self, filename: &str) -> Result<ssh2::File, String> {
-        let f = match self.sftp.as_ref().unwrap().open(Path::new(filename)) {
-            Err(e) => return Err(format!("Cannot open file {filename}: {e}")),
-            Ok(o) => o,
-        };
-        Ok(f)
-    }
-    pub fn sftp_rename(&mut self, src: &str, dst: &str) -> Result<(), String> {
-        let s = Path::new(src);
-        let d = Path::new(dst);
-        let sftp = self.sftp.as_ref().unwrap();
-        if let Err(e) = sftp.rename(&s, &d, None) {
-            return Err(format!("Cannot rename {src}: {e}"));
+
+    /// Write `data` to a temporary file next to `path`, then rename it over `path`.
+    fn put(
+        &self,
+        data: &mut dyn Read,
+        path: &Path,
+        mode: i32,
+        p: &mut Progress,
+        progress: &mut dyn FnMut(&Progress),
+    ) -> Result<()> {
+        let tmp = temp_name(path);
+        let result = (|| {
+            let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE;
+            let mut file = self.sftp.open_mode(&tmp, flags, mode, OpenType::File).map_err(sftp_error("create", &tmp))?;
+            let mut buf = vec![0u8; CHUNK];
+            loop {
+                let n = data.read(&mut buf).map_err(io_error("read", &p.path))?;
+                if n == 0 {
+                    break;
+                }
+                file.write_all(&buf[..n]).map_err(io_error("write", &tmp))?;
+                p.bytes += n as u64;
+                progress(p);
+            }
+            file.fsync().or_else(|e| if is_unsupported(&e) { Ok(()) } else { Err(e) }).map_err(sftp_error("fsync", &tmp))?;
+            drop(file);
+            self.rename(&tmp, path)
+        })();
+        if result.is_err() {
+            let _ = self.sftp.unlink(&tmp);
         }
-        Ok(())
-    }
-    pub fn sftp_delete(&mut self, filename: &str) -> Result<(), String> {
-        println!("deleting {filename}");
-        
-        let path = Path::new(filename);
-        let sftp = self.sftp.as_ref().unwrap();
-        let stat = match sftp.lstat(path) {
-            Err(e) => return Err(format!("{filename}: {e}")),
-            Ok(o) => o,
-        };
-        if stat.file_type().is_symlink() || stat.file_type().is_file() {
-            //println!("{filename} is file or link");        
-            match sftp.unlink(path) {
-                Err(e) => Err(format!("Cannot delete {filename}: {e}")),
-                Ok(_) => Ok(())
-            }    
-        } else {
-            //println!("file is folder: {filename}");
-            let files: Vec<(PathBuf, FileStat)> = match sftp.readdir(path) {
-                Err(e) => return Err(format!("Cannot read directory {filename}: {e}")),
-                Ok(o) => o
-            };
-            //println!("files in: {filename}: {}: {:?}", files.len(), files);                
-            if files.len() > 0 {
-               for (f,_) in files {
-                    if let Err(e) = self.sftp_delete(f.clone().to_str().unwrap()) {
-                        return Err(format!("Cannot delete directory {filename}: {e}"));
-                    }
+        result
+    }
+
+    fn upload_file(&self, local: &Path, remote: &Path, p: &mut Progress, progress: &mut dyn FnMut(&Progress)) -> Result<()> {
+        let mut file = fs::File::open(local).map_err(io_error("open", local))?;
+        p.path = local.to_path_buf();
+        let mode = local_mode(&file);
+        self.put(&mut file, remote, mode, p, progress)
+    }
+
+    fn download_file(
+        &self,
+        remote: &Path,
+        stat: &FileStat,
+        local: &Path,
+        p: &mut Progress,
+        progress: &mut dyn FnMut(&Progress),
+    ) -> Result<()> {
+        p.path = remote.to_path_buf();
+        let mut file = self.sftp.open(remote).map_err(sftp_error("open", remote))?;
+        let tmp = temp_name(local);
+        let result = (|| {
+            let mut out = fs::File::create(&tmp).map_err(io_error("create", &tmp))?;
+            let mut buf = vec![0u8; CHUNK];
+            loop {
+                let n = file.read(&mut buf).map_err(io_error("read", remote))?;
+                if n == 0 {
+                    break;
                 }
+                out.write_all(&buf[..n]).map_err(io_error("write", &tmp))?;
+                p.bytes += n as u64;
+                progress(p);
             }
-            println!("rmdir folder: {filename}");
-            match self.sftp.as_ref().unwrap().rmdir(path) {
-                Err(e) => return Err(format!("Cannot delete directory {filename}: {e}")),
-                Ok(_) => Ok(()),
+            out.sync_all().map_err(io_error("write", &tmp))?;
+            #[cfg(unix)]
+            if let Some(perm) = stat.perm {
+                use std::os::unix::fs::PermissionsExt;
+                fs::set_permissions(&tmp, fs::Permissions::from_mode(perm & 0o7777)).map_err(io_error("chmod", &tmp))?;
+            }
+            #[cfg(not(unix))]
+            let _ = stat;
+            fs::rename(&tmp, local).map_err(io_error("rename", &tmp))
+        })();
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        result
+    }
+
+    /// Regular files under `dir`, with their attributes.
+    fn remote_tree(&self, dir: &Path) -> Result<Vec<(PathBuf, FileStat)>> {
+        let mut files = Vec::new();
+        for (path, stat) in self.sftp.readdir(dir).map_err(sftp_error("readdir", dir))? {
+            if stat.is_dir() {
+                files.extend(self.remote_tree(&path)?);
+            } else if stat.is_file() {
+                files.push((path, stat));
+            } else {
+                log::debug!("Skipping {}: not a regular file", path.display());
             }
         }
-    }
-    pub fn sftp_readdir(&mut self, dirname: &str) 
-    -> Result<Vec<(PathBuf, FileStat)>, String> {
-        let path = Path::new(dirname);
-        let files: Vec<(PathBuf, FileStat)> = match self.sftp.as_ref().unwrap().readdir(path) {
-            Err(e) => return Err(format!("Cannot read directory {dirname}: {e}")),
-            Ok(o) => o
-        };
         Ok(files)
     }
-    pub fn sftp_readlink(&mut self, filename: &str) -> Result<String, String> {
-        let path = Path::new(filename);
-        let destination = match self.sftp.as_ref().unwrap().readlink(path) {
-            Err(e) => return Err(format!("Cannot read path {filename}: {e}")),
-            Ok(o) => o
-        };
-        Ok(String::from(destination.to_string_lossy()))
-    }
-    pub fn sftp_realpath(&mut self, filename: &str) -> Result<(String, FileStat), String> {
-        let path = Path::new(filename);
-        let sftp = self.sftp.as_ref().unwrap();
-        let destination = match self.sftp.as_ref().unwrap().realpath(path) {
-            Err(e) => return Err(format!("Cannot read real path {filename}: {e}")),
-            Ok(o) => o
-        };
-        let stat = match sftp.stat(&destination) {
-            Err(e) => return Err(format!("Cannot stat {filename}: {e}")),
-            Ok(o) => o
-        };
-        Ok((String::from(destination.to_string_lossy()), stat))
-    }
-    pub fn sftp_save(&mut self, filename: &str, data: &str) -> Result<(), String> {
-        let mut f = match self.sftp.as_ref().unwrap().create(Path::new(filename)) {
-            Err(e) => return Err(format!("Cannot create file {filename}: {e}")),
-            Ok(o) => o,
-        };
-        f.write_all(data.as_bytes()).expect("Cannot write data");
-        Ok(())
+}
+
+/// Append whatever `stream` has now to `out`; whether there was anything.
+fn read_some(stream: &mut impl Read, buf: &mut [u8], out: &mut Vec<u8>) -> io::Result<bool> {
+    match stream.read(buf) {
+        Ok(n) => {
+            out.extend_from_slice(&buf[..n]);
+            Ok(n > 0)
+        }
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e),
     }
 }
 
+/// Regular files under `dir`, with their sizes.
+fn local_tree(dir: &Path) -> Result<Vec<(PathBuf, u64)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir).map_err(io_error("readdir", dir))? {
+        let path = entry.map_err(io_error("readdir", dir))?.path();
+        let meta = local_metadata(&path)?;
+        if meta.is_dir() {
+            files.extend(local_tree(&path)?);
+        } else if meta.is_file() {
+            files.push((path, meta.len()));
+        }
+    }
+    Ok(files)
+}
+
+fn local_metadata(path: &Path) -> Result<fs::Metadata> {
+    fs::metadata(path).map_err(io_error("stat", path))
+}
+
+#[cfg(unix)]
+fn local_mode(file: &fs::File) -> i32 {
+    use std::os::unix::fs::PermissionsExt;
+    file.metadata().map(|m| (m.permissions().mode() & 0o7777) as i32).unwrap_or(0o644)
+}
+
+#[cfg(not(unix))]
+fn local_mode(_: &fs::File) -> i32 {
+    0o644
+}
+
+/// A name next to `path` for writing it out before the rename.
+fn temp_name(path: &Path) -> PathBuf {
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    path.with_file_name(format!(".{name}.tmp{}", std::process::id()))
+}
+
+fn shell_quote(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', r"'\''"))
+}
+
+/// SFTP status codes, from the server
+const FX_NO_SUCH_FILE: i32 = 2;
+const FX_OP_UNSUPPORTED: i32 = 8;
+
+fn is_not_found(e: &ssh2::Error) -> bool {
+    e.code() == ssh2::ErrorCode::SFTP(FX_NO_SUCH_FILE)
+}
+
+fn is_unsupported(e: &ssh2::Error) -> bool {
+    e.code() == ssh2::ErrorCode::SFTP(FX_OP_UNSUPPORTED)
+}
+
+fn sftp_error(op: &'static str, path: &Path) -> impl FnOnce(ssh2::Error) -> Error {
+    let path = path.to_path_buf();
+    move |e| Error::Sftp { op, path, reason: e.message().to_string() }
+}
+
+fn io_error(op: &'static str, path: &Path) -> impl FnOnce(io::Error) -> Error {
+    let path = path.to_path_buf();
+    move |e| Error::File { op, path, error: e }
+}
 
 #[cfg(test)]
 mod tests {
-    
     use super::*;
-    use std::env;
-    const PORT: i16 = 22;
-
-    fn get_params() -> (String, String, String) {
-        let host = env::var("TEST_SSH_HOST").unwrap();
-        let user = env::var("TEST_SSH_USER").unwrap();
-        let pass = env::var("TEST_SSH_PASS").unwrap();
-        assert!(host.len()>0);
-        assert!(user.len()>0);
-        assert!(pass.len()>0);
-        (host, user, pass)
-    }
-    #[test]
-    fn connect_with_password() {
-        let mut ssh = Ssh::new();
-        let (host, user, pass) = get_params();
-        let r = ssh.connect_with_password(&host, PORT, &user, &pass);
-        assert!(r.is_ok());
-    }
-    #[test]
-    fn connect_with_password_wrong() {
-        let mut ssh = Ssh::new();
-        let (host, user, _) = get_params();
-        let r = ssh.connect_with_password(&host, PORT, &user, "wrong");
-        assert!(r.is_err());
-    }
-    #[test]
-    fn connect_with_key() {
-        let mut ssh = Ssh::new();
-        let (host, user, _) = get_params();
-        let pkey = Ssh::private_key_path();
-        let pkey = pkey.to_str().unwrap();
-        let r = ssh.connect_with_key(&host, PORT, &user, &pkey);
-        assert!(r.is_ok());
-    }
-    #[test]
-    fn connect_with_key_wrong() {
-        let mut ssh = Ssh::new();
-        let (host, user, _) = get_params();
-        let r = ssh.connect_with_key(&host, PORT, &user, "/invalid/key");
-        assert!(r.is_err());
-    }
-    #[test]
-    fn connect_with_host_wrong() {
-        let mut ssh = Ssh::new();
-        let (_, user, pass) = get_params();
-        let r = ssh.connect_with_password("example.com", PORT, &user, &pass);
-        assert!(r.is_err());
-    }
-    #[test]
-    fn run_command() {
-        let mut ssh = Ssh::new();
-        let (host, user, pass) = get_params();
-        let r = ssh.connect_with_password(&host, PORT, &user, &pass);
-        assert!(r.is_ok());
-        let output = ssh.run("whoami").unwrap();
-        assert_eq!("support", output.as_str());
-    }
-    #[test]
-    fn has_private_key() {
-        assert!(Ssh::has_private_key());
-    }
-    #[test]
-    #[ignore = "makes ssh keys unavailable for other tests to pass"]
-    fn generate_keys() {
-        let seckey = Ssh::private_key_path();
-        let secbak = PathBuf::from(seckey.to_string_lossy().to_string() + ".bak");
-        let pubkey = Ssh::public_key_path();
-        let pubbak = PathBuf::from(pubkey.to_string_lossy().to_string() + ".bak");
-
-        // backup keys
-        if seckey.exists() {
-            std::fs::rename(&seckey, &secbak).unwrap();
-        }
-        if pubkey.exists() {
-            std::fs::rename(&pubkey, &pubbak).unwrap();
-        }
-        assert!(!Ssh::has_private_key());
-        assert!(!Ssh::has_public_key());
-
-        assert!(Ssh::generate_keys().is_ok()); 
-        assert!(Ssh::generate_public_key().is_ok());  
-        assert!(Ssh::has_private_key());
-        assert!(Ssh::has_public_key());
-
-        // restore keys
-        if secbak.exists() {
-            std::fs::rename(&secbak, &seckey).unwrap();
-        }
-        if pubbak.exists() {
-            std::fs::rename(&pubbak, &pubkey).unwrap();
-        }
-        
-    }
-    
-    #[test]
-    fn setup_ssh() {
-        let (host, user, pass) = get_params();
-        assert!(Ssh::setup_ssh(&host, PORT, &user, &pass).is_ok());
-    }
-
-    #[test]
-    fn supported_algs() {
-        println!("{}",Ssh::supported_algs());
-    }
-    #[test]
-    fn mkdir_rmdir() {
-        let mut ssh = Ssh::new();
-        let (host, user, pass) = get_params();
-        let r = ssh.connect_with_password(&host, PORT, &user, &pass);
-        assert!(r.is_ok());
-        assert!(ssh.sftp_stat( "/home/support").is_ok());
-        assert!(ssh.sftp_stat( "/home/support/folder").is_err());
-        assert!(ssh.sftp_mkdir("/home/support/folder").is_ok());
-        assert!(ssh.sftp_stat( "/home/support/folder").is_ok());
-        assert!(ssh.sftp_rmdir("/home/support/folder").is_ok());
-        assert!(ssh.sftp_stat( "/home/support/folder").is_err());
-        
-    }
-    #[test]
-    fn create_delete() {
-        let mut ssh = Ssh::new();
-        let (host, user, pass) = get_params();
-        let r = ssh.connect_with_password(&host, PORT, &user, &pass);
-        assert!(r.is_ok());
-        assert!(ssh.sftp_stat( "/home/support").is_ok());
-        assert!(ssh.sftp_stat( "/home/support/file").is_err());
-        assert!(ssh.sftp_create("/home/support/file").is_ok());
-        assert!(ssh.sftp_stat( "/home/support/file").is_ok());
-        assert!(ssh.sftp_delete("/home/support/file").is_ok());
-        assert!(ssh.sftp_stat( "/home/support/file").is_err());
-
-        assert!(ssh.sftp_mkdir( "/home/support/dir").is_ok());
-        assert!(ssh.sftp_stat( "/home/support/dir").is_ok());
-        assert!(ssh.sftp_create("/home/support/dir/file").is_ok());
-        assert!(ssh.sftp_stat( "/home/support/dir/file").is_ok());
-        assert!(ssh.sftp_delete( "/home/support/dir").is_ok());
-        assert!(ssh.sftp_stat( "/home/support/dir").is_err());
-        
-    }
-    #[test]
-    fn readdir() {
-        let mut ssh = Ssh::new();
-        let (host, user, pass) = get_params();        
-        let r = ssh.connect_with_password(&host, PORT, &user, &pass);
-        assert!(r.is_ok());
-        assert!(ssh.sftp_stat( "/home/support").is_ok());
-        let files = ssh.sftp_readdir("/").unwrap();
-        assert!(files.len() > 0);
-        // for f in files  {
-        //     println!("{:?}", f);
-        // }
-    }
-    #[test]
-    fn rename() {
-        let mut ssh = Ssh::new();
-        let (host, user, pass) = get_params();
-        let r = ssh.connect_with_password(&host, PORT, &user, &pass);
-        assert!(r.is_ok());
-        assert!(ssh.sftp_stat( "/home/support").is_ok());
-        assert!(ssh.sftp_stat( "/home/support/file").is_err());
-        assert!(ssh.sftp_create("/home/support/file").is_ok());
-        assert!(ssh.sftp_stat( "/home/support/file").is_ok());
-        assert!(ssh.sftp_rename("/home/support/file","/home/support/file1").is_ok());
-        assert!(ssh.sftp_stat( "/home/support/file").is_err());
-        assert!(ssh.sftp_stat( "/home/support/file1").is_ok());
-        assert!(ssh.sftp_delete( "/home/support/file1").is_ok());
-        assert!(ssh.sftp_stat( "/home/support/file1").is_err());
-    }
-}
\ No newline at end of file
+    use crate::connect::Auth;
+    use crate::test_server::TestServer;
+
+    /// A directory in the server's scratch home, not created yet.
+    async fn scratch(ssh: &Ssh, name: &str) -> PathBuf {
+        ssh.real_path(".").await.unwrap().join(name)
+    }
+
+    #[tokio::test]
+    #[ignore = "needs sshd"]
+    async fn logins() {
+        let server = TestServer::start();
+        let ssh = Ssh::connect(server.options()).await.unwrap();
+        assert!(ssh.auth_method().starts_with("publickey"));
+
+        let mut opts = server.options();
+        opts.auth = Auth::Password("wrong".to_string());
+        assert!(matches!(Ssh::connect(opts).await, Err(Error::Auth { .. })));
+        // A key the server does not know
+        let mut opts = server.options();
+        opts.auth = Auth::KeyFile { path: server.dir.join("host_key"), passphrase: None };
+        assert!(matches!(Ssh::connect(opts).await, Err(Error::Auth { .. })));
+        let mut opts = server.options();
+        opts.auth = Auth::KeyFile { path: "/invalid/key".into(), passphrase: None };
+        assert!(matches!(Ssh::connect(opts).await, Err(Error::KeyNotFound(_))));
+
+        let mut opts = server.options();
+        opts.host = "tunnel-test.invalid".to_string();
+        assert!(matches!(Ssh::connect(opts).await, Err(Error::Connect { .. })));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs sshd"]
+    async fn run_command() {
+        let server = TestServer::start();
+        let opts = server.options();
+        let user = opts.user.clone();
+        let ssh = Ssh::connect(opts).await.unwrap();
+        let out = ssh.run("whoami; echo oops >&2; exit 3").await.unwrap();
+        assert_eq!(out.stdout.trim(), user);
+        assert_eq!(out.stderr.trim(), "oops");
+        assert_eq!(out.status, 3);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs sshd"]
+    async fn directories() {
+        let server = TestServer::start();
+        let ssh = Ssh::connect(server.options()).await.unwrap();
+        let dir = scratch(&ssh, "dirs").await;
+        ssh.mkdir(&dir, 0o755).await.unwrap();
+        assert!(ssh.stat(&dir).await.unwrap().unwrap().is_dir());
+        ssh.rmdir(&dir).await.unwrap();
+        assert!(ssh.stat(&dir).await.unwrap().is_none());
+
+        let deep = dir.join("a/b/c");
+        ssh.mkdir_all(&deep, 0o755).await.unwrap();
+        ssh.mkdir_all(&deep, 0o755).await.unwrap();
+        ssh.write_atomic(deep.join("file"), b"x".to_vec(), 0o644).await.unwrap();
+        assert_eq!(ssh.read_dir(&dir).await.unwrap().len(), 1);
+        ssh.remove(&dir).await.unwrap();
+        assert!(ssh.stat(&dir).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs sshd"]
+    async fn atomic_writes_and_renames() {
+        let server = TestServer::start();
+        let ssh = Ssh::connect(server.options()).await.unwrap();
+        let dir = scratch(&ssh, "files").await;
+        ssh.mkdir(&dir, 0o755).await.unwrap();
+        let (a, b) = (dir.join("a"), dir.join("b"));
+
+        ssh.write_atomic(&a, b"first".to_vec(), 0o600).await.unwrap();
+        ssh.write_atomic(&a, b"second".to_vec(), 0o600).await.unwrap();
+        assert_eq!(ssh.read(&a).await.unwrap(), b"second");
+        assert_eq!(ssh.stat(&a).await.unwrap().unwrap().perm.unwrap() & 0o777, 0o600);
+
+        ssh.write_atomic(&b, b"old".to_vec(), 0o644).await.unwrap();
+        ssh.rename(&a, &b).await.unwrap();
+        assert!(ssh.stat(&a).await.unwrap().is_none());
+        assert_eq!(ssh.read(&b).await.unwrap(), b"second");
+        // Only b is left: no temporaries behind.
+        assert_eq!(ssh.read_dir(&dir).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs sshd"]
+    async fn upload_and_download_trees() {
+        let server = TestServer::start();
+        let ssh = Ssh::connect(server.options()).await.unwrap();
+        let remote = scratch(&ssh, "tree").await;
+        let local = server.dir.join("local");
+        fs::create_dir_all(local.join("src/sub")).unwrap();
+        let big: Vec<u8> = (0..300_000u32).map(|i| i as u8).collect();
+        fs::write(local.join("src/big"), &big).unwrap();
+        fs::write(local.join("src/sub/small"), b"small").unwrap();
+
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = Arc::clone(&seen);
+        let sent = ssh.upload_dir(local.join("src"), &remote, move |p| log.lock().unwrap().push(p.bytes)).await.unwrap();
+        assert_eq!(sent, big.len() as u64 + 5);
+        let seen = seen.lock().unwrap().clone();
+        assert!(seen.windows(2).all(|w| w[0] < w[1]) && seen.last() == Some(&sent));
+
+        let got = ssh.download_dir(&remote, local.join("dst"), |_| {}).await.unwrap();
+        assert_eq!(got, sent);
+        assert_eq!(fs::read(local.join("dst/big")).unwrap(), big);
+        assert_eq!(fs::read(local.join("dst/sub/small")).unwrap(), b"small");
+
+        ssh.download(remote.join("sub/small"), local.join("one"), |_| {}).await.unwrap();
+        assert_eq!(fs::read(local.join("one")).unwrap(), b"small");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs sshd"]
+    async fn authorized_keys() {
+        let server = TestServer::start();
+        let ssh = Ssh::connect(server.options()).await.unwrap();
+        let key = server.dir.join("new_key");
+        let keygen = std::process::Command::new("ssh-keygen")
+            .args(["-q", "-t", "ed25519", "-N", "", "-C", "tunnel-test", "-f"])
+            .arg(&key)
+            .status()
+            .unwrap();
+        assert!(keygen.success());
+        let public = fs::read_to_string(key.with_extension("pub")).unwrap();
+
+        let mut opts = server.options();
+        opts.auth = Auth::KeyFile { path: key, passphrase: None };
+        assert!(matches!(Ssh::connect(opts.clone()).await, Err(Error::Auth { .. })));
+
+        // Lands in the scratch home, which the server reads keys from too.
+        ssh.authorize_key(&public).await.unwrap();
+        ssh.authorize_key(&public).await.unwrap();
+        let path = ssh.real_path(".").await.unwrap().join(".ssh/authorized_keys");
+        let keys = String::from_utf8(ssh.read(&path).await.unwrap()).unwrap();
+        assert_eq!(keys.matches(public.trim()).count(), 1);
+        assert!(Ssh::connect(opts).await.is_ok());
+    }
+}
@@ -0,0 +1,345 @@
+//! `~/.ssh/config`: the subset that decides where and as whom we connect.
+//! As in ssh, the first value found for a keyword wins (IdentityFile
+//! accumulates), and `Host` blocks apply when any pattern matches the name
+//! given on the command line.
+
+use std::path::{Path, PathBuf};
+
+use crate::connect::{Auth, ConnectOptions};
+use crate::error::{Error, Result};
+use crate::known_hosts::glob;
+
+/// Jump hosts deeper than this are taken to be a loop.
+const MAX_JUMPS: usize = 8;
+
+/// Settings for one host, after all matching blocks are applied.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HostConfig {
+    pub host_name: Option<String>,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    pub identity_files: Vec<String>,
+    /// `[user@]host[:port]` hops, first one first; empty for a direct connection
+    pub proxy_jump: Vec<String>,
+    pub server_alive_interval: Option<u32>,
+    pub server_alive_count_max: Option<u32>,
+}
+
+#[derive(Debug)]
+struct Block {
+    /// None for settings before the first Host line, which apply to every host
+    patterns: Option<Vec<String>>,
+    settings: Vec<(String, String)>,
+}
+
+#[derive(Debug, Default)]
+pub struct SshConfig {
+    blocks: Vec<Block>,
+}
+
+impl SshConfig {
+    /// ~/.ssh/config, or nothing if there is none.
+    pub fn load_default() -> Self {
+        let Some(path) = dirs::home_dir().map(|h| h.join(".ssh/config")) else { return Self::default() };
+        let mut config = Self::default();
+        config.include(&path, 0);
+        config
+    }
+
+    pub fn parse(text: &str) -> Self {
+        let mut config = Self::default();
+        config.parse_into(text, 0);
+        config
+    }
+
+    fn include(&mut self, path: &Path, depth: usize) {
+        match std::fs::read_to_string(path) {
+            Ok(text) => self.parse_into(&text, depth),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => log::warn!("{}: {e}", path.display()),
+        }
+    }
+
+    fn parse_into(&mut self, text: &str, depth: usize) {
+        for line in text.lines() {
+            let Some((key, value)) = split_line(line) else { continue };
+            match key.as_str() {
+                "host" => self.blocks.push(Block {
+                    patterns: Some(value.split_whitespace().map(|p| p.to_lowercase()).collect()),
+                    settings: Vec::new(),
+                }),
+                // Match conditions are not supported; only `Match all` applies.
+                "match" => self.blocks.push(Block {
+                    patterns: Some(if value.eq_ignore_ascii_case("all") { vec!["*".to_string()] } else { Vec::new() }),
+                    settings: Vec::new(),
+                }),
+                "include" if depth < 16 => {
+                    for file in value.split_whitespace() {
+                        for path in include_paths(file) {
+                            self.include(&path, depth + 1);
+                        }
+                    }
+                }
+                _ => {
+                    if self.blocks.is_empty() {
+                        self.blocks.push(Block { patterns: None, settings: Vec::new() });
+                    }
+                    self.blocks.last_mut().expect("just pushed").settings.push((key, value));
+                }
+            }
+        }
+    }
+
+    /// Settings for `host` as typed by the user (an alias or a real name).
+    pub fn host(&self, host: &str) -> HostConfig {
+        let name = host.to_lowercase();
+        let mut c = HostConfig::default();
+        for block in self.blocks.iter().filter(|b| b.patterns.as_ref().is_none_or(|p| matches(p, &name))) {
+            for (key, value) in &block.settings {
+                match key.as_str() {
+                    "hostname" if c.host_name.is_none() => c.host_name = Some(value.replace("%h", host)),
+                    "user" if c.user.is_none() => c.user = Some(value.clone()),
+                    "port" if c.port.is_none() => c.port = value.parse().ok(),
+                    "identityfile" => c.identity_files.push(value.clone()),
+                    "proxyjump" if c.proxy_jump.is_empty() => {
+                        // "none" is recorded too, so later blocks cannot add a jump
+                        c.proxy_jump = value.split(',').map(|j| j.trim().to_string()).collect();
+                    }
+                    "serveraliveinterval" if c.server_alive_interval.is_none() => c.server_alive_interval = value.parse().ok(),
+                    "serveralivecountmax" if c.server_alive_count_max.is_none() => c.server_alive_count_max = value.parse().ok(),
+                    _ => {}
+                }
+            }
+        }
+        if c.proxy_jump == ["none"] {
+            c.proxy_jump.clear();
+        }
+        c
+    }
+
+    /// Connection options for `host` as ssh would use them: `user` and `port`
+    /// from the command line win over the config, which wins over defaults.
+    /// Jump hosts are resolved the same way; the first hop may have jumps of
+    /// its own, as with `ssh -J`.
+    pub fn options(&self, host: &str, user: Option<&str>, port: Option<u16>) -> Result<ConnectOptions> {
+        self.options_at(host, user, port, true, 0)
+    }
+
+    fn options_at(&self, host: &str, user: Option<&str>, port: Option<u16>, jumps: bool, depth: usize) -> Result<ConnectOptions> {
+        if depth > MAX_JUMPS {
+            return Err(Error::Config(format!("more than {MAX_JUMPS} jump hosts reaching {host}; is ProxyJump looping?")));
+        }
+        let c = self.host(host);
+        let user = match user.map(str::to_string).or(c.user.clone()) {
+            Some(u) => u,
+            None => local_user().ok_or_else(|| Error::Config(format!("no user for {host}; use user@{host}")))?,
+        };
+        let real_host = c.host_name.clone().unwrap_or_else(|| host.to_string());
+
+        let mut opts = ConnectOptions::new(&real_host, &user);
+        opts.port = port.or(c.port).unwrap_or(22);
+        opts.auth = Auth::Auto;
+        opts.identity_files = c
+            .identity_files
+            .iter()
+            .map(|f| expand_path(f, &real_host, &user))
+            .collect();
+        if let Some(secs) = c.server_alive_interval {
+            opts.keepalive_interval = secs;
+        }
+        if let Some(count) = c.server_alive_count_max {
+            opts.keepalive_count_max = count.max(1);
+        }
+
+        if jumps {
+            // a,b,c: reach c through b through a; only `a` uses its own ProxyJump
+            let mut via: Option<ConnectOptions> = None;
+            for (i, hop) in c.proxy_jump.iter().enumerate() {
+                let (hop_user, hop_host, hop_port) = parse_hop(hop)?;
+                let mut hop_opts = self.options_at(&hop_host, hop_user.as_deref(), hop_port, i == 0, depth + 1)?;
+                if let Some(prev) = via.take() {
+                    hop_opts.proxy_jump = Some(Box::new(prev));
+                }
+                via = Some(hop_opts);
+            }
+            opts.proxy_jump = via.map(Box::new);
+        }
+        Ok(opts)
+    }
+}
+
+/// `Keyword value`, `Keyword=value` or `Keyword = "value"`, keyword lowercased.
+fn split_line(line: &str) -> Option<(String, String)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let split = line.find(|c: char| c.is_whitespace() || c == '=')?;
+    let (key, rest) = line.split_at(split);
+    let value = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '=').trim();
+    let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+    Some((key.to_lowercase(), value.to_string()))
+}
+
+fn matches(patterns: &[String], name: &str) -> bool {
+    let mut matched = false;
+    for p in patterns {
+        match p.strip_prefix('!') {
+            Some(neg) if glob(neg, name) => return false,
+            Some(_) => {}
+            None => matched |= glob(p, name),
+        }
+    }
+    matched
+}
+
+/// `[user@]host[:port]` (or `[user@][v6addr]:port`) from a ProxyJump list.
+fn parse_hop(hop: &str) -> Result<(Option<String>, String, Option<u16>)> {
+    let bad = || Error::Config(format!("bad ProxyJump entry: {hop}"));
+    let (user, rest) = match hop.rsplit_once('@') {
+        Some((u, r)) => (Some(u.to_string()), r),
+        None => (None, hop),
+    };
+    let (host, port) = match rest.strip_prefix('[') {
+        Some(r) => {
+            let (h, tail) = r.split_once(']').ok_or_else(bad)?;
+            (h, tail.strip_prefix(':'))
+        }
+        None => match rest.split_once(':') {
+            Some((h, p)) => (h, Some(p)),
+            None => (rest, None),
+        },
+    };
+    if host.is_empty() {
+        return Err(bad());
+    }
+    let port = port.map(|p| p.parse().map_err(|_| bad())).transpose()?;
+    Ok((user, host.to_string(), port))
+}
+
+/// Files named by an Include line: relative paths are under ~/.ssh, and a
+/// `*` in the file name matches like the shell.
+fn include_paths(file: &str) -> Vec<PathBuf> {
+    let home = dirs::home_dir().unwrap_or_default();
+    let path = match file.strip_prefix("~/") {
+        Some(rest) => home.join(rest),
+        None if Path::new(file).is_absolute() => PathBuf::from(file),
+        None => home.join(".ssh").join(file),
+    };
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    if !name.contains(['*', '?']) {
+        return vec![path];
+    }
+    let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+    let mut found: Vec<PathBuf> = std::fs::read_dir(&dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|e| glob(&name, &e.file_name().to_string_lossy()))
+        .map(|e| e.path())
+        .collect();
+    found.sort();
+    found
+}
+
+/// `~` and the tokens ssh allows in IdentityFile: %d %h %r %u %%.
+fn expand_path(path: &str, host: &str, user: &str) -> PathBuf {
+    let home = dirs::home_dir().unwrap_or_default();
+    let mut out = String::new();
+    let mut chars = path.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('%', Some('d')) => out.push_str(&home.to_string_lossy()),
+            ('%', Some('h')) => out.push_str(host),
+            ('%', Some('r')) => out.push_str(user),
+            ('%', Some('u')) => out.push_str(&local_user().unwrap_or_default()),
+            ('%', Some('%')) => out.push('%'),
+            _ => {
+                out.push(c);
+                continue;
+            }
+        }
+        chars.next();
+    }
+    match out.strip_prefix("~/") {
+        Some(rest) => home.join(rest),
+        None => PathBuf::from(out),
+    }
+}
+
+pub(crate) fn local_user() -> Option<String> {
+    std::env::var("USER").or_else(|_| std::env::var("USERNAME")).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "\
+# defaults first, as most configs have them at the end
+Host bastion
+    HostName bastion.example.com
+    User jump
+    Port 2222
+
+Host inner
+    HostName 10.1.2.3
+    ProxyJump bastion
+
+Host app-*  !app-legacy
+    ProxyJump bastion,ops@inner:2200
+    IdentityFile ~/.ssh/%h_key
+    ServerAliveInterval=15
+    ServerAliveCountMax 2
+
+Host *
+    User = \"me\"
+    IdentityFile ~/.ssh/id_ed25519
+";
+
+    #[test]
+    fn host_blocks() {
+        let config = SshConfig::parse(CONFIG);
+        let c = config.host("bastion");
+        assert_eq!(c.host_name.as_deref(), Some("bastion.example.com"));
+        assert_eq!(c.user.as_deref(), Some("jump"));
+        assert_eq!(c.port, Some(2222));
+        assert_eq!(c.identity_files, ["~/.ssh/id_ed25519"]);
+
+        let c = config.host("app-web");
+        assert_eq!(c.user.as_deref(), Some("me"));
+        assert_eq!(c.proxy_jump, ["bastion", "ops@inner:2200"]);
+        assert_eq!(c.server_alive_interval, Some(15));
+        assert_eq!(c.identity_files, ["~/.ssh/%h_key", "~/.ssh/id_ed25519"]);
+
+        assert!(config.host("app-legacy").proxy_jump.is_empty());
+    }
+
+    #[test]
+    fn jump_chain() {
+        let config = SshConfig::parse(CONFIG);
+        let opts = config.options("app-web", None, Some(22)).unwrap();
+        assert_eq!((opts.host.as_str(), opts.user.as_str(), opts.port), ("app-web", "me", 22));
+        assert_eq!((opts.keepalive_interval, opts.keepalive_count_max), (15, 2));
+
+        // app-web <- inner (as ops, port 2200, its own ProxyJump ignored) <- bastion
+        let inner = opts.proxy_jump.as_deref().unwrap();
+        assert_eq!((inner.host.as_str(), inner.user.as_str(), inner.port), ("10.1.2.3", "ops", 2200));
+        let bastion = inner.proxy_jump.as_deref().unwrap();
+        assert_eq!((bastion.host.as_str(), bastion.user.as_str(), bastion.port), ("bastion.example.com", "jump", 2222));
+        assert!(bastion.proxy_jump.is_none());
+
+        // The first hop uses its own config, jumps included.
+        let opts = config.options("x", Some("root"), None).unwrap();
+        assert_eq!(opts.user, "root");
+        assert!(opts.proxy_jump.is_none());
+        let via = SshConfig::parse("Host x\n  ProxyJump inner\nHost *\n  User u\n").options("x", None, None).unwrap();
+        assert_eq!(via.proxy_jump.as_deref().unwrap().host, "inner");
+    }
+
+    #[test]
+    fn jump_loops_are_refused() {
+        let config = SshConfig::parse("Host a\n  ProxyJump b\nHost b\n  ProxyJump a\n");
+        assert!(matches!(config.options("a", Some("u"), None), Err(Error::Config(_))));
+    }
+}
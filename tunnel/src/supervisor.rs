@@ -0,0 +1,346 @@
+//! The session behind an [`SshTunnel`](crate::SshTunnel), and the supervisor
+//! that makes sure it still answers. When it stops answering the supervisor
+//! logs in again, jump hosts and all, with backoff, and sets up the remote
+//! listeners again on the new session; local listeners never went away.
+
+use std::sync::{Arc, Mutex};
+
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, watch},
+    time::{sleep, timeout, Duration},
+};
+
+use crate::connect::{open_session, ConnectOptions, Login};
+use crate::driver::{Accepted, Driver, Open};
+use crate::error::{Error, Result};
+use crate::tunnel::{splice, Target};
+
+const BACKOFF_MIN: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+/// libssh2's LIBSSH2_ERROR_CHANNEL_FAILURE: the server turned a channel down
+const CHANNEL_FAILURE: i32 = -21;
+
+/// Where a tunnel's session stands; see [`SshTunnel::status`](crate::SshTunnel::status).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Status {
+    /// Logged in, and answering keepalives
+    Connected,
+    /// The session stopped answering and is being replaced. `error` is why
+    /// the previous attempt failed, if there was one.
+    Reconnecting { attempt: u32, error: Option<String> },
+    /// Logging in again cannot work (e.g. the host key changed, or the login
+    /// was refused); nothing is forwarded any more
+    Failed(String),
+    /// Shut down
+    Closed,
+}
+
+/// One logged-in session, and the jump host sessions it runs through.
+#[derive(Clone)]
+pub(crate) struct Connection {
+    pub(crate) driver: Arc<Driver>,
+    pub(crate) auth_method: String,
+    via: Option<Arc<Connection>>,
+}
+
+impl Connection {
+    /// Log in to `opts.host`. With [`ConnectOptions::proxy_jump`] the jump
+    /// hosts are logged into first and each session runs over a direct-tcpip
+    /// channel of the one before.
+    pub(crate) async fn open(mut opts: ConnectOptions) -> Result<Self> {
+        let (via, transport) = through_jumps(&mut opts).await?;
+        let login = tokio::task::spawn_blocking(move || open_session(&opts, transport)).await??;
+        Self::start(login, via)
+    }
+
+    /// Drive a session that is logged in already.
+    pub(crate) fn start(login: Login, via: Option<Arc<Connection>>) -> Result<Self> {
+        let driver = Driver::start(login.session, login.socket)?;
+        Ok(Self { driver: Arc::new(driver), auth_method: login.method, via })
+    }
+
+    /// Tell the servers we are leaving, innermost first.
+    pub(crate) async fn close(&self) {
+        if timeout(Duration::from_secs(2), self.driver.disconnect()).await.is_err() {
+            log::debug!("SSH disconnect did not finish within 2s");
+        }
+        if let Some(via) = &self.via {
+            Box::pin(via.close()).await;
+        }
+    }
+}
+
+/// Log in to the jump hosts in `opts.proxy_jump` (taking them out) and get a
+/// socket through them to `opts.host`; (None, None) without jump hosts. The
+/// jump sessions must outlive the socket.
+pub(crate) async fn through_jumps(opts: &mut ConnectOptions) -> Result<(Option<Arc<Connection>>, Option<std::net::TcpStream>)> {
+    let Some(jump) = opts.proxy_jump.take() else { return Ok((None, None)) };
+    log::info!("Connecting to {}:{} through {}", opts.host, opts.port, jump.host);
+    let via = Box::pin(Connection::open(*jump)).await?;
+    let transport = relay(&via.driver, Target::Tcp { host: opts.host.clone(), port: opts.port }).await?;
+    Ok((Some(Arc::new(via)), Some(transport)))
+}
+
+/// A local socket connected to `target` through `driver`'s session. libssh2
+/// needs a real socket under the next session, so the channel is spliced to
+/// one end of a loopback TCP pair and the other end is returned.
+async fn relay(driver: &Driver, target: Target) -> Result<std::net::TcpStream> {
+    let (writer, reader) = driver.open(Open::Forward(target)).await?;
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let ours = TcpStream::connect(listener.local_addr()?).await?;
+    // Only take the connection we just made, not some other local process's.
+    let theirs = loop {
+        let (stream, peer) = listener.accept().await?;
+        if peer == ours.local_addr()? {
+            break stream;
+        }
+    };
+    theirs.set_nodelay(true)?;
+    tokio::spawn(splice(theirs, writer, reader));
+
+    let ours = ours.into_std()?;
+    ours.set_nonblocking(false)?;
+    ours.set_nodelay(true)?;
+    Ok(ours)
+}
+
+/// A remote listener, to be set up again on each new session.
+pub(crate) struct Rebind {
+    pub(crate) id: u64,
+    pub(crate) host: String,
+    /// The port the server picked the first time, so it stays the same
+    pub(crate) port: u16,
+    /// Where the new session's connections go
+    pub(crate) accepted: mpsc::UnboundedSender<Accepted>,
+}
+
+/// What a tunnel's tasks share: the session in use now, and its status.
+pub(crate) struct Link {
+    current: Mutex<Connection>,
+    pub(crate) rebinds: Mutex<Vec<Rebind>>,
+    pub(crate) status: watch::Sender<Status>,
+}
+
+impl Link {
+    pub(crate) fn new(conn: Connection) -> Self {
+        Self { current: Mutex::new(conn), rebinds: Mutex::new(Vec::new()), status: watch::channel(Status::Connected).0 }
+    }
+
+    pub(crate) fn current(&self) -> Connection {
+        self.current.lock().unwrap().clone()
+    }
+
+    /// The driver of the session in use now, to open channels on.
+    pub(crate) fn driver(&self) -> Arc<Driver> {
+        Arc::clone(&self.current.lock().unwrap().driver)
+    }
+}
+
+/// Check the session every `keepalive_interval` and replace it once
+/// `keepalive_count_max` checks in a row go unanswered, until shutdown or a
+/// failure that logging in again would not fix.
+pub(crate) async fn supervise(link: Arc<Link>, opts: ConnectOptions, mut shutdown_rx: watch::Receiver<bool>) {
+    let interval = Duration::from_secs(opts.keepalive_interval.into());
+    let mut missed = 0;
+    loop {
+        tokio::select! {
+            _ = sleep(interval) => {}
+            _ = shutdown_rx.changed() => return,
+        }
+        // Opening a channel takes a round trip, so an answer proves the whole
+        // path works, not just that our socket is open.
+        match timeout(interval, link.driver().open(Open::Ping)).await {
+            Ok(Ok(_)) => {
+                missed = 0;
+                continue;
+            }
+            // Refusing the channel is an answer too; a dead socket is not.
+            Ok(Err(Error::Channel { error, .. })) if error.code() == ssh2::ErrorCode::Session(CHANNEL_FAILURE) => {
+                missed = 0;
+                continue;
+            }
+            Ok(Err(e)) => log::warn!("SSH session to {} failed: {e}", opts.host),
+            Err(_) => {
+                missed += 1;
+                if missed < opts.keepalive_count_max {
+                    log::debug!("{} did not answer keepalive {missed}", opts.host);
+                    continue;
+                }
+                log::warn!("{} did not answer for {}s", opts.host, missed * opts.keepalive_interval);
+            }
+        }
+        missed = 0;
+        if !reconnect(&link, &opts, &mut shutdown_rx).await {
+            return;
+        }
+    }
+}
+
+/// Log in again until it works. False on shutdown or a lasting failure.
+async fn reconnect(link: &Link, opts: &ConnectOptions, shutdown_rx: &mut watch::Receiver<bool>) -> bool {
+    let mut delay = BACKOFF_MIN;
+    let mut error = None;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        link.status.send_replace(Status::Reconnecting { attempt, error: error.take() });
+        let result = tokio::select! {
+            r = Connection::open(opts.clone()) => r,
+            _ = shutdown_rx.changed() => return false,
+        };
+        match result {
+            Ok(conn) => {
+                relisten(link, &conn).await;
+                let old = std::mem::replace(&mut *link.current.lock().unwrap(), conn);
+                tokio::spawn(async move { old.close().await });
+                log::info!("Reconnected to {}", opts.host);
+                link.status.send_replace(Status::Connected);
+                return true;
+            }
+            Err(e) if lasting(&e) => {
+                log::error!("Reconnecting to {} failed: {e}", opts.host);
+                link.status.send_replace(Status::Failed(e.to_string()));
+                return false;
+            }
+            Err(e) => {
+                log::warn!("Reconnecting to {} failed: {e}; retrying in {delay:?}", opts.host);
+                error = Some(e.to_string());
+            }
+        }
+        tokio::select! {
+            _ = sleep(delay) => {}
+            _ = shutdown_rx.changed() => return false,
+        }
+        delay = (delay * 2).min(BACKOFF_MAX);
+    }
+}
+
+/// Ask the new session for the remote listeners the old one had.
+async fn relisten(link: &Link, conn: &Connection) {
+    let rebinds: Vec<_> = link
+        .rebinds
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|r| (r.id, r.host.clone(), r.port, r.accepted.clone()))
+        .collect();
+    for (id, host, port, accepted) in rebinds {
+        match conn.driver.listen(id, &host, port).await {
+            Ok((_, rx)) => {
+                let _ = accepted.send(rx);
+            }
+            Err(e) => log::warn!("Remote forward {host}:{port} could not be restored: {e}"),
+        }
+    }
+}
+
+/// Failures that trying again will not fix.
+fn lasting(e: &Error) -> bool {
+    matches!(
+        e,
+        Error::HostKey { .. }
+            | Error::HostKeyChanged { .. }
+            | Error::UnknownHost { .. }
+            | Error::KeyNotFound(_)
+            | Error::Auth { .. }
+            | Error::Config(_)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{Proxy, TestServer};
+    use crate::tunnel::{Forward, SshTunnel};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const WAIT: Duration = Duration::from_secs(20);
+
+    /// Port of a local server that echoes what it gets.
+    async fn echo_server() -> u16 {
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((conn, _)) = echo.accept().await {
+                tokio::spawn(async move {
+                    let (mut r, mut w) = conn.into_split();
+                    let _ = tokio::io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+        port
+    }
+
+    async fn echoes(port: u16) -> bool {
+        let mut conn = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        conn.write_all(b"ping").await.unwrap();
+        let mut echoed = [0u8; 4];
+        conn.read_exact(&mut echoed).await.is_ok() && &echoed == b"ping"
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "needs sshd"]
+    async fn reconnects_and_restores_remote_forwards() {
+        let server = TestServer::start();
+        let proxy = Proxy::start(server.port).await;
+        let mut opts = server.options_on(proxy.port);
+        opts.keepalive_interval = 1;
+        let mut tunnel = SshTunnel::connect(opts).await.unwrap();
+        let mut status = tunnel.status();
+
+        // here -L-> remote port -R-> echo server here
+        let echo_port = echo_server().await;
+        let remote = Forward::Remote {
+            bind: ("127.0.0.1".to_string(), 0),
+            target: Target::Tcp { host: "127.0.0.1".to_string(), port: echo_port },
+        };
+        let (_, remote_port) = tunnel.add_forward(remote).await.unwrap();
+        let local = Forward::Local {
+            bind: ("127.0.0.1".to_string(), 0),
+            target: Target::Tcp { host: "127.0.0.1".to_string(), port: remote_port },
+        };
+        let (_, local_port) = tunnel.add_forward(local).await.unwrap();
+        assert!(echoes(local_port).await);
+
+        // The connection drops, and logging in again fails for a while.
+        proxy.refuse(true);
+        proxy.cut();
+        let first = |s: &Status| matches!(s, Status::Reconnecting { attempt: 1, error: None });
+        timeout(WAIT, status.wait_for(first)).await.unwrap().unwrap();
+        let started = std::time::Instant::now();
+        let second = |s: &Status| matches!(s, Status::Reconnecting { attempt: 2, error: Some(_) });
+        timeout(WAIT, status.wait_for(second)).await.unwrap().unwrap();
+        assert!(started.elapsed() >= BACKOFF_MIN - Duration::from_millis(200));
+
+        proxy.refuse(false);
+        timeout(WAIT, status.wait_for(|s| *s == Status::Connected)).await.unwrap().unwrap();
+        // Through the new session, and back over the remote listener it set up again
+        assert!(echoes(local_port).await);
+        tunnel.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "needs sshd"]
+    async fn changed_host_key_fails_without_retrying() {
+        let server = TestServer::start();
+        let other = TestServer::start();
+        let proxy = Proxy::start(server.port).await;
+        let mut opts = server.options_on(proxy.port);
+        opts.keepalive_interval = 1;
+        let tunnel = SshTunnel::connect(opts).await.unwrap();
+        let mut status = tunnel.status();
+
+        // Same address, but another host key behind it
+        proxy.retarget(other.port);
+        proxy.cut();
+        let failed = timeout(WAIT, status.wait_for(|s| matches!(s, Status::Failed(_)))).await.unwrap().unwrap().clone();
+        assert!(matches!(&failed, Status::Failed(e) if e.contains("has changed")), "{failed:?}");
+
+        // One login for the tunnel and one attempt to replace it, no more.
+        sleep(BACKOFF_MIN * 3).await;
+        assert_eq!(proxy.accepted(), 2);
+        assert_eq!(*status.borrow(), failed);
+        let _ = tunnel.shutdown().await;
+    }
+}
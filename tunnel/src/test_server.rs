@@ -0,0 +1,170 @@
+//! A throwaway sshd for the integration tests, and a proxy in front of it
+//! that can drop connections the way a failing network does.
+
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, Ordering};
+use std::time::Duration;
+
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::connect::{Auth, ConnectOptions};
+
+/// An sshd on a loopback port that lets the current user in with a fresh
+/// key. SFTP starts in a scratch home directory, whose
+/// .ssh/authorized_keys is honoured too, so tests never touch the real one.
+/// Tests that use it are `#[ignore = "needs sshd"]`; run them with
+/// `cargo test -- --ignored`.
+pub(crate) struct TestServer {
+    child: Child,
+    pub(crate) dir: PathBuf,
+    pub(crate) port: u16,
+}
+
+impl TestServer {
+    pub(crate) fn start() -> Self {
+        let sshd = ["/usr/sbin/sshd", "/usr/local/sbin/sshd", "/usr/bin/sshd"]
+            .into_iter()
+            .map(PathBuf::from)
+            .find(|p| p.exists())
+            .expect("sshd not found");
+        // Tests run in parallel, so every server gets its own keys and config.
+        static SERVERS: AtomicU32 = AtomicU32::new(0);
+        let n = SERVERS.fetch_add(1, Ordering::Relaxed);
+        let dir = std::env::temp_dir().join(format!("tunnel-test-{}-{n}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("home")).unwrap();
+        for key in ["host_key", "user_key"] {
+            let status = Command::new("ssh-keygen")
+                .args(["-q", "-t", "ed25519", "-N", "", "-f"])
+                .arg(dir.join(key))
+                .status()
+                .unwrap();
+            assert!(status.success());
+        }
+        std::fs::copy(dir.join("user_key.pub"), dir.join("authorized_keys")).unwrap();
+
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let config = format!(
+            "Port {port}\nListenAddress 127.0.0.1\nHostKey {d}/host_key\n\
+             AuthorizedKeysFile {d}/authorized_keys {d}/home/.ssh/authorized_keys\n\
+             PidFile none\nUsePAM no\nStrictModes no\nPasswordAuthentication no\nAllowTcpForwarding yes\n\
+             Subsystem sftp internal-sftp -d {d}/home\n",
+            d = dir.display()
+        );
+        std::fs::write(dir.join("sshd_config"), config).unwrap();
+
+        let child = Command::new(sshd)
+            .args(["-D", "-e", "-f"])
+            .arg(dir.join("sshd_config"))
+            .stdin(Stdio::null())
+            .spawn()
+            .unwrap();
+        let server = TestServer { child, dir, port };
+        for _ in 0..50 {
+            if std::net::TcpStream::connect(("127.0.0.1", port)).is_ok() {
+                return server;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        panic!("sshd did not start listening");
+    }
+
+    pub(crate) fn options(&self) -> ConnectOptions {
+        self.options_on(self.port)
+    }
+
+    /// Options for reaching this server on another port, e.g. through a
+    /// [`Proxy`]; the host key is trusted there too.
+    pub(crate) fn options_on(&self, port: u16) -> ConnectOptions {
+        let host_key = std::fs::read_to_string(self.dir.join("host_key.pub")).unwrap();
+        let known_hosts = self.dir.join(format!("known_hosts-{port}"));
+        std::fs::write(&known_hosts, format!("[127.0.0.1]:{port} {host_key}")).unwrap();
+
+        let user = std::env::var("USER").unwrap_or_else(|_| "root".to_string());
+        let mut opts = ConnectOptions::new("127.0.0.1", &user);
+        opts.port = port;
+        opts.auth = Auth::KeyFile { path: self.dir.join("user_key"), passphrase: None };
+        opts.known_hosts = Some(known_hosts);
+        opts
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// A loopback TCP relay to a [`TestServer`].
+pub(crate) struct Proxy {
+    pub(crate) port: u16,
+    target: Arc<AtomicU16>,
+    refusing: Arc<AtomicBool>,
+    /// Connections taken so far, refused ones included
+    accepted: Arc<AtomicU32>,
+    cut: watch::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl Proxy {
+    pub(crate) async fn start(target: u16) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let target = Arc::new(AtomicU16::new(target));
+        let refusing = Arc::new(AtomicBool::new(false));
+        let accepted = Arc::new(AtomicU32::new(0));
+        let cut = watch::Sender::new(());
+
+        let (to, refuse, count, cuts) = (target.clone(), refusing.clone(), accepted.clone(), cut.clone());
+        let task = tokio::spawn(async move {
+            while let Ok((mut conn, _)) = listener.accept().await {
+                count.fetch_add(1, Ordering::Relaxed);
+                if refuse.load(Ordering::Relaxed) {
+                    continue;
+                }
+                let port = to.load(Ordering::Relaxed);
+                let mut cut_rx = cuts.subscribe();
+                tokio::spawn(async move {
+                    let Ok(mut upstream) = TcpStream::connect(("127.0.0.1", port)).await else { return };
+                    tokio::select! {
+                        _ = tokio::io::copy_bidirectional(&mut conn, &mut upstream) => {}
+                        _ = cut_rx.changed() => {}
+                    }
+                });
+            }
+        });
+        Proxy { port, target, refusing, accepted, cut, task }
+    }
+
+    /// Drop every connection through the proxy.
+    pub(crate) fn cut(&self) {
+        self.cut.send_replace(());
+    }
+
+    /// Close new connections right away while `refusing` is set.
+    pub(crate) fn refuse(&self, refusing: bool) {
+        self.refusing.store(refusing, Ordering::Relaxed);
+    }
+
+    /// Send new connections to another port.
+    pub(crate) fn retarget(&self, port: u16) {
+        self.target.store(port, Ordering::Relaxed);
+    }
+
+    pub(crate) fn accepted(&self) -> u32 {
+        self.accepted.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        self.task.abort();
+        self.cut();
+    }
+}
@@ -0,0 +1,602 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, watch},
+    task::JoinHandle,
+    time::{sleep, Duration},
+};
+
+use crate::channel::{ChannelReader, ChannelWriter};
+use crate::connect::{Auth, ConnectOptions};
+use crate::driver::{Accepted, Driver, Open};
+use crate::error::{Error, Result};
+use crate::exec::RemoteCommand;
+use crate::socks;
+use crate::ssh::Ssh;
+use crate::supervisor::{supervise, Connection, Link, Rebind, Status};
+
+/// What a forwarded connection is connected to on the remote side.
+#[derive(Debug, Clone)]
+pub enum Target {
+    Tcp { host: String, port: u16 },
+    /// A Unix socket on the remote host (OpenSSH's direct-streamlocal)
+    Unix(PathBuf),
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::Tcp { host, port } => write!(f, "{host}:{port}"),
+            Target::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// Names a forward of an [`SshTunnel`], for [`SshTunnel::remove_forward`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ForwardId(u64);
+
+/// A forward: where it listens and where its connections go. Port 0 in
+/// `bind` lets the listening side pick one.
+#[derive(Debug, Clone)]
+pub enum Forward {
+    /// Listen here; connections go to `target` as seen from the remote host (ssh -L)
+    Local { bind: (String, u16), target: Target },
+    /// Listen on the remote host; connections go to `target` as seen from here (ssh -R)
+    Remote { bind: (String, u16), target: Target },
+    /// A SOCKS5 proxy here; each connection goes where its client asks, as
+    /// seen from the remote host (ssh -D)
+    Dynamic { bind: (String, u16) },
+}
+
+struct Active {
+    id: ForwardId,
+    forward: Forward,
+    /// The port actually listened on
+    port: u16,
+    task: JoinHandle<()>,
+}
+
+/// SSH tunnel manager that handles the SSH session and connections. All
+/// libssh2 calls happen on the session's driver thread, so forwards and
+/// commands can open channels concurrently. A supervisor replaces the session
+/// when it stops answering; see [`SshTunnel::status`].
+pub struct SshTunnel {
+    link: Arc<Link>,
+    shutdown_tx: watch::Sender<bool>,
+    forwards: Vec<Active>,
+    next_id: u64,
+    supervisor: Option<JoinHandle<()>>,
+}
+
+impl SshTunnel {
+    /// Create a new SSH tunnel forwarding `local_bind` to `remote_dst`, authenticating
+    /// with a private key file. Returns once the local listener is up.
+    pub async fn new(
+        ssh_host: &str,
+        ssh_port: u16,
+        user: &str,
+        privkey_path: &Path,
+        local_bind: (&str, u16),
+        remote_dst: (&str, u16),
+    ) -> Result<Self> {
+        let mut opts = ConnectOptions::new(ssh_host, user);
+        opts.port = ssh_port;
+        opts.auth = Auth::KeyFile { path: privkey_path.to_path_buf(), passphrase: None };
+
+        let mut tunnel = Self::connect(opts).await?;
+        let target = Target::Tcp { host: remote_dst.0.to_string(), port: remote_dst.1 };
+        tunnel.forward(local_bind, target).await?;
+        Ok(tunnel)
+    }
+
+    /// Open and authenticate an SSH session, without any forwards yet. With
+    /// [`ConnectOptions::proxy_jump`] the jump hosts are connected first and
+    /// each session runs over a direct-tcpip channel of the one before.
+    pub async fn connect(opts: ConnectOptions) -> Result<Self> {
+        let conn = Connection::open(opts.clone()).await?;
+        Ok(Self::start(conn, opts))
+    }
+
+    /// Carry on with the session of `ssh` rather than logging in again, e.g.
+    /// after installing something over it. `opts` are the ones it was opened
+    /// with, for logging in again if it drops. Fails while clones of `ssh`
+    /// are still around.
+    pub async fn from_ssh(ssh: Ssh, opts: ConnectOptions) -> Result<Self> {
+        let conn = ssh.into_connection().await?;
+        Ok(Self::start(conn, opts))
+    }
+
+    fn start(conn: Connection, opts: ConnectOptions) -> Self {
+        let link = Arc::new(Link::new(conn));
+        let (shutdown_tx, _) = watch::channel(false);
+        let supervisor = (opts.keepalive_interval > 0 && opts.reconnect)
+            .then(|| tokio::spawn(supervise(Arc::clone(&link), opts, shutdown_tx.subscribe())));
+        Self { link, shutdown_tx, forwards: Vec::new(), next_id: 0, supervisor }
+    }
+
+    /// How the session authenticated, e.g. "publickey (ssh-agent: me@laptop)".
+    pub fn auth_method(&self) -> String {
+        self.link.current().auth_method
+    }
+
+    /// Where the session stands. The receiver sees every change, e.g. to show
+    /// that the tunnel is reconnecting.
+    pub fn status(&self) -> watch::Receiver<Status> {
+        self.link.status.subscribe()
+    }
+
+    /// Listen on `local_bind` (port 0 picks a free port) and forward each connection
+    /// to `target`. Returns the bound address once the listener is up.
+    pub async fn forward(&mut self, local_bind: (&str, u16), target: Target) -> Result<SocketAddr> {
+        let bind = (local_bind.0.to_string(), local_bind.1);
+        let (_, addr) = self.add_local(bind, Some(target)).await?;
+        Ok(addr)
+    }
+
+    /// Start a forward alongside the others on this session. Returns its id
+    /// and the port it listens on (locally or on the remote host).
+    pub async fn add_forward(&mut self, forward: Forward) -> Result<(ForwardId, u16)> {
+        match forward {
+            Forward::Local { bind, target } => {
+                let (id, addr) = self.add_local(bind, Some(target)).await?;
+                Ok((id, addr.port()))
+            }
+            Forward::Dynamic { bind } => {
+                let (id, addr) = self.add_local(bind, None).await?;
+                Ok((id, addr.port()))
+            }
+            Forward::Remote { bind, target } => {
+                let id = self.next_id();
+                let (port, accepted) = self.link.driver().listen(id.0, &bind.0, bind.1).await?;
+                log::info!("Forwarding remote {}:{port} -> {target}", bind.0);
+                let (rebind_tx, rebind_rx) = mpsc::unbounded_channel();
+                let rebind = Rebind { id: id.0, host: bind.0.clone(), port, accepted: rebind_tx };
+                self.link.rebinds.lock().unwrap().push(rebind);
+                let shutdown_rx = self.shutdown_tx.subscribe();
+                let task = tokio::spawn(serve_remote(accepted, rebind_rx, target.clone(), shutdown_rx));
+                self.forwards.push(Active { id, forward: Forward::Remote { bind, target }, port, task });
+                Ok((id, port))
+            }
+        }
+    }
+
+    /// A local listener for `target`, or for SOCKS clients without one.
+    async fn add_local(&mut self, bind: (String, u16), target: Option<Target>) -> Result<(ForwardId, SocketAddr)> {
+        let bind_addr = format!("{}:{}", bind.0, bind.1);
+        let listener = TcpListener::bind(&bind_addr).await.map_err(|error| Error::Bind { addr: bind_addr, error })?;
+        let addr = listener.local_addr()?;
+        match &target {
+            Some(target) => log::info!("Forwarding {addr} -> {target}"),
+            None => log::info!("SOCKS proxy on {addr}"),
+        }
+
+        let id = self.next_id();
+        let link = Arc::clone(&self.link);
+        let task = tokio::spawn(serve(listener, link, target.clone(), self.shutdown_tx.subscribe()));
+        let forward = match target {
+            Some(target) => Forward::Local { bind, target },
+            None => Forward::Dynamic { bind },
+        };
+        self.forwards.push(Active { id, forward, port: addr.port(), task });
+        Ok((id, addr))
+    }
+
+    fn next_id(&mut self) -> ForwardId {
+        self.next_id += 1;
+        ForwardId(self.next_id)
+    }
+
+    /// Stop listening for `id`. Connections it already made carry on until
+    /// they close. False if there is no such forward.
+    pub fn remove_forward(&mut self, id: ForwardId) -> bool {
+        let Some(i) = self.forwards.iter().position(|f| f.id == id) else { return false };
+        let active = self.forwards.remove(i);
+        // Accepting is all the task does; each connection has its own.
+        active.task.abort();
+        if let Forward::Remote { .. } = active.forward {
+            self.link.rebinds.lock().unwrap().retain(|r| r.id != id.0);
+            self.link.driver().unlisten(id.0);
+        }
+        true
+    }
+
+    /// The forwards running now, oldest first, with the port each listens on.
+    pub fn forwards(&self) -> impl Iterator<Item = (ForwardId, &Forward, u16)> {
+        self.forwards.iter().map(|f| (f.id, &f.forward, f.port))
+    }
+
+    /// Run `command` on the remote host.
+    pub async fn exec(&self, command: &str) -> Result<RemoteCommand> {
+        let (stdin, stdout) = self.link.driver().open(Open::Exec(command.to_string())).await?;
+        Ok(RemoteCommand { stdin: Some(stdin), stdout, buf: Vec::new() })
+    }
+
+    /// Shutdown the tunnel
+    pub async fn shutdown(mut self) -> Result<()> {
+        log::debug!("Shutting down tunnel");
+        let _ = self.shutdown_tx.send(true);
+
+        let tasks = self.forwards.drain(..).map(|f| f.task).chain(self.supervisor.take());
+        for task in tasks.collect::<Vec<_>>() {
+            if tokio::time::timeout(Duration::from_secs(2), task).await.is_err() {
+                log::debug!("Tunnel task did not stop within 2s");
+            }
+        }
+
+        self.link.current().close().await;
+        self.link.status.send_replace(Status::Closed);
+        Ok(())
+    }
+}
+
+impl Drop for SshTunnel {
+    fn drop(&mut self) {
+        let _ = self.shutdown_tx.send(true);
+    }
+}
+
+/// Accept local connections until shutdown. Without a `target` each client
+/// names its own over SOCKS.
+async fn serve(
+    listener: TcpListener,
+    link: Arc<Link>,
+    target: Option<Target>,
+    mut shutdown_rx: watch::Receiver<bool>,
+) {
+    loop {
+        tokio::select! {
+            result = listener.accept() => {
+                match result {
+                    Ok((client, peer)) => {
+                        log::debug!("New connection from {peer}");
+                        let driver = link.driver();
+                        let target = target.clone();
+                        tokio::spawn(async move {
+                            let result = match &target {
+                                Some(target) => handle_connection(client, &driver, target).await,
+                                None => handle_socks(client, &driver).await,
+                            };
+                            if let Err(e) = result {
+                                log::warn!("Connection from {peer}: {e}");
+                            }
+                            log::debug!("Connection from {peer} finished");
+                        });
+                    }
+                    Err(e) => {
+                        log::warn!("Accept error: {e}");
+                        sleep(Duration::from_millis(100)).await;
+                    }
+                }
+            }
+            _ = shutdown_rx.changed() => break,
+        }
+    }
+}
+
+/// Handle a single client connection
+async fn handle_connection(client: TcpStream, driver: &Driver, target: &Target) -> Result<()> {
+    let (writer, reader) = driver.open(Open::Forward(target.clone())).await?;
+    splice(client, writer, reader).await;
+    Ok(())
+}
+
+/// Handle a SOCKS client: open a channel to where it asks, then tell it how
+/// that went.
+async fn handle_socks(mut client: TcpStream, driver: &Driver) -> Result<()> {
+    let target = socks::accept(&mut client).await?;
+    log::debug!("SOCKS connect to {target}");
+    match driver.open(Open::Forward(target)).await {
+        Ok((writer, reader)) => {
+            socks::reply(&mut client, socks::SUCCEEDED).await?;
+            splice(client, writer, reader).await;
+            Ok(())
+        }
+        Err(e) => {
+            let code = match e {
+                Error::Channel { .. } => socks::CONNECTION_REFUSED,
+                _ => socks::GENERAL_FAILURE,
+            };
+            let _ = socks::reply(&mut client, code).await;
+            Err(e)
+        }
+    }
+}
+
+/// Connect what a remote listener accepts to `target` here, until shutdown.
+/// After a reconnect the listener on the new session arrives on `rebind`.
+async fn serve_remote(
+    accepted: Accepted,
+    mut rebind: mpsc::UnboundedReceiver<Accepted>,
+    target: Target,
+    mut shutdown_rx: watch::Receiver<bool>,
+) {
+    let mut accepted = Some(accepted);
+    loop {
+        let next = async {
+            match accepted.as_mut() {
+                Some(a) => a.recv().await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            ends = next => {
+                // None: that session is gone; wait for the next one
+                let Some((writer, reader)) = ends else {
+                    accepted = None;
+                    continue;
+                };
+                let target = target.clone();
+                tokio::spawn(async move {
+                    log::debug!("Remote connection for {target}");
+                    if let Err(e) = connect_local(&target, writer, reader).await {
+                        log::warn!("Remote connection to {target}: {e}");
+                    }
+                });
+            }
+            new = rebind.recv() => match new {
+                Some(a) => accepted = Some(a),
+                None => break,
+            },
+            _ = shutdown_rx.changed() => break,
+        }
+    }
+}
+
+async fn connect_local(target: &Target, writer: ChannelWriter, reader: ChannelReader) -> std::io::Result<()> {
+    match target {
+        Target::Tcp { host, port } => {
+            let stream = TcpStream::connect((host.as_str(), *port)).await?;
+            stream.set_nodelay(true)?;
+            splice(stream, writer, reader).await;
+        }
+        #[cfg(unix)]
+        Target::Unix(path) => splice(tokio::net::UnixStream::connect(path).await?, writer, reader).await,
+        #[cfg(not(unix))]
+        Target::Unix(_) => return Err(std::io::ErrorKind::Unsupported.into()),
+    }
+    Ok(())
+}
+
+/// Copy between a local connection and a channel until either side is done.
+pub(crate) async fn splice<S>(client: S, writer: ChannelWriter, mut reader: ChannelReader)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut client_reader, mut client_writer) = tokio::io::split(client);
+
+    // client -> channel; dropping the writer sends EOF to the remote side
+    let upstream = tokio::spawn(async move {
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            let n = tokio::select! {
+                read = client_reader.read(&mut buffer) => match read {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => n,
+                },
+                // Torn down by the driver, e.g. on shutdown
+                _ = writer.closed() => break,
+            };
+            if !writer.send(buffer[..n].to_vec()).await {
+                break;
+            }
+        }
+    });
+
+    // channel -> client, until the remote side sends EOF
+    while let Some(data) = reader.recv().await {
+        if client_writer.write_all(&data).await.is_err() {
+            upstream.abort();
+            return;
+        }
+    }
+    // Half-close: the client may still have data for the remote side.
+    let _ = client_writer.shutdown().await;
+    let _ = upstream.await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::TestServer;
+
+    /// Send `total` bytes through a forward and check they all arrive.
+    async fn forward_bulk(total: u64) {
+        let server = TestServer::start();
+
+        // Counts what it receives and reports the total once the sender is done.
+        let sink = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let sink_port = sink.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut conn, _) = sink.accept().await.unwrap();
+            let mut buf = vec![0u8; 256 * 1024];
+            let mut total = 0u64;
+            loop {
+                match conn.read(&mut buf).await.unwrap() {
+                    0 => break,
+                    n => total += n as u64,
+                }
+            }
+            conn.write_all(&total.to_be_bytes()).await.unwrap();
+        });
+
+        let mut tunnel = SshTunnel::connect(server.options()).await.unwrap();
+        let target = Target::Tcp { host: "127.0.0.1".to_string(), port: sink_port };
+        let addr = tunnel.forward(("127.0.0.1", 0), target).await.unwrap();
+
+        let start = std::time::Instant::now();
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        let chunk = vec![0x5au8; 1 << 20];
+        for _ in 0..total / chunk.len() as u64 {
+            conn.write_all(&chunk).await.unwrap();
+        }
+        conn.shutdown().await.unwrap();
+        let mut received = [0u8; 8];
+        conn.read_exact(&mut received).await.unwrap();
+        let elapsed = start.elapsed();
+
+        let received = u64::from_be_bytes(received);
+        assert_eq!(received, total);
+        let mib = (total >> 20) as f64;
+        eprintln!("{mib} MiB in {:.2?} ({:.0} MiB/s)", elapsed, mib / elapsed.as_secs_f64());
+        tunnel.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "needs sshd"]
+    async fn tunnel_takes_over_ssh_session() {
+        let server = TestServer::start();
+        let ssh = Ssh::connect(server.options()).await.unwrap();
+        assert_eq!(ssh.run("echo installed").await.unwrap().stdout.trim(), "installed");
+        let tunnel = SshTunnel::from_ssh(ssh, server.options()).await.unwrap();
+        let mut command = tunnel.exec("echo launched").await.unwrap();
+        assert_eq!(command.next_line().await.as_deref(), Some("launched"));
+        tunnel.shutdown().await.unwrap();
+    }
+
+    /// Enough to cross many channel window updates.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "needs sshd"]
+    async fn forwards_bulk_data() {
+        forward_bulk(64 << 20).await;
+    }
+
+    /// Throughput check; run with `cargo test --release -- --ignored`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "needs sshd"]
+    async fn forwards_one_gib() {
+        forward_bulk(1 << 30).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "needs sshd"]
+    async fn concurrent_connections() {
+        let server = TestServer::start();
+
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((conn, _)) = echo.accept().await {
+                tokio::spawn(async move {
+                    let (mut r, mut w) = conn.into_split();
+                    let _ = tokio::io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+
+        let mut tunnel = SshTunnel::connect(server.options()).await.unwrap();
+        let target = Target::Tcp { host: "127.0.0.1".to_string(), port: echo_port };
+        let addr = tunnel.forward(("127.0.0.1", 0), target).await.unwrap();
+
+        // All at once: every one of them must get its channel.
+        let clients: Vec<_> = (0..32u8)
+            .map(|i| {
+                tokio::spawn(async move {
+                    let conn = TcpStream::connect(addr).await.unwrap();
+                    let (mut r, mut w) = conn.into_split();
+                    let sent = vec![i; 256 * 1024];
+                    let writer = tokio::spawn(async move {
+                        w.write_all(&sent).await.unwrap();
+                        w.shutdown().await.unwrap();
+                    });
+                    let mut echoed = Vec::new();
+                    r.read_to_end(&mut echoed).await.unwrap();
+                    writer.await.unwrap();
+                    echoed.len() == 256 * 1024 && echoed.iter().all(|&b| b == i)
+                })
+            })
+            .collect();
+        for client in clients {
+            assert!(client.await.unwrap());
+        }
+        tunnel.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "needs sshd"]
+    async fn half_close_keeps_uploading() {
+        let server = TestServer::start();
+
+        // Says goodbye and closes its write side, then counts what still comes in.
+        let sink = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let sink_port = sink.local_addr().unwrap().port();
+        let (count_tx, count_rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (conn, _) = sink.accept().await.unwrap();
+            let (mut r, mut w) = conn.into_split();
+            w.write_all(b"bye").await.unwrap();
+            w.shutdown().await.unwrap();
+            let mut rest = Vec::new();
+            r.read_to_end(&mut rest).await.unwrap();
+            let _ = count_tx.send(rest.len());
+        });
+
+        let mut tunnel = SshTunnel::connect(server.options()).await.unwrap();
+        let target = Target::Tcp { host: "127.0.0.1".to_string(), port: sink_port };
+        let addr = tunnel.forward(("127.0.0.1", 0), target).await.unwrap();
+
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        let mut reply = Vec::new();
+        conn.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"bye");
+        conn.write_all(&vec![7u8; 1 << 20]).await.unwrap();
+        conn.shutdown().await.unwrap();
+        assert_eq!(count_rx.await.unwrap(), 1 << 20);
+        tunnel.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "needs sshd"]
+    async fn remote_forward_round_trip() {
+        let server = TestServer::start();
+
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((conn, _)) = echo.accept().await {
+                tokio::spawn(async move {
+                    let (mut r, mut w) = conn.into_split();
+                    let _ = tokio::io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+
+        // here -L-> remote port -R-> echo server here
+        let mut tunnel = SshTunnel::connect(server.options()).await.unwrap();
+        let remote = Forward::Remote {
+            bind: ("127.0.0.1".to_string(), 0),
+            target: Target::Tcp { host: "127.0.0.1".to_string(), port: echo_port },
+        };
+        let (remote_id, remote_port) = tunnel.add_forward(remote).await.unwrap();
+        assert_ne!(remote_port, 0);
+        let local = Forward::Local {
+            bind: ("127.0.0.1".to_string(), 0),
+            target: Target::Tcp { host: "127.0.0.1".to_string(), port: remote_port },
+        };
+        let (local_id, local_port) = tunnel.add_forward(local).await.unwrap();
+        assert_eq!(tunnel.forwards().map(|(id, ..)| id).collect::<Vec<_>>(), [remote_id, local_id]);
+
+        let mut conn = TcpStream::connect(("127.0.0.1", local_port)).await.unwrap();
+        conn.write_all(b"ping").await.unwrap();
+        let mut echoed = [0u8; 4];
+        conn.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+
+        // Removing the remote listener leaves open connections alone.
+        assert!(tunnel.remove_forward(remote_id));
+        assert!(!tunnel.remove_forward(remote_id));
+        conn.write_all(b"pong").await.unwrap();
+        conn.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"pong");
+
+        // New connections get through to the remote host, which refuses them.
+        let mut conn = TcpStream::connect(("127.0.0.1", local_port)).await.unwrap();
+        let mut rest = Vec::new();
+        assert_eq!(conn.read_to_end(&mut rest).await.unwrap_or(0), 0);
+        tunnel.shutdown().await.unwrap();
+    }
+}